@@ -1,46 +1,132 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use common::{ClientId, ClientMessage, ServerMessage, secure::SecureStreamError};
+use common::{
+    ClientId, ClientMessage, MessageId, ServerMessage, secure::SecureStreamError,
+    stream::MessageError,
+};
 use crossterm::{
-    event::{DisableMouseCapture, EnableMouseCapture, MouseEvent, MouseEventKind},
+    event::{
+        DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
     execute,
 };
-use futures::{SinkExt, StreamExt};
+use futures::{SinkExt, StreamExt, stream::FuturesUnordered};
 use log::{error, info, warn};
 use ratatui::{
     DefaultTerminal, Frame,
     buffer::Buffer,
-    layout::{Constraint, Layout, Rect},
-    style::{Color, Style, Stylize},
+    layout::{Constraint, Layout, Margin, Position, Rect},
+    style::{Style, Stylize},
     text::{Line, Span, Text},
-    widgets::{Block, HighlightSpacing, List, ListItem, ListState, StatefulWidget, Widget},
+    widgets::{
+        Block, HighlightSpacing, List, ListItem, ListState, Scrollbar, ScrollbarOrientation,
+        ScrollbarState, StatefulWidget, Widget,
+    },
 };
 
-use tokio::time::Instant;
-use tokio_util::bytes::Bytes;
-use unicode_width::UnicodeWidthStr;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 use crate::{
     CommandArgs,
     app::{
         event::{Event, EventSender, EventStream, InteractiveEvent, TermEvent},
-        resources::AppResources,
+        resources::{AppResources, SERVER_HOST, ServerAddr},
+        tls::{Security, TlsError},
         vim::SendMessageWidget,
     },
+    config::{ClientConfig, ClientState, ConfigError},
+    theme::Theme,
 };
 
+pub mod clipboard;
+pub mod command;
+pub mod complete;
 pub mod event;
+pub mod notify;
+pub mod prompt;
 pub mod resources;
+pub mod tls;
 pub mod vim;
 
+/// How long a sent message waits for its ack before it is marked as not delivered.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reconnects tried after the connection drops before giving up.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long quitting waits to tell the server we're leaving.
+const LEAVE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Bounds of the user list's width.
+const SIDEBAR_MIN_WIDTH: u16 = 12;
+const SIDEBAR_MAX_WIDTH: u16 = 60;
+/// Columns Alt+Left and Alt+Right resize the user list by.
+const SIDEBAR_STEP: u16 = 2;
+
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("An issue occurred communicating with the server.")]
     ServerError,
-    #[error("No valid ports were found")]
-    NoValidPorts,
+    #[error(
+        "Could not connect to any of the server's {tried} addresses, the last ({addr}) failed with: {source}"
+    )]
+    NoValidPorts {
+        tried: usize,
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    #[error("The server rejected the join request: {reason}")]
+    JoinRejected { reason: String },
+    #[error("Removed from the server: {reason}")]
+    Kicked { reason: String },
+    #[error("The server closed the connection: {reason}")]
+    Disconnected { reason: String },
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Could not resolve the server address {host}: {source}")]
+    Resolve {
+        host: String,
+        source: std::io::Error,
+    },
+    #[error("Timed out after {timeout:?} connecting to the server.")]
+    ConnectTimeout { timeout: Duration },
+    #[error("Could not connect to the server at {addr}: {source}. Is it running and reachable?")]
+    Connect {
+        addr: ServerAddr,
+        source: std::io::Error,
+    },
+    #[error(
+        "Connected to {addr} but the secure handshake failed: {source}. The server may be running an incompatible version."
+    )]
+    Handshake {
+        addr: ServerAddr,
+        source: SecureStreamError,
+    },
+    #[error("Refusing to talk to {addr}: {source}")]
+    KeyMismatch {
+        addr: ServerAddr,
+        source: SecureStreamError,
+    },
+    #[error(
+        "{addr} doesn't allow connections without encryption, drop --insecure or set allow_insecure in its settings."
+    )]
+    InsecureRefused { addr: ServerAddr },
+    #[error(
+        "--pin can't tell which of the {servers} servers it is for, pin them in server_fingerprints of the config instead."
+    )]
+    AmbiguousPin { servers: usize },
+    #[error("--insecure can't be used with tls in the config, drop one of them.")]
+    InsecureTls,
     #[error(transparent)]
-    SecureStreamError(#[from] SecureStreamError),
+    Tls(#[from] TlsError),
+    #[error(transparent)]
+    Message(#[from] MessageError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
@@ -48,134 +134,562 @@ pub enum AppError {
 }
 
 pub async fn run_app(args: CommandArgs) -> Result<(), AppError> {
-    let CommandArgs { name } = args;
-    let resources = Arc::new(AppResources::new(name).await?);
+    let mut terminal = ratatui::init();
+    let result = run_in_terminal(args, &mut terminal).await;
+    ratatui::restore();
+    result
+}
 
-    let mut app = App::new(resources).await?;
+async fn run_in_terminal(
+    args: CommandArgs,
+    terminal: &mut DefaultTerminal,
+) -> Result<(), AppError> {
+    let CommandArgs {
+        name,
+        channel,
+        servers,
+        #[cfg(unix)]
+        unix,
+        pin,
+        insecure,
+        config,
+        ..
+    } = args;
+    // A tab per server, the host is unused for the Unix socket.
+    let mut targets: Vec<(String, Option<PathBuf>)> =
+        servers.into_iter().map(|host| (host, None)).collect();
+    #[cfg(unix)]
+    if let Some(path) = unix {
+        targets.push((SERVER_HOST.to_owned(), Some(path)));
+    }
+    if targets.is_empty() {
+        targets.push((SERVER_HOST.to_owned(), None));
+    }
+    if pin.is_some() && targets.len() > 1 {
+        return Err(AppError::AmbiguousPin {
+            servers: targets.len(),
+        });
+    }
+    let config_path = PathBuf::from(config);
+    let config = ClientConfig::load(&config_path)?;
+    if insecure && config.tls {
+        return Err(AppError::InsecureTls);
+    }
+    let theme = Theme::load(&config.theme)?;
+    let name = match name {
+        Some(name) => name,
+        None => match prompt::prompt_name(terminal, &theme).await? {
+            Some(name) => name,
+            None => return Ok(()),
+        },
+    };
+    let mut connections = Vec::with_capacity(targets.len());
+    for (host, unix) in targets {
+        let server = match &unix {
+            Some(path) => path.display().to_string(),
+            None => host.clone(),
+        };
+        let security = match insecure {
+            true => Security::Insecure,
+            false => {
+                let pin = config.server_fingerprint(&server, pin.as_deref());
+                Security::from_config(&config, &host, pin)?
+            }
+        };
+        prompt::draw_connecting(terminal, &server, &theme)?;
+        let resources = AppResources::new(
+            name.clone(),
+            channel.clone(),
+            host,
+            unix,
+            security,
+            config.clone(),
+        )
+        .await?;
+        connections.push(Arc::new(resources));
+    }
 
-    app.run().await?;
+    let mut app = App::new(connections, theme, ClientState::path(&config_path)).await?;
 
-    Ok(())
+    app.run(terminal).await
 }
 
-#[derive(Debug, Default, Clone, Hash, PartialEq, Eq)]
-pub enum FrameStatus {
-    LastFrame {
-        instant: Instant,
-    },
-    AwaitingRender {
-        instant: Instant,
-    },
-    #[default]
-    None,
+pub struct App {
+    /// One per server, never empty.
+    tabs: Vec<Tab>,
+    /// Index of the tab shown, keys go to its draft.
+    active: usize,
+    /// Set by event handlers that changed what is shown, the next frame tick draws and clears
+    /// it.
+    redraw_pending: bool,
+    theme: Theme,
+    /// Where the state is saved on exit, beside the config.
+    state_path: PathBuf,
+    sidebar_width: u16,
+    /// `sidebar_width` on startup, the state is only saved if the sidebar was resized from it.
+    initial_sidebar_width: u16,
+    /// Set while the user list's border is dragged.
+    resizing_sidebar: bool,
 }
 
-pub struct App {
+/// A server connection with its own messages, user list and draft.
+struct Tab {
     resources: Arc<AppResources>,
     messages: MessageListWidget,
     client_list: ClientListWidget,
     send_message: SendMessageWidget,
-    frame_status: FrameStatus,
+    /// Name from the server's welcome, empty if it didn't send one.
+    server_name: String,
+    /// Cleared once the banner is shown, it only greets the first join.
+    banner_pending: bool,
+    /// Reconnect attempt under way, shown in the title.
+    reconnecting: Option<u32>,
+    /// Messages arrived since the tab was last shown.
+    unread: bool,
+}
+
+impl Tab {
+    fn new(resources: Arc<AppResources>, theme: Theme) -> Self {
+        Self {
+            messages: MessageListWidget::new(theme),
+            client_list: ClientListWidget::new(theme),
+            send_message: SendMessageWidget::new(Arc::clone(&resources), theme),
+            server_name: String::new(),
+            banner_pending: true,
+            reconnecting: None,
+            unread: false,
+            resources,
+        }
+    }
+
+    /// Name in the tab bar, the server's own if it sent one.
+    fn label(&self) -> String {
+        match self.server_name.is_empty() {
+            true => self.resources.target(),
+            false => self.server_name.clone(),
+        }
+    }
 }
 
 impl App {
-    pub async fn new(resources: Arc<AppResources>) -> Result<Self, AppError> {
+    /// The app with a tab for each of `connections`, which mustn't be empty.
+    pub async fn new(
+        connections: Vec<Arc<AppResources>>,
+        theme: Theme,
+        state_path: PathBuf,
+    ) -> Result<Self, AppError> {
+        let tabs: Vec<_> = connections
+            .into_iter()
+            .map(|resources| Tab::new(resources, theme))
+            .collect();
+        let state = ClientState::load(&state_path).unwrap_or_else(|err| {
+            warn!("Ignoring the state in {}: {}", state_path.display(), err);
+            ClientState::default()
+        });
+        let sidebar_width = state
+            .sidebar_width
+            .unwrap_or(tabs[0].resources.config.sidebar_width)
+            .clamp(SIDEBAR_MIN_WIDTH, SIDEBAR_MAX_WIDTH);
         Ok(Self {
-            messages: MessageListWidget::new(),
-            client_list: ClientListWidget::new(),
-            send_message: SendMessageWidget::new(Arc::clone(&resources)),
-            frame_status: FrameStatus::None,
-            resources,
+            tabs,
+            active: 0,
+            redraw_pending: false,
+            theme,
+            state_path,
+            sidebar_width,
+            initial_sidebar_width: sidebar_width,
+            resizing_sidebar: false,
         })
     }
-    pub async fn run(&mut self) -> Result<(), AppError> {
-        let mut terminal = ratatui::init();
-        let event_stream = EventStream::new();
+
+    /// Settings shared by every tab, they're loaded once.
+    fn config(&self) -> &ClientConfig {
+        &self.tabs[0].resources.config
+    }
+
+    fn active_tab(&mut self) -> &mut Tab {
+        &mut self.tabs[self.active]
+    }
+
+    pub async fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), AppError> {
+        let event_stream = EventStream::new(self.tabs.len());
 
         {
             let mut stdout = std::io::stdout();
-            execute!(stdout, EnableMouseCapture).unwrap();
+            execute!(stdout, EnableMouseCapture, EnableBracketedPaste).unwrap();
         }
 
-        let event_sender = event_stream.event_sender().clone();
-        let resources = Arc::clone(&self.resources);
+        let connections = self
+            .tabs
+            .iter()
+            .enumerate()
+            .map(|(index, tab)| {
+                let event_sender = event_stream.event_sender(index).clone();
+                let resources = Arc::clone(&tab.resources);
+                let network =
+                    tokio::task::spawn(Self::network_loop(resources, event_sender.clone()));
+                (network, event_sender)
+            })
+            .collect();
 
         let result = tokio::select! {
-            res = self.interactive_loop(&resources, &mut terminal, event_stream) => {
+            res = self.interactive_loop(terminal, event_stream) => {
                 res
             }
-            res = tokio::task::spawn(Self::network_loop(Arc::clone(&resources), event_sender)) => {
-                res?
+            res = Self::watch_connections(connections) => {
+                res
             }
         };
         {
             let mut stdout = std::io::stdout();
-            execute!(stdout, DisableMouseCapture).unwrap();
+            execute!(stdout, DisableMouseCapture, DisableBracketedPaste).unwrap();
         }
+        self.save_state();
         result
     }
 
+    /// Waits for the network loops of all tabs. A connection that ends while others are still
+    /// up is reported in its tab, the result of the last one to end is returned.
+    async fn watch_connections(
+        connections: Vec<(JoinHandle<Result<(), AppError>>, EventSender)>,
+    ) -> Result<(), AppError> {
+        let mut connections: FuturesUnordered<_> = connections
+            .into_iter()
+            .map(|(network, event_sender)| async move { (network.await, event_sender) })
+            .collect();
+        while let Some((result, event_sender)) = connections.next().await {
+            let result = match result {
+                Ok(result) => result,
+                Err(err) => Err(err.into()),
+            };
+            if connections.is_empty() {
+                return result;
+            }
+            let content = match result {
+                Ok(()) => "Disconnected.".to_owned(),
+                Err(err) => format!("Disconnected: {}", err),
+            };
+            let _ = event_sender
+                .send(InteractiveEvent::SystemMessage { content })
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Saves the sidebar's width to the state file if it was resized, the config is left alone.
+    fn save_state(&self) {
+        if self.sidebar_width == self.initial_sidebar_width {
+            return;
+        }
+        let state = ClientState {
+            sidebar_width: Some(self.sidebar_width),
+        };
+        if let Err(err) = state.save(&self.state_path) {
+            warn!(
+                "Failed to save the state to {}: {}",
+                self.state_path.display(),
+                err
+            );
+        }
+    }
+
     pub async fn network_loop(
         resources: Arc<AppResources>,
         event_sender: EventSender,
     ) -> Result<(), AppError> {
         // Connect to server
-        while let Some(Ok(message)) = resources.read_msg.lock().await.next().await {
-            let message: ServerMessage = match serde_cbor::de::from_slice(&message) {
-                Ok(message) => message,
-                Err(err) => {
+        loop {
+            // Not matched on directly, the lock must be released before reconnecting takes it.
+            let next = resources.read_msg.lock().await.next().await;
+            let message = match next {
+                Some(Ok(message)) => message,
+                Some(Err(err)) if err.is_recoverable() => {
                     warn!("Received a corrupted message from server: {}", err);
                     continue;
                 }
+                Some(Err(_)) | None if resources.is_leaving() => break,
+                Some(Err(err)) => {
+                    warn!("Lost connection to the server: {}", err);
+                    Self::reconnect(&resources, &event_sender).await?;
+                    continue;
+                }
+                None => {
+                    warn!("The server closed the connection");
+                    Self::reconnect(&resources, &event_sender).await?;
+                    continue;
+                }
             };
 
-            match message {
-                ServerMessage::AcceptJoin => {
-                    info!("Server accepted your join request.")
-                }
-                ServerMessage::ClientListUpdate { clients } => {
-                    event_sender
-                        .send(InteractiveEvent::ClientListUpdate { clients })
-                        .await
-                        .unwrap();
+            let messages = match message {
+                // Handled as if the messages had been sent one by one.
+                ServerMessage::Batch(messages) => messages,
+                message => vec![message],
+            };
+            for message in messages {
+                match message {
+                    ServerMessage::AcceptJoin { .. } => {
+                        info!("Server accepted your join request.")
+                    }
+                    ServerMessage::ResumeRejected => {
+                        warn!("Unexpected resume rejection outside of reconnecting")
+                    }
+                    ServerMessage::JoinRejected { reason } => {
+                        return Err(AppError::JoinRejected { reason });
+                    }
+                    ServerMessage::Welcome { server_name, motd } => {
+                        event_sender
+                            .send(InteractiveEvent::Welcome { server_name, motd })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::ChannelJoined { channel } => {
+                        resources.state.write().await.channel = Some(channel.clone());
+                        event_sender
+                            .send(InteractiveEvent::ChannelJoined { channel })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::ClientListUpdate { clients } => {
+                        event_sender
+                            .send(InteractiveEvent::ClientListUpdate { clients })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::MessageEdited {
+                        id,
+                        sender,
+                        content,
+                    } => {
+                        event_sender
+                            .send(InteractiveEvent::MessageEdited {
+                                id,
+                                sender,
+                                content,
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::MessageDeleted { id, sender } => {
+                        event_sender
+                            .send(InteractiveEvent::MessageDeleted { id, sender })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::Kicked { reason } => {
+                        resources.set_leaving();
+                        return Err(AppError::Kicked { reason });
+                    }
+                    ServerMessage::Error { reason } => {
+                        resources.set_leaving();
+                        return Err(AppError::Disconnected { reason });
+                    }
+                    ServerMessage::Stats {
+                        client_count,
+                        uptime_secs,
+                        total_messages,
+                    } => {
+                        let uptime = Duration::from_secs(uptime_secs);
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!(
+                                    "{} clients connected, up for {:?}, {} messages sent.",
+                                    client_count, uptime, total_messages
+                                ),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::AdminRejected { reason } => {
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!("Admin command refused: {}", reason),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::PrivateMessage {
+                        sender,
+                        to,
+                        message,
+                    } => {
+                        event_sender
+                            .send(InteractiveEvent::ReceivePrivateMessage {
+                                sender,
+                                to,
+                                content: message,
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::PrivateMessageRejected { to, reason } => {
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!("Couldn't message {}: {}", to, reason),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::WhoisResult {
+                        id,
+                        joined_at,
+                        away,
+                        last_active,
+                    } => {
+                        let since_epoch = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        let joined = since_epoch.saturating_sub(Duration::from_secs(joined_at));
+                        let last_active = Duration::from_secs(last_active);
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!(
+                                    "{}\njoined {} ago\nlast active {} ago{}",
+                                    id,
+                                    format_elapsed(joined),
+                                    format_elapsed(last_active),
+                                    if away { ", away" } else { "" },
+                                ),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::WhoisRejected { name, reason } => {
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!("Couldn't look up {}: {}", name, reason),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::MessageRejected { id, reason } => {
+                        event_sender
+                            .send(InteractiveEvent::MessageFailed { id })
+                            .await
+                            .unwrap();
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!("Couldn't send message {}: {}", id, reason),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::EditRejected { id, reason } => {
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage {
+                                content: format!("Couldn't change message {}: {}", id, reason),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::Ack { id } => {
+                        event_sender
+                            .send(InteractiveEvent::MessageAcked { id })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::ReceiveMessage {
+                        id,
+                        message,
+                        sender,
+                        reply_to,
+                    } => {
+                        event_sender
+                            .send(InteractiveEvent::ReceiveMessage {
+                                id,
+                                sender,
+                                content: message,
+                                reply_to,
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    ServerMessage::Batch(_) => {
+                        warn!("Ignoring a batch nested in another batch")
+                    }
                 }
-                ServerMessage::ReceiveMessage { message, sender } => {
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets back onto the server after the connection dropped, retrying a few times.
+    async fn reconnect(
+        resources: &Arc<AppResources>,
+        event_sender: &EventSender,
+    ) -> Result<(), AppError> {
+        let mut attempt = 1;
+        loop {
+            event_sender
+                .send(InteractiveEvent::Reconnecting {
+                    attempt: Some(attempt),
+                })
+                .await
+                .unwrap();
+            let result = resources.reconnect().await;
+            if result.is_ok() {
+                event_sender
+                    .send(InteractiveEvent::Reconnecting { attempt: None })
+                    .await
+                    .unwrap();
+            }
+            match result {
+                Ok(true) => {
+                    info!("Resumed the session");
                     event_sender
-                        .send(InteractiveEvent::ReceiveMessage {
-                            sender,
-                            content: message,
+                        .send(InteractiveEvent::SystemMessage {
+                            content: "Reconnected.".to_owned(),
                         })
                         .await
                         .unwrap();
+                    return Ok(());
                 }
+                // The server sends the usual join messages, which reset the view.
+                Ok(false) => {
+                    info!("Could not resume, joined as a new session");
+                    return Ok(());
+                }
+                Err(err) if attempt < RECONNECT_ATTEMPTS => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, err);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
             }
         }
-
-        Ok(())
     }
 
     pub async fn interactive_loop(
         &mut self,
-        resources: &Arc<AppResources>,
         terminal: &mut DefaultTerminal,
         mut event_stream: EventStream,
     ) -> Result<(), AppError> {
+        // Redraws are coalesced into at most one draw per tick, a tick after a quiet
+        // spell comes right away.
+        let mut frames = tokio::time::interval(self.config().frame_interval());
+        frames.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         let exit_result = loop {
-            match event_stream.next().await {
-                Some(Ok(event)) => {
-                    match self
-                        .handle_event(resources, event, event_stream.event_sender(), terminal)
-                        .await
-                    {
-                        Ok(false) => (),
-                        Ok(true) => {
-                            break Ok(());
-                        }
-                        Err(err) => {
-                            break Err(err);
-                        }
+            let event = tokio::select! {
+                event = event_stream.next() => event,
+                _ = frames.tick(), if self.redraw_pending => {
+                    self.redraw_pending = false;
+                    if let Err(err) = terminal.draw(|frame| self.render(frame)) {
+                        break Err(err.into());
                     }
+                    continue;
                 }
+            };
+            match event {
+                Some(Ok(event)) => match self.handle_event(event, &event_stream, terminal).await {
+                    Ok(false) => (),
+                    Ok(true) => {
+                        break Ok(());
+                    }
+                    Err(err) => {
+                        break Err(err);
+                    }
+                },
                 Some(Err(err)) => {
                     error!("Error in interactive loop: {}", err)
                 }
@@ -183,164 +697,567 @@ impl App {
             }
         };
 
-        ratatui::restore();
-
-        self.on_exit(resources).await;
+        self.on_exit().await;
 
         exit_result
     }
 
-    pub async fn on_exit(&mut self, _resources: &Arc<AppResources>) {}
+    /// Leaves every server.
+    pub async fn on_exit(&mut self) {
+        let leaves = self.tabs.iter().map(|tab| Self::leave(&tab.resources));
+        futures::future::join_all(leaves).await;
+    }
 
-    fn render(&mut self, frame: &mut Frame) {
+    /// Tells the server we're leaving so it drops us from the client list without waiting on
+    /// TCP teardown. Best-effort, gives up after [`LEAVE_TIMEOUT`].
+    async fn leave(resources: &AppResources) {
+        resources.set_leaving();
+        let leave = async {
+            let mut write_msg = resources.write_msg.lock().await;
+            write_msg.send(ClientMessage::Leave).await?;
+            write_msg.close().await
+        };
+        match tokio::time::timeout(LEAVE_TIMEOUT, leave).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => warn!("Failed to tell the server we're leaving: {}", err),
+            Err(_) => warn!("Timed out telling the server we're leaving"),
+        }
+    }
+
+    /// Splits the terminal into the title, messages, draft and user list areas, as they are
+    /// while `tab` is shown.
+    fn layout(&self, tab: &Tab, area: Rect) -> [Rect; 4] {
         let layout1 = Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]);
 
-        let [title_area, main_area] = layout1.areas(frame.area());
+        let [title_area, main_area] = layout1.areas(area);
 
-        let layout2 = Layout::horizontal([Constraint::Fill(1), Constraint::Length(26)]);
+        let layout2 =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Length(self.sidebar_width)]);
 
         let [main_area2, client_list_area] = layout2.areas(main_area);
-        let layout3 = Layout::vertical([Constraint::Fill(1), Constraint::Length(8)]);
+        let send_height = tab.send_message.height();
+        let layout3 = Layout::vertical([Constraint::Fill(1), Constraint::Length(send_height)]);
         let [messages_area, send_area] = layout3.areas(main_area2);
-        let title = Line::from("term-chat 🚀")
-            .centered()
-            .bold()
-            .fg(Color::Rgb(255, 242, 197));
-        frame.render_widget(title, title_area);
-        frame.render_widget(&mut self.messages, messages_area);
-        frame.render_widget(&mut self.send_message, send_area);
-        frame.render_widget(&mut self.client_list, client_list_area);
+        [title_area, messages_area, send_area, client_list_area]
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [title_area, messages_area, send_area, client_list_area] =
+            self.layout(&self.tabs[self.active], frame.area());
+        frame.render_widget(self.title(), title_area);
+        let tab = self.active_tab();
+        frame.render_widget(&mut tab.messages, messages_area);
+        frame.render_widget(&mut tab.send_message, send_area);
+        frame.render_widget(&mut tab.client_list, client_list_area);
+    }
+
+    /// The title row, listing the tabs if there are several.
+    fn title(&self) -> Line<'static> {
+        let mut spans = vec![Span::from("term-chat 🚀")];
+        if let [tab] = &self.tabs[..] {
+            if !tab.server_name.is_empty() {
+                spans.push(Span::from(format!(" {}", tab.server_name)));
+            }
+        } else {
+            for (index, tab) in self.tabs.iter().enumerate() {
+                let unread = if tab.unread { "*" } else { "" };
+                let label = Span::from(format!(" {}:{}{} ", index + 1, tab.label(), unread));
+                spans.push(Span::from(" "));
+                spans.push(if index == self.active {
+                    label.reversed()
+                } else {
+                    label
+                });
+            }
+        }
+        if let Some(attempt) = self.tabs[self.active].reconnecting {
+            spans.push(Span::from(format!(
+                " (reconnecting, attempt {} of {}…)",
+                attempt, RECONNECT_ATTEMPTS
+            )));
+        }
+        Line::from(spans).centered().bold().fg(self.theme.title)
+    }
+
+    /// Appends a message to the tab at `tab` and scrolls so the newest messages are in view.
+    fn push_message(&mut self, tab: usize, message: Message, terminal: &mut DefaultTerminal) {
+        let [_, messages_area, _, _] = self.layout(&self.tabs[tab], terminal.get_frame().area());
+        let messages = &mut self.tabs[tab].messages;
+        messages.messages.push(message);
+        let inner = messages_area.inner(Margin::new(1, 1));
+        let width = MessageListWidget::content_width(inner);
+        let heights = messages
+            .messages
+            .iter()
+            .map(|message| message.line_count(width));
+        *messages.list_state.offset_mut() = scroll_to_end(heights, usize::from(inner.height));
     }
 
     async fn handle_event(
         &mut self,
-        resources: &Arc<AppResources>,
         event: Event,
-        event_sender: &EventSender,
+        event_stream: &EventStream,
         terminal: &mut DefaultTerminal,
     ) -> Result<bool, AppError> {
         match event {
-            Event::Interactive(event) => {
-                self.handle_interactive_event(resources, event, event_sender, terminal)
+            Event::Interactive(tab, event) => {
+                let event_sender = event_stream.event_sender(tab);
+                self.handle_interactive_event(tab, event, event_sender, terminal)
                     .await
             }
-            Event::Term(event) => self.handle_term_event(event, event_sender, terminal).await,
+            Event::Term(event) => {
+                let event_sender = event_stream.event_sender(self.active);
+                self.handle_term_event(event, event_sender, terminal).await
+            }
         }
     }
 
     async fn handle_interactive_event(
         &mut self,
-        resources: &Arc<AppResources>,
+        tab: usize,
         event: InteractiveEvent,
         event_sender: &EventSender,
         terminal: &mut DefaultTerminal,
     ) -> Result<bool, AppError> {
+        let resources = &Arc::clone(&self.tabs[tab].resources);
         match event {
             InteractiveEvent::Quit => Ok(true),
-            InteractiveEvent::RedrawRequest => {
-                match self.frame_status {
-                    FrameStatus::AwaitingRender { instant } => {
-                        if Instant::now() > instant {
-                            self.frame_status = FrameStatus::LastFrame {
-                                instant: Instant::now(),
-                            };
-                            terminal.draw(|frame| self.render(frame)).unwrap();
-                        }
-                    }
-                    FrameStatus::LastFrame { instant } => {
-                        if Instant::now() > instant + Duration::from_secs_f32(1.0 / 60.0) {
-                            self.frame_status = FrameStatus::LastFrame {
-                                instant: Instant::now(),
-                            };
-                            terminal.draw(|frame| self.render(frame)).unwrap();
-                        } else {
-                            let target_instant = instant + Duration::from_secs_f32(1.0 / 60.0);
-                            self.frame_status = FrameStatus::AwaitingRender {
-                                instant: target_instant,
-                            };
-                            let event_sender = event_sender.clone();
-                            tokio::spawn(async move {
-                                tokio::time::sleep_until(target_instant).await;
-                                event_sender.send(InteractiveEvent::RedrawRequest).await.unwrap();
-                            });
-                        }
-                    }
-                    FrameStatus::None => {
-                        self.frame_status = FrameStatus::LastFrame {
-                            instant: Instant::now(),
-                        };
-                        terminal.draw(|frame| self.render(frame)).unwrap();
-                    }
+            InteractiveEvent::ClientListUpdate { clients } => {
+                let mut state = resources.state.write().await;
+                state.roster = clients
+                    .iter()
+                    .map(|client| client.id.name.clone())
+                    .collect();
+                self.tabs[tab].client_list.clients.clear();
+                let now = Instant::now();
+                for client in clients {
+                    let ignored = state.ignored.contains(&client.id.name);
+                    let idle = Duration::from_secs(client.last_active);
+                    self.tabs[tab].client_list.clients.push(ClientItem {
+                        id: client.id,
+                        ignored,
+                        last_active: now.checked_sub(idle).unwrap_or(now),
+                    });
                 }
+                drop(state);
+                self.redraw_pending = true;
                 Ok(false)
             }
-            InteractiveEvent::ClientListUpdate { clients } => {
-                self.client_list.clients.clear();
-                for client in clients {
-                    self.client_list.clients.push(ClientItem { id: client });
+            InteractiveEvent::ReceiveMessage {
+                id,
+                sender,
+                content,
+                reply_to,
+            } => {
+                self.tabs[tab].client_list.touch(&sender);
+                let own_id = resources.id();
+                if sender == own_id {
+                    // Our own message, already shown when it was sent.
+                    if self.tabs[tab].messages.find_mut(id, &own_id).is_some() {
+                        return Ok(false);
+                    }
+                } else if resources.state.read().await.ignored.contains(&sender.name) {
+                    return Ok(false);
+                } else {
+                    notify::notify(&resources.config, &own_id.name, &sender.name, &content);
                 }
+                self.tabs[tab].unread |= tab != self.active;
+                self.push_message(
+                    tab,
+                    Message {
+                        id: Some(id),
+                        sender: MessageSender::Client(sender),
+                        content,
+                        status: DeliveryStatus::Delivered,
+                        edited: false,
+                        reply_to,
+                    },
+                    terminal,
+                );
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::SystemMessage { content } => {
+                self.push_message(tab, Message::system(content), terminal);
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::ShowInfo => {
+                let compression = resources.compression;
+                let limits = resources.limits();
+                let rate = match limits.messages_per_second {
+                    0 => "as often as you like".to_owned(),
+                    rate => format!("up to {} a second", rate),
+                };
+                let content = format!(
+                    "You are {} on {}, encrypted with {}, sending {:?} compression ({:?}) above {} bytes.\nThe server takes messages of up to {} characters, {}, and up to {} clients.",
+                    resources.id(),
+                    resources.server_addr(),
+                    resources.encryption(),
+                    compression.algorithm,
+                    compression.level,
+                    compression.threshold,
+                    limits.max_message_length,
+                    rate,
+                    limits.max_clients,
+                );
                 event_sender
-                    .send(InteractiveEvent::RedrawRequest)
+                    .send(InteractiveEvent::SystemMessage { content })
                     .await
                     .unwrap();
                 Ok(false)
             }
-            InteractiveEvent::ReceiveMessage { sender, content } => {
-                self.messages.messages.push(Message {
-                    id: sender,
-                    content,
-                });
-                let layout = Layout::vertical([
-                    Constraint::Length(1),
-                    Constraint::Fill(1),
-                    Constraint::Length(8),
-                ]);
-                let [_title_area, messages_area, _send_area] =
-                    layout.areas(terminal.get_frame().area());
-
-                let mut messages_height = (messages_area.height as usize).saturating_sub(2);
-                let mut first_message = 0;
-
-                for (n, message) in self.messages.messages.iter().enumerate().rev() {
-                    match messages_height.checked_sub(message.content.split('\n').count()) {
-                        Some(0) => {
-                            first_message = n;
-                            break;
-                        }
-                        None => {
-                            first_message = n.saturating_sub(1);
-                            break;
-                        }
-                        Some(x) => {
-                            messages_height = x;
-                        }
-                    }
-                }
-
-                *self.messages.list_state.offset_mut() = first_message;
+            InteractiveEvent::Ignore { name } => {
+                resources.state.write().await.ignored.insert(name.clone());
+                self.tabs[tab].client_list.set_ignored(&name, true);
+                let content = format!("Ignoring messages from {}.", name);
                 event_sender
-                    .send(InteractiveEvent::RedrawRequest)
+                    .send(InteractiveEvent::SystemMessage { content })
                     .await
                     .unwrap();
                 Ok(false)
             }
-            InteractiveEvent::SendMessage { content } => {
-                let resources = Arc::clone(resources);
+            InteractiveEvent::Unignore { name } => {
+                let content = if resources.state.write().await.ignored.remove(&name) {
+                    self.tabs[tab].client_list.set_ignored(&name, false);
+                    format!("No longer ignoring {}.", name)
+                } else {
+                    format!("{} isn't ignored.", name)
+                };
+                event_sender
+                    .send(InteractiveEvent::SystemMessage { content })
+                    .await
+                    .unwrap();
+                Ok(false)
+            }
+            InteractiveEvent::SaveTranscript { path } => {
+                let path = expand_tilde(&path);
+                let transcript = self.tabs[tab].messages.transcript();
+                let event_sender = event_sender.clone();
                 tokio::spawn(async move {
-                    let message =
-                        serde_cbor::to_vec(&ClientMessage::SendMessage { message: content })
-                            .unwrap();
+                    let display_path = path.display().to_string();
+                    let result =
+                        tokio::task::spawn_blocking(move || std::fs::write(&path, transcript))
+                            .await
+                            .map_err(std::io::Error::other)
+                            .and_then(|result| result);
+                    let content = match result {
+                        Ok(()) => format!("Saved transcript to {}", display_path),
+                        Err(err) => {
+                            format!("Failed to save transcript to {}: {}", display_path, err)
+                        }
+                    };
+                    event_sender
+                        .send(InteractiveEvent::SystemMessage { content })
+                        .await
+                        .unwrap();
+                });
+                Ok(false)
+            }
+            InteractiveEvent::Admin { command } => {
+                Self::spawn_write(resources, ClientMessage::Admin(command));
+                Ok(false)
+            }
+            InteractiveEvent::JoinChannel { channel } => {
+                Self::spawn_write(resources, ClientMessage::JoinChannel { channel });
+                Ok(false)
+            }
+            InteractiveEvent::Whois { name } => {
+                Self::spawn_write(resources, ClientMessage::Whois { name });
+                Ok(false)
+            }
+            InteractiveEvent::PrivateMessage { to, content } => {
+                let message = ClientMessage::PrivateMessage {
+                    to,
+                    message: content,
+                };
+                Self::spawn_write(resources, message);
+                Ok(false)
+            }
+            InteractiveEvent::ReceivePrivateMessage {
+                sender,
+                to,
+                content,
+            } => {
+                if sender != resources.id() {
+                    if resources.state.read().await.ignored.contains(&sender.name) {
+                        return Ok(false);
+                    }
+                    notify::notify_private(&resources.config, &sender.name, &content);
+                }
+                self.tabs[tab].unread |= tab != self.active;
+                self.push_message(
+                    tab,
+                    Message {
+                        id: None,
+                        sender: MessageSender::Client(sender),
+                        content: format!("(to {}) {}", to, content),
+                        status: DeliveryStatus::Delivered,
+                        edited: false,
+                        reply_to: None,
+                    },
+                    terminal,
+                );
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::Reconnecting { attempt } => {
+                self.tabs[tab].reconnecting = attempt;
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::Welcome { server_name, motd } => {
+                self.tabs[tab].server_name = server_name;
+                // Pushed directly, so it stays ahead of messages that arrived after it.
+                if !motd.is_empty() && resources.config.show_motd {
+                    self.push_message(tab, Message::system(motd), terminal);
+                }
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::ChannelJoined { channel } => {
+                info!("Joined channel #{}", channel);
+                self.tabs[tab].messages.clear();
+                // Right after the clear, ahead of the welcome and any messages.
+                if std::mem::take(&mut self.tabs[tab].banner_pending) {
+                    if let Some(banner) = resources.config.banner.text() {
+                        self.push_message(tab, Message::system(banner.to_owned()), terminal);
+                        let joined = format!("Joined #{} as {}.", channel, resources.id().name);
+                        self.push_message(tab, Message::system(joined), terminal);
+                    }
+                }
+                self.tabs[tab].messages.channel = channel;
+                self.tabs[tab].messages.own_id = Some(resources.id());
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::ClearMessages => {
+                self.tabs[tab].messages.clear();
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::SendMessage { content, reply_to } => {
+                let id = MessageId::random();
+                // Show the message straight away, the server ack confirms it.
+                self.push_message(
+                    tab,
+                    Message {
+                        id: Some(id),
+                        sender: MessageSender::Client(resources.id()),
+                        content: content.clone(),
+                        status: DeliveryStatus::Pending,
+                        edited: false,
+                        reply_to,
+                    },
+                    terminal,
+                );
 
-                    let mut write_msg = resources.write_msg.lock().await;
-                    if let Err(err) = write_msg.send(Bytes::from(message)).await {
+                let resources = Arc::clone(resources);
+                let failed_sender = event_sender.clone();
+                tokio::spawn(async move {
+                    let message = ClientMessage::SendMessage {
+                        id,
+                        message: content,
+                        reply_to,
+                    };
+                    let write_result = resources.write_msg.lock().await.send(message).await;
+                    if let Err(err) = write_result {
                         error!("Error writing to server: {}", err);
+                    } else {
+                        tokio::time::sleep(ACK_TIMEOUT).await;
                     }
+                    // Ignored if the ack already arrived.
+                    let _ = failed_sender
+                        .send(InteractiveEvent::MessageFailed { id })
+                        .await;
                 });
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::EditLastMessage { content } => {
+                let Some(id) = self.tabs[tab].messages.last_own_id(&resources.id()) else {
+                    return Self::no_own_message(event_sender).await;
+                };
+                Self::spawn_write(
+                    resources,
+                    ClientMessage::EditMessage {
+                        id,
+                        new_content: content,
+                    },
+                );
+                Ok(false)
+            }
+            InteractiveEvent::DeleteLastMessage => {
+                let Some(id) = self.tabs[tab].messages.last_own_id(&resources.id()) else {
+                    return Self::no_own_message(event_sender).await;
+                };
+                Self::spawn_write(resources, ClientMessage::DeleteMessage { id });
+                Ok(false)
+            }
+            InteractiveEvent::MessageEdited {
+                id,
+                sender,
+                content,
+            } => {
+                if let Some(message) = self.tabs[tab].messages.find_mut(id, &sender) {
+                    message.content = content;
+                    message.edited = true;
+                    self.redraw_pending = true;
+                }
                 Ok(false)
             }
+            InteractiveEvent::MessageDeleted { id, sender } => {
+                self.tabs[tab].messages.remove(id, &sender);
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            InteractiveEvent::MessageAcked { id } => {
+                if let Some(message) = self.tabs[tab].messages.find_mut(id, &resources.id()) {
+                    message.status = DeliveryStatus::Acked;
+                    self.redraw_pending = true;
+                }
+                Ok(false)
+            }
+            InteractiveEvent::MessageFailed { id } => {
+                if let Some(message) = self.tabs[tab].messages.find_mut(id, &resources.id()) {
+                    if message.status == DeliveryStatus::Pending {
+                        message.status = DeliveryStatus::Failed;
+                        self.redraw_pending = true;
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Selects the user or message under a left click, returns whether one was selected.
+    fn click(&mut self, position: Position) -> bool {
+        let tab = self.active_tab();
+        let clients = &tab.client_list;
+        let offset = clients.list_state.offset();
+        let heights = std::iter::repeat_n(1, clients.clients.len());
+        let clicked = item_at(clients.area, offset, heights, position);
+        if let Some(index) = clicked {
+            tab.client_list.list_state.select(Some(index));
+            return true;
+        }
+        let messages = &tab.messages;
+        let offset = messages.list_state.offset();
+        let heights = messages.heights.iter().copied();
+        let clicked = item_at(messages.area, offset, heights, position);
+        if let Some(index) = clicked {
+            tab.messages.list_state.select(Some(index));
+            return true;
+        }
+        false
+    }
+
+    /// Moves the message selection with j/k, by a screenful with Ctrl+u/Ctrl+d and to the
+    /// oldest or newest with Home/End, quotes the selected message on Enter and replies to it
+    /// on `r`.
+    ///
+    /// Returns whether the key was used.
+    fn navigate_messages(&mut self, event: KeyEvent) -> bool {
+        if event.kind != KeyEventKind::Press {
+            return false;
+        }
+        let tab = self.active_tab();
+        if event.modifiers == KeyModifiers::CONTROL {
+            match event.code {
+                KeyCode::Char('u') => tab.messages.page_up(),
+                KeyCode::Char('d') => tab.messages.page_down(),
+                _ => return false,
+            }
+            return true;
+        }
+        match event.code {
+            KeyCode::Home => {
+                tab.messages.select_first();
+                true
+            }
+            KeyCode::End => {
+                tab.messages.select_last();
+                true
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                tab.messages.select_next();
+                true
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                tab.messages.select_previous();
+                true
+            }
+            KeyCode::Enter => {
+                let Some(message) = tab.messages.selected() else {
+                    return false;
+                };
+                let sender = match &message.sender {
+                    MessageSender::Client(id) => id.name.clone(),
+                    MessageSender::System => "system".to_owned(),
+                };
+                let content = message.content.clone();
+                tab.send_message.quote(&sender, &content);
+                tab.messages.list_state.select(None);
+                true
+            }
+            KeyCode::Char('r') => {
+                let Some(Message {
+                    id: Some(id),
+                    sender: MessageSender::Client(sender),
+                    ..
+                }) = tab.messages.selected()
+                else {
+                    return false;
+                };
+                let (id, sender) = (*id, sender.name.clone());
+                tab.send_message.reply(id, &sender);
+                tab.messages.list_state.select(None);
+                true
+            }
+            KeyCode::Esc if tab.messages.list_state.selected().is_some() => {
+                tab.messages.list_state.select(None);
+                true
+            }
+            _ => false,
         }
     }
 
+    /// Moves the message selection by a screenful on PageUp/PageDown, whatever the draft's
+    /// state. Returns whether it was one of them.
+    fn page_messages(&mut self, event: KeyEvent) -> bool {
+        if event.kind != KeyEventKind::Press {
+            return false;
+        }
+        let messages = &mut self.active_tab().messages;
+        match event.code {
+            KeyCode::PageUp => messages.page_up(),
+            KeyCode::PageDown => messages.page_down(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sends `message` to the server in the background, logging failures.
+    fn spawn_write(resources: &Arc<AppResources>, message: ClientMessage) {
+        let resources = Arc::clone(resources);
+        tokio::spawn(async move {
+            let mut write_msg = resources.write_msg.lock().await;
+            if let Err(err) = write_msg.send(message).await {
+                error!("Error writing to server: {}", err);
+            }
+        });
+    }
+
+    async fn no_own_message(event_sender: &EventSender) -> Result<bool, AppError> {
+        event_sender
+            .send(InteractiveEvent::SystemMessage {
+                content: "You haven't sent a message that can be changed.".to_owned(),
+            })
+            .await
+            .unwrap();
+        Ok(false)
+    }
+
     async fn handle_term_event(
         &mut self,
         event: TermEvent,
@@ -348,68 +1265,209 @@ impl App {
         _terminal: &mut DefaultTerminal,
     ) -> Result<bool, AppError> {
         if let TermEvent::Key(event) = event {
-            if self.send_message.input(event, event_sender).await {
-                event_sender
-                    .send(InteractiveEvent::RedrawRequest)
-                    .await
-                    .unwrap();
+            if self.switch_tab(event) || self.page_messages(event) || self.resize_sidebar(event) {
+                self.redraw_pending = true;
+                return Ok(false);
+            }
+            if self.active_tab().send_message.navigates_messages().await
+                && self.navigate_messages(event)
+            {
+                self.redraw_pending = true;
+                return Ok(false);
+            }
+            if self
+                .active_tab()
+                .send_message
+                .input(event, event_sender)
+                .await
+            {
+                self.redraw_pending = true;
             }
         }
         match event {
+            TermEvent::Paste(text) => {
+                if self.active_tab().send_message.paste(&text).await {
+                    self.redraw_pending = true;
+                }
+                Ok(false)
+            }
             TermEvent::FocusGained | TermEvent::Resize(_, _) => {
-                event_sender
-                    .send(InteractiveEvent::RedrawRequest)
-                    .await
-                    .unwrap();
+                self.redraw_pending = true;
                 Ok(false)
             }
             TermEvent::Mouse(MouseEvent {
                 kind: MouseEventKind::ScrollUp,
                 ..
             }) => {
-                self.messages.scroll_up();
-                event_sender
-                    .send(InteractiveEvent::RedrawRequest)
-                    .await
-                    .unwrap();
+                self.active_tab().messages.scroll_up();
+                self.redraw_pending = true;
                 Ok(false)
             }
             TermEvent::Mouse(MouseEvent {
                 kind: MouseEventKind::ScrollDown,
                 ..
             }) => {
-                self.messages.scroll_down();
-                event_sender
-                    .send(InteractiveEvent::RedrawRequest)
-                    .await
-                    .unwrap();
+                self.active_tab().messages.scroll_down();
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            TermEvent::Mouse(MouseEvent {
+                kind: MouseEventKind::Down(MouseButton::Left),
+                column,
+                row,
+                ..
+            }) => {
+                let area = self.tabs[self.active].client_list.area;
+                if column == area.x && (area.top()..area.bottom()).contains(&row) {
+                    self.resizing_sidebar = true;
+                    return Ok(false);
+                }
+                if self.click(Position::new(column, row)) {
+                    self.redraw_pending = true;
+                }
+                Ok(false)
+            }
+            TermEvent::Mouse(MouseEvent {
+                kind: MouseEventKind::Drag(MouseButton::Left),
+                column,
+                ..
+            }) if self.resizing_sidebar => {
+                let width = self.tabs[self.active]
+                    .client_list
+                    .area
+                    .right()
+                    .saturating_sub(column);
+                self.set_sidebar_width(width);
+                self.redraw_pending = true;
+                Ok(false)
+            }
+            TermEvent::Mouse(MouseEvent {
+                kind: MouseEventKind::Up(MouseButton::Left),
+                ..
+            }) => {
+                self.resizing_sidebar = false;
                 Ok(false)
             }
             _ => Ok(false),
         }
     }
+
+    /// Alt+1 to Alt+9 show the tab with that number, returns whether it was one of them.
+    fn switch_tab(&mut self, event: KeyEvent) -> bool {
+        if event.kind != KeyEventKind::Press || event.modifiers != KeyModifiers::ALT {
+            return false;
+        }
+        let KeyCode::Char(digit @ '1'..='9') = event.code else {
+            return false;
+        };
+        let index = usize::from(digit as u8 - b'1');
+        if index >= self.tabs.len() {
+            return false;
+        }
+        self.active = index;
+        self.tabs[index].unread = false;
+        true
+    }
+
+    /// Alt+Left widens the user list and Alt+Right narrows it, returns whether it was one of
+    /// them.
+    fn resize_sidebar(&mut self, event: KeyEvent) -> bool {
+        if event.kind != KeyEventKind::Press || event.modifiers != KeyModifiers::ALT {
+            return false;
+        }
+        let width = match event.code {
+            KeyCode::Left => self.sidebar_width.saturating_add(SIDEBAR_STEP),
+            KeyCode::Right => self.sidebar_width.saturating_sub(SIDEBAR_STEP),
+            _ => return false,
+        };
+        self.set_sidebar_width(width);
+        true
+    }
+
+    fn set_sidebar_width(&mut self, width: u16) {
+        self.sidebar_width = width.clamp(SIDEBAR_MIN_WIDTH, SIDEBAR_MAX_WIDTH);
+    }
 }
 
 struct ClientListWidget {
     clients: Vec<ClientItem>,
     list_state: ListState,
+    /// Where it was last rendered, to map clicks to users.
+    area: Rect,
+    theme: Theme,
 }
 
 struct ClientItem {
     id: ClientId,
+    /// Shown dimmed, their messages are hidden.
+    ignored: bool,
+    /// When they last sent anything, as far as we know.
+    last_active: Instant,
 }
 
-impl From<&'_ ClientItem> for ListItem<'_> {
-    fn from(value: &'_ ClientItem) -> Self {
-        ListItem::new(format!("⚡ {}", value.id.name))
+impl ClientItem {
+    const PREFIX: &str = "⚡ ";
+    /// Inactivity after which how long they have been idle is shown next to the name.
+    const IDLE_AFTER: Duration = Duration::from_secs(60);
+
+    /// How long they have been idle, e.g. ` (2m)`, empty while active.
+    fn idle_suffix(&self) -> String {
+        let idle = self.last_active.elapsed();
+        match idle >= Self::IDLE_AFTER {
+            true => format!(" ({})", format_elapsed(idle)),
+            false => String::new(),
+        }
+    }
+
+    /// The item with its name cut to fit in `width` columns, in the away color while idle.
+    fn list_item(&self, width: usize, theme: &Theme) -> ListItem<'static> {
+        let suffix = self.idle_suffix();
+        let name = truncate(
+            &self.id.name,
+            width.saturating_sub(Self::PREFIX.width() + suffix.width()),
+        );
+        let style = match suffix.is_empty() {
+            true => Style::new(),
+            false => Style::new().fg(theme.away),
+        };
+        let item =
+            ListItem::new(Line::from(format!("{}{}{}", Self::PREFIX, name, suffix))).style(style);
+        if self.ignored { item.dim() } else { item }
+    }
+}
+
+/// `elapsed` in its largest whole unit, e.g. `2m` or `3h`.
+fn format_elapsed(elapsed: Duration) -> String {
+    let minutes = elapsed.as_secs() / 60;
+    match minutes {
+        0..60 => format!("{}m", minutes),
+        60..1440 => format!("{}h", minutes / 60),
+        _ => format!("{}d", minutes / 1440),
     }
 }
 
 impl ClientListWidget {
-    fn new() -> Self {
+    fn new(theme: Theme) -> Self {
         Self {
             clients: vec![],
             list_state: ListState::default(),
+            area: Rect::default(),
+            theme,
+        }
+    }
+
+    /// Marks `id` as active just now, e.g. when a message from them arrives.
+    fn touch(&mut self, id: &ClientId) {
+        if let Some(client) = self.clients.iter_mut().find(|client| client.id == *id) {
+            client.last_active = Instant::now();
+        }
+    }
+
+    fn set_ignored(&mut self, name: &str, ignored: bool) {
+        for client in self.clients.iter_mut() {
+            if client.id.name == name {
+                client.ignored = ignored;
+            }
         }
     }
 }
@@ -417,33 +1475,181 @@ impl ClientListWidget {
 impl Widget for &mut ClientListWidget {
     fn render(self, area: Rect, buf: &mut Buffer) {
         // a block with a right aligned title with the loading state on the right
-        let block = Block::bordered()
-            .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+        let mut block = Block::bordered()
+            .border_style(self.theme.border_style())
             .title("Users Online");
 
-        // a table with the list of pull requests
-        let items = self.clients.iter();
+        // Less the highlight symbol's column.
+        let width = usize::from(block.inner(area).width).saturating_sub(1);
+        let items = self
+            .clients
+            .iter()
+            .map(|client| client.list_item(width, &self.theme));
+        // The selected user's full name, in case it had to be cut.
+        let selected = self
+            .list_state
+            .selected()
+            .and_then(|index| self.clients.get(index));
+        if let Some(client) = selected {
+            let suffix = client.idle_suffix();
+            if ClientItem::PREFIX.width() + client.id.name.width() + suffix.width() > width {
+                block = block.title_bottom(client.id.name.clone());
+            }
+        }
         let list = List::new(items)
             .block(block)
             .highlight_spacing(HighlightSpacing::Always)
             .highlight_symbol(">")
-            .highlight_style(Style::new().on_blue());
+            .highlight_style(self.theme.selection_style());
 
         StatefulWidget::render(list, area, buf, &mut self.list_state);
+        self.area = area;
     }
 }
 
 struct MessageListWidget {
     messages: Vec<Message>,
     list_state: ListState,
+    channel: String,
+    /// Where it was last rendered and the height of each message then, to map clicks to
+    /// messages.
+    area: Rect,
+    heights: Vec<usize>,
+    /// Who we joined as, set once joined.
+    own_id: Option<ClientId>,
+    theme: Theme,
 }
 
 impl MessageListWidget {
-    fn new() -> Self {
+    fn new(theme: Theme) -> Self {
         Self {
             messages: vec![],
             list_state: ListState::default(),
+            channel: String::new(),
+            area: Rect::default(),
+            heights: vec![],
+            own_id: None,
+            theme,
+        }
+    }
+    /// Columns messages are wrapped to inside the list's borders, less the highlight symbol.
+    fn content_width(inner: Rect) -> usize {
+        usize::from(inner.width).saturating_sub(1)
+    }
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.list_state = ListState::default();
+        self.heights.clear();
+    }
+    /// Plain text rendering of the messages, one `[name]: content` entry per message.
+    fn transcript(&self) -> String {
+        let mut transcript = String::new();
+        for message in self.messages.iter() {
+            let header = message.header();
+            let indent = " ".repeat(header.width() + 1);
+            for (n, line) in message.content.lines().enumerate() {
+                if n == 0 {
+                    transcript.push_str(&header);
+                    transcript.push(' ');
+                } else {
+                    transcript.push_str(&indent);
+                }
+                transcript.push_str(line);
+                transcript.push('\n');
+            }
         }
+        transcript
+    }
+    /// The most recent message `id` from `sender`. Ids are picked by senders, so another
+    /// client's message may carry the same one.
+    fn find_mut(&mut self, id: MessageId, sender: &ClientId) -> Option<&mut Message> {
+        self.messages
+            .iter_mut()
+            .rev()
+            .find(|message| message.is_from(id, sender))
+    }
+    /// Id of our most recent message the server has acked.
+    fn last_own_id(&self, own_id: &ClientId) -> Option<MessageId> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|message| {
+                message.status == DeliveryStatus::Acked
+                    && matches!(&message.sender, MessageSender::Client(sender) if sender == own_id)
+            })
+            .and_then(|message| message.id)
+    }
+    fn remove(&mut self, id: MessageId, sender: &ClientId) {
+        self.messages.retain(|message| !message.is_from(id, sender));
+        if self
+            .list_state
+            .selected()
+            .is_some_and(|index| index >= self.messages.len())
+        {
+            self.list_state.select(self.messages.len().checked_sub(1));
+        }
+    }
+    fn selected(&self) -> Option<&Message> {
+        self.list_state
+            .selected()
+            .and_then(|index| self.messages.get(index))
+    }
+    /// Selects the next (newer) message, stopping at the newest.
+    fn select_next(&mut self) {
+        if let Some(index) = self.list_state.selected() {
+            let last = self.messages.len().saturating_sub(1);
+            self.list_state.select(Some((index + 1).min(last)));
+        }
+    }
+    /// Selects the previous (older) message, starting from the newest.
+    fn select_previous(&mut self) {
+        match self.list_state.selected() {
+            Some(index) => self.list_state.select(Some(index.saturating_sub(1))),
+            None if !self.messages.is_empty() => {
+                self.list_state.select(Some(self.messages.len() - 1))
+            }
+            None => (),
+        }
+    }
+    /// Messages that fit in the view from where it's scrolled to, at least one.
+    fn page_len(&self) -> usize {
+        let viewport = usize::from(self.area.height.saturating_sub(2));
+        let mut lines = 0;
+        let fitting = self
+            .heights
+            .iter()
+            .skip(self.list_state.offset())
+            .take_while(|&&height| {
+                lines += height;
+                lines <= viewport
+            })
+            .count();
+        fitting.max(1)
+    }
+    /// Moves the selection a screenful older, starting from the newest.
+    fn page_up(&mut self) {
+        if self.messages.is_empty() {
+            return;
+        }
+        let index = self.list_state.selected().unwrap_or(self.messages.len());
+        self.list_state
+            .select(Some(index.saturating_sub(self.page_len())));
+    }
+    /// Moves the selection a screenful newer, stopping at the newest.
+    fn page_down(&mut self) {
+        if let Some(index) = self.list_state.selected() {
+            let last = self.messages.len().saturating_sub(1);
+            self.list_state
+                .select(Some((index + self.page_len()).min(last)));
+        }
+    }
+    fn select_first(&mut self) {
+        if !self.messages.is_empty() {
+            self.list_state.select(Some(0));
+        }
+    }
+    fn select_last(&mut self) {
+        self.list_state.select(self.messages.len().checked_sub(1));
     }
     fn scroll_up(&mut self) {
         self.list_state.scroll_up_by(1);
@@ -453,31 +1659,122 @@ impl MessageListWidget {
     }
 }
 
+#[derive(Debug, Clone)]
+enum MessageSender {
+    Client(ClientId),
+    /// Generated locally, e.g. command feedback.
+    System,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeliveryStatus {
+    /// Sent by us, waiting for the server ack.
+    Pending,
+    /// Sent by us and acked by the server.
+    Acked,
+    /// Received from the server.
+    Delivered,
+    /// Sent by us, the ack never came.
+    Failed,
+}
+
 #[derive(Debug, Clone)]
 struct Message {
-    id: ClientId,
+    /// `None` for messages that never went through the server.
+    id: Option<MessageId>,
+    sender: MessageSender,
     content: String,
+    status: DeliveryStatus,
+    /// The sender changed the content after sending.
+    edited: bool,
+    reply_to: Option<MessageId>,
 }
 
-impl From<&'_ Message> for ListItem<'_> {
-    fn from(value: &'_ Message) -> Self {
+impl Message {
+    /// Whether this is message `id` sent by `sender`.
+    fn is_from(&self, id: MessageId, sender: &ClientId) -> bool {
+        self.id == Some(id) && matches!(&self.sender, MessageSender::Client(from) if from == sender)
+    }
+    /// Generated locally, e.g. command feedback.
+    fn system(content: String) -> Self {
+        Self {
+            id: None,
+            sender: MessageSender::System,
+            content,
+            status: DeliveryStatus::Delivered,
+            edited: false,
+            reply_to: None,
+        }
+    }
+    /// Lines taken up in the message view, `width` columns wide.
+    fn line_count(&self, width: usize) -> usize {
+        // Colors don't change the height.
+        self.list_item(None, width, None, &Theme::default())
+            .height()
+    }
+    fn header(&self) -> String {
+        match &self.sender {
+            MessageSender::Client(id) => format!("[{}]:", id.name),
+            MessageSender::System => "*".to_owned(),
+        }
+    }
+    /// Renders the message wrapped to `width` columns, `replied` is the message it replies to
+    /// if still in view. Messages from `own_id` get the own-message color.
+    fn list_item(
+        &self,
+        replied: Option<&Message>,
+        width: usize,
+        own_id: Option<&ClientId>,
+        theme: &Theme,
+    ) -> ListItem<'static> {
         let mut text = Text::default();
 
-        let full_header = format!("[{}]: ", value.id.name);
+        if let Some(reply_line) = self.reply_to.map(|_| reply_preview(replied, theme)) {
+            text.push_line(reply_line);
+        }
+
+        let header = self.header();
+        let full_header = format!("{} ", header);
 
         let header_width = full_header.width();
 
+        let header_style = match &self.sender {
+            MessageSender::Client(id) => {
+                let color = match Some(id) == own_id {
+                    true => theme.own_message,
+                    false => theme.sender,
+                };
+                Style::new().fg(color).bg(theme.header_background).bold()
+            }
+            MessageSender::System => Style::new().fg(theme.system_message).italic(),
+        };
+
         let mut header_line = Line::default();
-        header_line.push_span(
-            Span::from(format!("[{}]:", value.id.name))
-                .style(Style::new().fg(Color::Cyan).bg(Color::Black).bold()),
-        );
-        header_line.push_span(Span::from(" ").style(Style::new().fg(Color::Cyan)));
+        header_line.push_span(Span::from(header).style(header_style));
+        header_line.push_span(Span::from(" "));
+
+        let content_style = match (&self.sender, self.status) {
+            (_, DeliveryStatus::Pending) => Style::new().fg(theme.muted),
+            (_, DeliveryStatus::Failed) => Style::new().fg(theme.error),
+            (MessageSender::System, _) => Style::new().fg(theme.system_message),
+            (MessageSender::Client(_), DeliveryStatus::Acked | DeliveryStatus::Delivered) => {
+                Style::new().fg(theme.text)
+            }
+        };
+        let status_marker = match self.status {
+            DeliveryStatus::Acked => Some(Span::from(" ✓").style(Style::new().fg(theme.success))),
+            DeliveryStatus::Failed => Some(Span::from(" ⚠").style(Style::new().fg(theme.error))),
+            DeliveryStatus::Pending | DeliveryStatus::Delivered => None,
+        };
 
-        let mut lines = value.content.lines();
+        let content_width = width.saturating_sub(header_width).max(1);
+        let mut lines = self
+            .content
+            .lines()
+            .flat_map(|line| wrap(line, content_width));
 
         if let Some(line) = lines.next() {
-            header_line.push_span(Span::from(line.to_owned()).style(Style::new()));
+            header_line.push_span(Span::from(line).style(content_style));
         }
 
         text.push_line(header_line);
@@ -486,10 +1783,27 @@ impl From<&'_ Message> for ListItem<'_> {
             let mut line = Line::default();
 
             line.push_span(Span::from(format!("{:width$}", "", width = header_width)));
-            line.push_span(Span::from(format!("{}", line_content)).style(Style::new()));
+            line.push_span(Span::from(line_content).style(content_style));
 
             text.push_line(line);
         }
+
+        let mut suffix = vec![];
+        if self.edited {
+            suffix.push(Span::from(" (edited)").style(Style::new().fg(theme.muted)));
+        }
+        suffix.extend(status_marker);
+        let suffix_width: usize = suffix.iter().map(Span::width).sum();
+        if let Some(last_line) = text.lines.last_mut() {
+            // Wraps like the content, onto a line of its own if it doesn't fit.
+            if last_line.width() + suffix_width > width && header_width + suffix_width <= width {
+                let mut line = Line::from(" ".repeat(header_width));
+                line.spans.extend(suffix);
+                text.push_line(line);
+            } else {
+                last_line.spans.extend(suffix);
+            }
+        }
         ListItem::new(text)
     }
 }
@@ -497,18 +1811,419 @@ impl From<&'_ Message> for ListItem<'_> {
 impl Widget for &mut MessageListWidget {
     fn render(self, area: Rect, buf: &mut Buffer) {
         // a block with a right aligned title with the loading state on the right
+        let title = if self.channel.is_empty() {
+            "Messages".to_owned()
+        } else {
+            format!("Messages #{}", self.channel)
+        };
         let block = Block::bordered()
-            .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
-            .title("Messages");
+            .border_style(self.theme.border_style())
+            .title(title);
+
+        let inner = block.inner(area);
+        let viewport = inner.height as usize;
+        let width = MessageListWidget::content_width(inner);
 
-        // a table with the list of pull requests
-        let items = self.messages.iter();
+        // Later messages overwrite earlier ones, so replies find the most recent with an id.
+        let by_id: HashMap<MessageId, &Message> = self
+            .messages
+            .iter()
+            .filter_map(|message| Some((message.id?, message)))
+            .collect();
+        let items: Vec<ListItem> = self
+            .messages
+            .iter()
+            .map(|message| {
+                let replied = message.reply_to.and_then(|id| by_id.get(&id).copied());
+                message.list_item(replied, width, self.own_id.as_ref(), &self.theme)
+            })
+            .collect();
+        let heights: Vec<usize> = items.iter().map(ListItem::height).collect();
         let list = List::new(items)
             .block(block)
             .highlight_spacing(HighlightSpacing::Always)
             .highlight_symbol(">")
-            .highlight_style(Style::new().on_blue());
+            .highlight_style(self.theme.selection_style());
 
         StatefulWidget::render(list, area, buf, &mut self.list_state);
+
+        // Measured in lines rather than messages, which can span several.
+        let total: usize = heights.iter().sum();
+        if total > viewport {
+            let offset = self.list_state.offset().min(heights.len());
+            let mut state = ScrollbarState::new(total - viewport + 1)
+                .position(heights[..offset].iter().sum())
+                .viewport_content_length(viewport);
+            let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight)
+                .begin_symbol(None)
+                .end_symbol(None);
+            let area = area.inner(Margin {
+                vertical: 1,
+                horizontal: 0,
+            });
+            StatefulWidget::render(scrollbar, area, buf, &mut state);
+        }
+        self.area = area;
+        self.heights = heights;
+    }
+}
+
+/// Index of the item at `position` in a bordered list rendered in `area`, scrolled to `offset`
+/// and with items of the given heights. `None` for the border or past the last item.
+fn item_at(
+    area: Rect,
+    offset: usize,
+    heights: impl IntoIterator<Item = usize>,
+    position: Position,
+) -> Option<usize> {
+    let inner = area.inner(Margin::new(1, 1));
+    if !inner.contains(position) {
+        return None;
+    }
+    let mut row = usize::from(position.y - inner.y);
+    for (index, height) in heights.into_iter().enumerate().skip(offset) {
+        if row < height {
+            return Some(index);
+        }
+        row -= height;
+    }
+    None
+}
+
+/// Offset scrolling items of the given heights to the end of a view `height` rows tall, so the
+/// last item is fully visible.
+fn scroll_to_end(
+    heights: impl DoubleEndedIterator<Item = usize> + ExactSizeIterator,
+    height: usize,
+) -> usize {
+    let last = heights.len().saturating_sub(1);
+    let mut remaining = height;
+    for (n, item_height) in heights.enumerate().rev() {
+        match remaining.checked_sub(item_height) {
+            Some(0) => return n,
+            // The item doesn't fit, the one after it is the first fully visible.
+            None => return (n + 1).min(last),
+            Some(x) => remaining = x,
+        }
+    }
+    0
+}
+
+/// The `↳ replying to name: ...` line shown above a reply.
+fn reply_preview(replied: Option<&Message>, theme: &Theme) -> Line<'static> {
+    const PREVIEW_LENGTH: usize = 40;
+
+    let style = Style::new().fg(theme.system_message).italic();
+    let Some(replied) = replied else {
+        return Line::from(
+            Span::from("↳ replying to a message that is no longer shown").style(style),
+        );
+    };
+    let sender = match &replied.sender {
+        MessageSender::Client(id) => id.name.as_str(),
+        MessageSender::System => "system",
+    };
+    let first_line = replied.content.lines().next().unwrap_or_default();
+    let mut excerpt: String = first_line.chars().take(PREVIEW_LENGTH).collect();
+    if excerpt.len() < replied.content.len() {
+        excerpt.push('…');
+    }
+    Line::from(Span::from(format!("↳ replying to {}: {}", sender, excerpt)).style(style))
+}
+
+/// Splits `line` into pieces at most `width` columns wide, without splitting wide characters.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let mut pieces = vec![String::new()];
+    let mut used = 0;
+    for c in line.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if used > 0 && used + char_width > width {
+            pieces.push(String::new());
+            used = 0;
+        }
+        if let Some(piece) = pieces.last_mut() {
+            piece.push(c);
+        }
+        used += char_width;
+    }
+    pieces
+}
+
+/// `text` cut to at most `width` columns, ending with `…` if it was cut. Measured in display
+/// width so wide characters aren't split.
+fn truncate(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_owned();
+    }
+    let mut truncated = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if used + char_width + 1 > width {
+            break;
+        }
+        truncated.push(c);
+        used += char_width;
+    }
+    if width > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// Expands a leading `~` to the user's home directory.
+fn expand_tilde(path: &str) -> PathBuf {
+    let home = || std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => match home() {
+            Some(home) => PathBuf::from(home).join(rest.trim_start_matches(['/', '\\'])),
+            None => PathBuf::from(path),
+        },
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::{
+        ClientId, ClientMessage, MessageId, PeerAddr, ResumeToken, ServerLimits, ServerMessage,
+        codec::CompressionSettings, stream::split_message_stream,
+    };
+    use futures::{SinkExt, StreamExt};
+    use ratatui::layout::{Position, Rect};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        app::{
+            App, DeliveryStatus, Message, MessageListWidget, MessageSender,
+            event::InteractiveEvent, format_elapsed, item_at, resources::AppResources,
+            scroll_to_end, truncate, wrap,
+        },
+        config::ClientConfig,
+        theme::Theme,
+    };
+
+    fn message(content: &str, status: DeliveryStatus) -> Message {
+        Message {
+            id: None,
+            sender: MessageSender::System,
+            content: content.to_owned(),
+            status,
+            edited: false,
+            reply_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connection_resumes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let token = ResumeToken::random();
+        // The server end of the starting connection is already gone.
+        let resources = AppResources::disconnected(ClientConfig::default())
+            .await
+            .reconnect_to(&host, token);
+        let resources = Arc::new(resources);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut write_msg, mut read_msg) =
+                split_message_stream::<ServerMessage, ClientMessage>(
+                    stream,
+                    CompressionSettings::default(),
+                )
+                .await
+                .unwrap();
+            let request = read_msg.next().await.unwrap().unwrap();
+            assert!(
+                matches!(request, ClientMessage::Resume { token: resumed } if resumed == token)
+            );
+            let accept = ServerMessage::AcceptJoin {
+                id: ClientId {
+                    name: "test".to_owned(),
+                    addr: PeerAddr::Virtual(1),
+                },
+                resume_token: Some(token),
+                limits: ServerLimits {
+                    max_message_length: 4096,
+                    messages_per_second: 0,
+                    max_clients: 256,
+                },
+            };
+            write_msg.send(accept).await.unwrap();
+            (write_msg, read_msg)
+        });
+        let (event_sender, mut events) = mpsc::channel(16);
+        let network = tokio::spawn(App::network_loop(Arc::clone(&resources), event_sender));
+
+        let resumed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(event) = events.recv().await {
+                if let InteractiveEvent::SystemMessage { content } = event {
+                    return content;
+                }
+            }
+            panic!("network loop stopped without resuming");
+        });
+        assert_eq!(resumed.await.expect("should resume"), "Reconnected.");
+        assert_eq!(resources.id().addr, PeerAddr::Virtual(1));
+
+        // Leaving, the loop ends once the server closes the connection.
+        resources.set_leaving();
+        drop(server.await.unwrap());
+        tokio::time::timeout(Duration::from_secs(5), network)
+            .await
+            .expect("network loop should stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_item_at() {
+        let area = Rect::new(10, 5, 20, 8);
+        let heights = [2, 1, 3, 1];
+        let at = |offset, x, y| item_at(area, offset, heights, Position::new(x, y));
+
+        assert_eq!(at(0, 12, 6), Some(0));
+        assert_eq!(at(0, 12, 7), Some(0));
+        assert_eq!(at(0, 12, 8), Some(1));
+        assert_eq!(at(0, 12, 11), Some(2));
+        assert_eq!(at(1, 12, 6), Some(1));
+        assert_eq!(at(1, 12, 10), Some(3));
+        // Past the last item, on the border and outside the list.
+        assert_eq!(at(1, 12, 11), None);
+        assert_eq!(at(0, 12, 5), None);
+        assert_eq!(at(0, 10, 6), None);
+        assert_eq!(at(0, 40, 6), None);
+    }
+
+    #[test]
+    fn test_scroll_to_end() {
+        // A tall message above a short one in a 5 row view, only the short one fits.
+        let messages = [
+            message("first", DeliveryStatus::Delivered),
+            message(
+                "a long message wrapping onto many lines, more than the view has",
+                DeliveryStatus::Delivered,
+            ),
+            message("newest", DeliveryStatus::Delivered),
+        ];
+        let heights = messages.iter().map(|message| message.line_count(12));
+        assert!(messages[1].line_count(12) > 4);
+        let offset = scroll_to_end(heights, 5);
+        assert_eq!(offset, 2);
+        let area = Rect::new(0, 0, 14, 7);
+        let heights = messages.iter().map(|message| message.line_count(12));
+        assert_eq!(item_at(area, offset, heights, Position::new(1, 1)), Some(2));
+
+        assert_eq!(scroll_to_end([1, 2, 4].into_iter(), 5), 2);
+        assert_eq!(scroll_to_end([1, 2, 2].into_iter(), 5), 0);
+        assert_eq!(scroll_to_end([1, 2, 2].into_iter(), 4), 1);
+        // The newest message taller than the view is shown from its top.
+        assert_eq!(scroll_to_end([1, 9].into_iter(), 5), 1);
+        assert_eq!(scroll_to_end([].into_iter(), 5), 0);
+    }
+
+    #[test]
+    fn test_page_messages() {
+        let mut messages = MessageListWidget::new(Theme::default());
+        messages.messages = (0..10)
+            .map(|n| message(&n.to_string(), DeliveryStatus::Delivered))
+            .collect();
+        messages.heights = vec![1; 10];
+        // Three lines inside the borders.
+        messages.area = Rect::new(0, 0, 20, 5);
+        let selected = |messages: &MessageListWidget| messages.list_state.selected();
+
+        messages.page_down();
+        assert_eq!(selected(&messages), None);
+        messages.page_up();
+        assert_eq!(selected(&messages), Some(7));
+        messages.page_up();
+        messages.page_up();
+        assert_eq!(selected(&messages), Some(1));
+        messages.page_up();
+        assert_eq!(selected(&messages), Some(0));
+        messages.page_down();
+        assert_eq!(selected(&messages), Some(3));
+        messages.select_last();
+        messages.page_down();
+        assert_eq!(selected(&messages), Some(9));
+        messages.select_first();
+        assert_eq!(selected(&messages), Some(0));
+    }
+
+    #[test]
+    fn test_remove_matches_sender() {
+        let client = |name: &str| ClientId {
+            name: name.to_owned(),
+            addr: PeerAddr::Virtual(0),
+        };
+        let (alice, mallory) = (client("alice"), client("mallory"));
+        let sent = |sender: &ClientId, content| Message {
+            id: Some(MessageId(1)),
+            sender: MessageSender::Client(sender.clone()),
+            ..message(content, DeliveryStatus::Delivered)
+        };
+        let mut messages = MessageListWidget::new(Theme::default());
+        messages.messages = vec![sent(&alice, "hi"), sent(&mallory, "same id")];
+
+        assert!(messages.find_mut(MessageId(1), &client("bob")).is_none());
+        messages.remove(MessageId(1), &mallory);
+        assert_eq!(messages.messages.len(), 1);
+        assert_eq!(
+            messages.find_mut(MessageId(1), &alice).unwrap().content,
+            "hi"
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("alice", 5), "alice");
+        assert_eq!(truncate("alice", 4), "ali…");
+        assert_eq!(truncate("alice", 0), "");
+        // Wide characters take two columns and are never split.
+        assert_eq!(truncate("日本語の名前", 12), "日本語の名前");
+        assert_eq!(truncate("日本語の名前", 6), "日本…");
+        assert_eq!(truncate("日本語の名前", 5), "日本…");
+        assert_eq!(truncate("🚀🚀🚀", 4), "🚀…");
+    }
+
+    #[test]
+    fn test_format_elapsed() {
+        let idle = |secs| format_elapsed(Duration::from_secs(secs));
+        assert_eq!(idle(60), "1m");
+        assert_eq!(idle(59 * 60 + 59), "59m");
+        assert_eq!(idle(60 * 60), "1h");
+        assert_eq!(idle(23 * 60 * 60 + 3599), "23h");
+        assert_eq!(idle(3 * 24 * 60 * 60), "3d");
+    }
+
+    #[test]
+    fn test_wrap_wide_characters() {
+        assert_eq!(wrap("", 4), [""]);
+        assert_eq!(wrap("abcdef", 4), ["abcd", "ef"]);
+        // Two columns each, a piece never ends in half a character.
+        assert_eq!(wrap("日本語の", 4), ["日本", "語の"]);
+        assert_eq!(wrap("日本語", 5), ["日本", "語"]);
+        assert_eq!(wrap("a🚀b🚀", 3), ["a🚀", "b🚀"]);
+        // Too narrow for any character still makes progress.
+        assert_eq!(wrap("日本", 1), ["日", "本"]);
+    }
+
+    #[test]
+    fn test_message_line_count() {
+        // The `* ` header takes two columns, leaving eight for content.
+        let width = 10;
+        let line_count = |content| message(content, DeliveryStatus::Delivered).line_count(width);
+        assert_eq!(line_count("hello"), 1);
+        assert_eq!(line_count("hello\nworld"), 2);
+        assert_eq!(line_count("日本語の"), 1);
+        assert_eq!(line_count("日本語の名前"), 2);
+        assert_eq!(line_count("🚀🚀🚀🚀🚀🚀🚀🚀🚀"), 3);
+        // The delivery marker wraps too.
+        let acked = |content| message(content, DeliveryStatus::Acked).line_count(width);
+        assert_eq!(acked("日本語の"), 2);
+        assert_eq!(acked("日本語"), 1);
     }
 }
@@ -0,0 +1,62 @@
+use log::warn;
+
+/// Handle to the system clipboard, backing the `"+` register.
+///
+/// Failures such as a missing display over SSH are logged and otherwise ignored.
+#[derive(Default)]
+pub struct Clipboard {
+    #[cfg(feature = "clipboard")]
+    inner: Option<arboard::Clipboard>,
+}
+
+impl Clipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lazily connects so the clipboard stays owned for the lifetime of the app.
+    #[cfg(feature = "clipboard")]
+    fn inner(&mut self) -> Option<&mut arboard::Clipboard> {
+        if self.inner.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.inner = Some(clipboard),
+                Err(err) => {
+                    warn!("System clipboard unavailable: {}", err);
+                    return None;
+                }
+            }
+        }
+        self.inner.as_mut()
+    }
+
+    #[cfg(feature = "clipboard")]
+    pub fn set_text(&mut self, text: String) {
+        if let Some(clipboard) = self.inner() {
+            if let Err(err) = clipboard.set_text(text) {
+                warn!("Failed to copy to system clipboard: {}", err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    pub fn set_text(&mut self, _text: String) {
+        warn!("Built without clipboard support, ignoring copy.");
+    }
+
+    #[cfg(feature = "clipboard")]
+    pub fn get_text(&mut self) -> Option<String> {
+        match self.inner()?.get_text() {
+            Ok(text) => Some(text),
+            Err(err) => {
+                warn!("Failed to paste from system clipboard: {}", err);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    pub fn get_text(&mut self) -> Option<String> {
+        warn!("Built without clipboard support, ignoring paste.");
+        None
+    }
+}
@@ -0,0 +1,690 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::PathBuf,
+};
+
+use log::warn;
+
+use crate::{app::expand_tilde, config::LineNumbers};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Not an editor command: {0}")]
+    Unknown(String),
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+    #[error("Trailing characters: {0}")]
+    Trailing(String),
+    #[error("Usage: {0}")]
+    Usage(&'static str),
+    #[error("Not supported: {0}")]
+    Unsupported(&'static str),
+}
+
+/// A `:` command typed in the editor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `:q`, `:q!` quits the same way since drafts are never saved.
+    Quit,
+    /// `:w`, sends the draft.
+    Write,
+    /// `:wq`, sends the draft and quits.
+    WriteQuit,
+    Clear,
+    Stats,
+    Info,
+    Delete,
+    Help,
+    SetLineNumbers(LineNumbers),
+    Edit {
+        content: String,
+    },
+    Save {
+        path: String,
+    },
+    Ignore {
+        name: String,
+    },
+    Unignore {
+        name: String,
+    },
+    Kick {
+        name: String,
+    },
+    Ban {
+        name: String,
+    },
+    Join {
+        channel: String,
+    },
+    Msg {
+        name: String,
+        content: String,
+    },
+    Whois {
+        name: String,
+    },
+    /// A text macro from the config, inserts its expansion in the draft.
+    Insert {
+        text: String,
+    },
+}
+
+impl Command {
+    /// Parses a command line such as `:msg bob hi`, the leading `:` and surrounding
+    /// whitespace are optional. Returns `None` for an empty line.
+    pub fn parse(line: &str) -> Option<Result<Self, CommandError>> {
+        let (name, args) = split(line)?;
+        Some(Self::parse_parts(name, args))
+    }
+
+    /// Like [`Command::parse`], also expanding the names of `macros` to their text. Built-in
+    /// commands win over macros of the same name.
+    pub fn parse_with_macros(
+        line: &str,
+        macros: &BTreeMap<String, String>,
+    ) -> Option<Result<Self, CommandError>> {
+        let (name, args) = split(line)?;
+        let result = match (Self::parse_parts(name, args), macros.get(name)) {
+            (Err(CommandError::Unknown(_)), Some(_)) if !args.is_empty() => {
+                Err(CommandError::Trailing(args.to_owned()))
+            }
+            (Err(CommandError::Unknown(_)), Some(text)) => Ok(Self::Insert { text: text.clone() }),
+            (result, _) => result,
+        };
+        Some(result)
+    }
+
+    fn parse_parts(name: &str, args: &str) -> Result<Self, CommandError> {
+        // The server keys sessions by name and can't rename a live one.
+        if name == "nick" {
+            return Err(CommandError::Unsupported(
+                "renaming, reconnect with another name instead",
+            ));
+        }
+        let info = CommandInfo::find(name).ok_or_else(|| CommandError::Unknown(name.to_owned()))?;
+        // Commands without arguments don't silently drop what follows them.
+        if !args.is_empty() && !info.takes_arguments() {
+            return Err(CommandError::Trailing(args.to_owned()));
+        }
+        (info.parse)(args, info.usage)
+    }
+}
+
+/// Parses a built-in command's arguments, `usage` is its entry's usage for errors.
+type ParseFn = fn(args: &str, usage: &'static str) -> Result<Command, CommandError>;
+
+/// A built-in command, what the parser dispatches on and `:help` lists.
+#[derive(Debug)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// The command with its arguments, e.g. `:msg <name> <message>`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    parse: ParseFn,
+}
+
+impl CommandInfo {
+    /// The built-in command called `name` or one of its aliases.
+    pub fn find(name: &str) -> Option<&'static Self> {
+        COMMANDS
+            .iter()
+            .find(|info| info.name == name || info.aliases.contains(&name))
+    }
+
+    pub fn takes_arguments(&self) -> bool {
+        self.usage.contains(' ')
+    }
+
+    /// Whether the first argument is a user name, which Tab completes.
+    pub fn takes_name(&self) -> bool {
+        self.usage
+            .split_once(' ')
+            .is_some_and(|(_, args)| args.starts_with("<name>"))
+    }
+}
+
+/// Every built-in command, in the order `:help` lists them.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "q",
+        aliases: &["quit", "q!", "quit!"],
+        usage: ":q",
+        help: "Quit, the draft is dropped",
+        parse: |_, _| Ok(Command::Quit),
+    },
+    CommandInfo {
+        name: "w",
+        aliases: &["write"],
+        usage: ":w",
+        help: "Send the draft",
+        parse: |_, _| Ok(Command::Write),
+    },
+    CommandInfo {
+        name: "wq",
+        aliases: &["qw", "wq!", "x"],
+        usage: ":wq",
+        help: "Send the draft and quit",
+        parse: |_, _| Ok(Command::WriteQuit),
+    },
+    CommandInfo {
+        name: "msg",
+        aliases: &[],
+        usage: ":msg <name> <message>",
+        help: "Send a private message",
+        parse: |args, usage| {
+            let Some((name, content)) = args.split_once(char::is_whitespace) else {
+                return Err(CommandError::Usage(usage));
+            };
+            Ok(Command::Msg {
+                name: name.to_owned(),
+                content: required(content, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "edit",
+        aliases: &[],
+        usage: ":edit <message>",
+        help: "Replace your last message",
+        parse: |args, usage| {
+            Ok(Command::Edit {
+                content: required(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "delete",
+        aliases: &[],
+        usage: ":delete",
+        help: "Delete your last message",
+        parse: |_, _| Ok(Command::Delete),
+    },
+    CommandInfo {
+        name: "join",
+        aliases: &[],
+        usage: ":join <channel>",
+        help: "Move to another channel",
+        parse: |args, usage| {
+            Ok(Command::Join {
+                channel: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "whois",
+        aliases: &[],
+        usage: ":whois <name>",
+        help: "Show when someone joined and was last active",
+        parse: |args, usage| {
+            Ok(Command::Whois {
+                name: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "ignore",
+        aliases: &[],
+        usage: ":ignore <name>",
+        help: "Hide someone's messages",
+        parse: |args, usage| {
+            Ok(Command::Ignore {
+                name: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "unignore",
+        aliases: &[],
+        usage: ":unignore <name>",
+        help: "Show someone's messages again",
+        parse: |args, usage| {
+            Ok(Command::Unignore {
+                name: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "clear",
+        aliases: &[],
+        usage: ":clear",
+        help: "Clear the messages shown",
+        parse: |_, _| Ok(Command::Clear),
+    },
+    CommandInfo {
+        name: "save",
+        aliases: &[],
+        usage: ":save <path>",
+        help: "Save the messages shown to a file",
+        parse: |args, usage| {
+            Ok(Command::Save {
+                path: required(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "set",
+        aliases: &[],
+        usage: ":set <option>",
+        help: "Line numbers: number, relativenumber or nonumber",
+        parse: |args, usage| {
+            let line_numbers = match args {
+                "number" | "nu" => LineNumbers::Absolute,
+                "relativenumber" | "rnu" => LineNumbers::Relative,
+                "nonumber" | "nonu" | "norelativenumber" | "nornu" => LineNumbers::Off,
+                "" => return Err(CommandError::Usage(usage)),
+                option => return Err(CommandError::UnknownOption(option.to_owned())),
+            };
+            Ok(Command::SetLineNumbers(line_numbers))
+        },
+    },
+    CommandInfo {
+        name: "info",
+        aliases: &[],
+        usage: ":info",
+        help: "Show the connection and the server's limits",
+        parse: |_, _| Ok(Command::Info),
+    },
+    CommandInfo {
+        name: "stats",
+        aliases: &[],
+        usage: ":stats",
+        help: "Show server statistics, admins only",
+        parse: |_, _| Ok(Command::Stats),
+    },
+    CommandInfo {
+        name: "kick",
+        aliases: &[],
+        usage: ":kick <name>",
+        help: "Disconnect someone, admins only",
+        parse: |args, usage| {
+            Ok(Command::Kick {
+                name: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "ban",
+        aliases: &[],
+        usage: ":ban <name>",
+        help: "Disconnect someone and refuse their name, admins only",
+        parse: |args, usage| {
+            Ok(Command::Ban {
+                name: single(args, usage)?,
+            })
+        },
+    },
+    CommandInfo {
+        name: "help",
+        aliases: &["h"],
+        usage: ":help",
+        help: "List the commands",
+        parse: |_, _| Ok(Command::Help),
+    },
+];
+
+/// Names of the built-in commands starting with `prefix`, for Tab completion.
+pub fn complete_name(prefix: &str) -> impl Iterator<Item = &'static str> {
+    COMMANDS
+        .iter()
+        .map(|info| info.name)
+        .filter(move |name| name.starts_with(prefix))
+}
+
+/// The `:help` listing, one line per command and then the names of `macros`.
+pub fn help_text(macros: &BTreeMap<String, String>) -> String {
+    let width = COMMANDS.iter().map(|info| info.usage.len()).max();
+    let mut text = String::from("Commands:");
+    for info in COMMANDS {
+        text.push_str(&format!(
+            "\n  {:width$}  {}",
+            info.usage,
+            info.help,
+            width = width.unwrap_or_default()
+        ));
+        if !info.aliases.is_empty() {
+            text.push_str(&format!(" (also :{})", info.aliases.join(", :")));
+        }
+    }
+    if !macros.is_empty() {
+        let names: Vec<_> = macros.keys().map(|name| format!(":{}", name)).collect();
+        text.push_str(&format!("\nMacros: {}", names.join(" ")));
+    }
+    text
+}
+
+/// Splits a command line into the command's name and its arguments, `None` if it's empty.
+fn split(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    let line = line.strip_prefix(':').unwrap_or(line).trim_start();
+    if line.is_empty() {
+        return None;
+    }
+    match line.split_once(char::is_whitespace) {
+        Some((name, args)) => Some((name, args.trim())),
+        None => Some((line, "")),
+    }
+}
+
+/// `args` as the command's one argument, which may contain spaces.
+fn required(args: &str, usage: &'static str) -> Result<String, CommandError> {
+    match args.trim() {
+        "" => Err(CommandError::Usage(usage)),
+        args => Ok(args.to_owned()),
+    }
+}
+
+/// `args` as a single word, such as a name.
+fn single(args: &str, usage: &'static str) -> Result<String, CommandError> {
+    let arg = required(args, usage)?;
+    match arg.split_whitespace().nth(1) {
+        Some(_) => Err(CommandError::Usage(usage)),
+        None => Ok(arg),
+    }
+}
+
+/// Command lines entered before, oldest first, browsed with Up and Down in command mode.
+#[derive(Debug, Default)]
+pub struct CommandHistory {
+    entries: VecDeque<String>,
+    capacity: usize,
+    /// Saved to after each command if set.
+    path: Option<PathBuf>,
+    /// Entry shown while browsing, and the line typed before browsing started.
+    browsing: Option<(usize, String)>,
+}
+
+impl CommandHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// History kept in the file at `path`, starting from what it already holds.
+    pub fn load(path: &str, capacity: usize) -> Self {
+        let path = expand_tilde(path);
+        let mut history = Self::new(capacity);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    history.add(line);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => warn!(
+                "Failed to read command history from {}: {}",
+                path.display(),
+                err
+            ),
+        }
+        history.path = Some(path);
+        history
+    }
+
+    /// Records an entered command line, saving the history if it has a file.
+    pub fn push(&mut self, line: &str) {
+        self.browsing = None;
+        if !self.add(line) {
+            return;
+        }
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        if let Err(err) = std::fs::write(path, contents) {
+            warn!(
+                "Failed to save command history to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
+
+    /// Adds `line` unless it's empty or repeats the last entry, returns whether it was added.
+    fn add(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        let empty = line.strip_prefix(':').unwrap_or(line).trim().is_empty();
+        if empty || self.capacity == 0 || self.entries.back().is_some_and(|last| last == line) {
+            return false;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line.to_owned());
+        true
+    }
+
+    /// Entry before the one shown, starting from the newest. As in vim, only entries starting
+    /// with what was typed before browsing are visited.
+    pub fn older(&mut self, typed: &str) -> Option<&str> {
+        let (shown, prefix) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => (self.entries.len(), typed.to_owned()),
+        };
+        let found = self
+            .entries
+            .range(..shown)
+            .rposition(|entry| entry.starts_with(&prefix));
+        self.browsing = Some((found.unwrap_or(shown), prefix));
+        found.map(|index| self.entries[index].as_str())
+    }
+
+    /// Entry after the one shown, or the line typed before browsing once past the newest.
+    pub fn newer(&mut self) -> Option<&str> {
+        let (shown, prefix) = self.browsing.take()?;
+        let start = (shown + 1).min(self.entries.len());
+        let found = self
+            .entries
+            .range(start..)
+            .position(|entry| entry.starts_with(&prefix))
+            .map(|offset| start + offset);
+        match found {
+            Some(index) => {
+                self.browsing = Some((index, prefix));
+                Some(self.entries[index].as_str())
+            }
+            // Back to the typed line, unless it's already shown.
+            None => {
+                let back_to_typed = shown < self.entries.len();
+                self.browsing = Some((self.entries.len(), prefix));
+                let (_, prefix) = self.browsing.as_ref()?;
+                back_to_typed.then_some(prefix.as_str())
+            }
+        }
+    }
+
+    /// Forgets the browsing position, e.g. once the shown line is edited.
+    pub fn stop_browsing(&mut self) {
+        self.browsing = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        app::command::{
+            COMMANDS, Command, CommandError, CommandHistory, CommandInfo, complete_name, help_text,
+        },
+        config::LineNumbers,
+    };
+
+    fn parse(line: &str) -> Result<Command, CommandError> {
+        Command::parse(line).expect("not an empty command")
+    }
+
+    #[test]
+    fn test_whitespace_and_bang() {
+        assert_eq!(parse(":q"), Ok(Command::Quit));
+        assert_eq!(parse("  :q  "), Ok(Command::Quit));
+        assert_eq!(parse(":q!"), Ok(Command::Quit));
+        assert_eq!(parse(":  wq"), Ok(Command::WriteQuit));
+        assert_eq!(
+            parse(":set   rnu "),
+            Ok(Command::SetLineNumbers(LineNumbers::Relative))
+        );
+        assert_eq!(Command::parse(":"), None);
+        assert_eq!(Command::parse("   "), None);
+    }
+
+    #[test]
+    fn test_arguments() {
+        assert_eq!(
+            parse(":msg bob  hi there "),
+            Ok(Command::Msg {
+                name: "bob".to_owned(),
+                content: "hi there".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(":join\trust"),
+            Ok(Command::Join {
+                channel: "rust".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(":whois bob"),
+            Ok(Command::Whois {
+                name: "bob".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(":msg bob"),
+            Err(CommandError::Usage(":msg <name> <message>"))
+        );
+        assert_eq!(
+            parse(":kick bob carol"),
+            Err(CommandError::Usage(":kick <name>"))
+        );
+        assert_eq!(
+            parse(":q now"),
+            Err(CommandError::Trailing("now".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(
+            parse(":frobnicate x"),
+            Err(CommandError::Unknown("frobnicate".to_owned()))
+        );
+        assert_eq!(
+            parse(":set spell"),
+            Err(CommandError::UnknownOption("spell".to_owned()))
+        );
+        assert_eq!(parse(":w!"), Err(CommandError::Unknown("w!".to_owned())));
+        assert_eq!(
+            parse(":nick alice").unwrap_err().to_string(),
+            "Not supported: renaming, reconnect with another name instead"
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let macros = [("shrug", "¯\\_(ツ)_/¯"), ("q", "not quitting")]
+            .into_iter()
+            .map(|(name, text)| (name.to_owned(), text.to_owned()))
+            .collect();
+        let parse = |line| Command::parse_with_macros(line, &macros).unwrap();
+
+        assert_eq!(
+            parse(":shrug"),
+            Ok(Command::Insert {
+                text: "¯\\_(ツ)_/¯".to_owned()
+            })
+        );
+        assert_eq!(
+            parse(":shrug now"),
+            Err(CommandError::Trailing("now".to_owned()))
+        );
+        assert_eq!(parse(":q"), Ok(Command::Quit));
+        assert_eq!(
+            parse(":tableflip"),
+            Err(CommandError::Unknown("tableflip".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        for info in COMMANDS {
+            let example = info.usage.replace("<name>", "bob").replace(['<', '>'], "");
+            let parsed = Command::parse(&example).unwrap();
+            assert!(
+                parsed.is_ok() || info.name == "set",
+                "{}: {:?}",
+                example,
+                parsed
+            );
+            for alias in info.aliases {
+                let line = example.replacen(info.name, alias, 1);
+                assert_eq!(Command::parse(&line).unwrap(), parsed);
+            }
+        }
+
+        let names: Vec<_> = complete_name("w").collect();
+        assert_eq!(names, ["w", "wq", "whois"]);
+        let takes_name = |name| CommandInfo::find(name).unwrap().takes_name();
+        assert!(takes_name("msg") && takes_name("whois"));
+        assert!(!takes_name("join") && !takes_name("q"));
+        assert!(CommandInfo::find("quit!").is_some_and(|info| info.name == "q"));
+    }
+
+    #[test]
+    fn test_help_lists_every_command() {
+        let macros = BTreeMap::from([("shrug".to_owned(), "¯\\_(ツ)_/¯".to_owned())]);
+        let help = help_text(&macros);
+        for info in COMMANDS {
+            assert!(help.contains(&format!("  {}", info.usage)));
+        }
+        assert!(help.contains("  :msg <name> <message>  Send a private message\n"));
+        assert!(help.contains("(also :quit, :q!, :quit!)"));
+        assert!(help.ends_with("\nMacros: :shrug"));
+    }
+
+    #[test]
+    fn test_history_browsing() {
+        let mut history = CommandHistory::new(3);
+        for line in [":join a", ":q", ":join b", ":join b", ":", ":msg bob hi"] {
+            history.push(line);
+        }
+        // Bounded, without repeats or empty lines.
+        assert_eq!(history.entries, [":q", ":join b", ":msg bob hi"]);
+
+        assert_eq!(history.older(":"), Some(":msg bob hi"));
+        assert_eq!(history.older(":"), Some(":join b"));
+        assert_eq!(history.older(":"), Some(":q"));
+        assert_eq!(history.older(":"), None);
+        assert_eq!(history.newer(), Some(":join b"));
+        assert_eq!(history.newer(), Some(":msg bob hi"));
+        assert_eq!(history.newer(), Some(":"));
+        assert_eq!(history.newer(), None);
+
+        history.stop_browsing();
+        assert_eq!(history.older(":j"), Some(":join b"));
+        assert_eq!(history.older(":j"), None);
+        assert_eq!(history.newer(), Some(":j"));
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("term-chat-history-{}", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, ":join a\n:join b\n").unwrap();
+
+        let mut history = CommandHistory::load(path_str, 2);
+        history.push(":q");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), ":join b\n:q\n");
+
+        let mut reloaded = CommandHistory::load(path_str, 2);
+        assert_eq!(reloaded.older(":"), Some(":q"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
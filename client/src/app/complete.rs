@@ -0,0 +1,223 @@
+use tui_textarea::{CursorMove, TextArea};
+
+use crate::app::command::{CommandInfo, complete_name};
+
+/// Tab completion of user names, after `@` or as the argument of a command like `:msg`, and of
+/// command names on the command line.
+#[derive(Debug, Default)]
+pub struct NameCompleter {
+    active: Option<Active>,
+}
+
+/// A completion that repeated Tabs keep cycling.
+#[derive(Debug)]
+struct Active {
+    row: usize,
+    /// Columns of the completed name in the line.
+    start: usize,
+    end: usize,
+    candidates: Vec<String>,
+    /// Candidate in place, `None` while it's their common prefix.
+    shown: Option<usize>,
+}
+
+fn is_name_char(c: char) -> bool {
+    !c.is_whitespace() && c != '@'
+}
+
+impl NameCompleter {
+    /// Completes the name before the cursor against `names`, first to the candidates' longest
+    /// common prefix and then cycling through them. Returns whether there was a name to
+    /// complete, `command_line` allows completing command names and arguments.
+    pub fn complete(
+        &mut self,
+        text_area: &mut TextArea<'static>,
+        names: &[String],
+        command_line: bool,
+    ) -> bool {
+        let (row, col) = text_area.cursor();
+        let chars: Vec<char> = text_area.lines()[row].chars().collect();
+
+        if let Some(active) = self
+            .active
+            .as_mut()
+            .filter(|active| active.row == row && active.end == col && active.end <= chars.len())
+        {
+            let next = active
+                .shown
+                .map_or(0, |shown| (shown + 1) % active.candidates.len());
+            active.shown = Some(next);
+            let (start, end) = (active.start, active.end);
+            let name = active.candidates[next].clone();
+            active.end = start + name.chars().count();
+            replace(text_area, row, start, end, &name);
+            return true;
+        }
+        self.active = None;
+
+        let start = col
+            - chars[..col]
+                .iter()
+                .rev()
+                .take_while(|&&c| is_name_char(c))
+                .count();
+        let before: String = chars[..start].iter().collect();
+        let typed: String = chars[start..col].iter().collect::<String>().to_lowercase();
+        let after_at = before.ends_with('@');
+        let command_argument = command_line
+            && before.ends_with(char::is_whitespace)
+            && before
+                .trim()
+                .strip_prefix(':')
+                .and_then(CommandInfo::find)
+                .is_some_and(CommandInfo::takes_name);
+        let mut candidates: Vec<String> = if command_line && before.is_empty() {
+            // The command's name, typed with its `:`.
+            let Some(prefix) = typed.strip_prefix(':') else {
+                return false;
+            };
+            complete_name(prefix)
+                .map(|name| format!(":{}", name))
+                .collect()
+        } else if after_at || command_argument {
+            names
+                .iter()
+                .filter(|name| name.to_lowercase().starts_with(&typed))
+                .cloned()
+                .collect()
+        } else {
+            return false;
+        };
+        candidates.sort();
+        candidates.dedup();
+        let Some(first) = candidates.first() else {
+            return false;
+        };
+
+        let common: String = first
+            .chars()
+            .enumerate()
+            .take_while(|&(i, c)| {
+                candidates
+                    .iter()
+                    .all(|candidate| candidate.chars().nth(i) == Some(c))
+            })
+            .map(|(_, c)| c)
+            .collect();
+        let typed_len = col - start;
+        let (name, shown) = if candidates.len() == 1 {
+            (first.clone(), Some(0))
+        } else if common.chars().count() > typed_len {
+            (common, None)
+        } else {
+            (first.clone(), Some(0))
+        };
+        replace(text_area, row, start, col, &name);
+        self.active = Some(Active {
+            row,
+            start,
+            end: start + name.chars().count(),
+            candidates,
+            shown,
+        });
+        true
+    }
+
+    /// Ends the current completion, e.g. once something other than Tab is typed.
+    pub fn reset(&mut self) {
+        self.active = None;
+    }
+}
+
+/// Puts `text` in place of columns `start..end` of `row`, leaving the cursor after it and the
+/// yank buffer untouched.
+fn replace(text_area: &mut TextArea<'static>, row: usize, start: usize, end: usize, text: &str) {
+    let yank_text = text_area.yank_text();
+    text_area.move_cursor(CursorMove::Jump(row as u16, start as u16));
+    text_area.delete_str(end - start);
+    text_area.insert_str(text);
+    text_area.set_yank_text(yank_text);
+}
+
+#[cfg(test)]
+mod test {
+    use tui_textarea::{CursorMove, TextArea};
+
+    use crate::app::complete::NameCompleter;
+
+    fn text_area(line: &str) -> TextArea<'static> {
+        let mut text_area = TextArea::from([line.to_owned()]);
+        text_area.move_cursor(CursorMove::End);
+        text_area
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_common_prefix_then_cycle() {
+        let names = names(&["alice", "alicia", "bob"]);
+        let mut completer = NameCompleter::default();
+        let mut text_area = text_area("hi @al");
+
+        assert!(completer.complete(&mut text_area, &names, false));
+        assert_eq!(text_area.lines(), ["hi @alic"]);
+        assert!(completer.complete(&mut text_area, &names, false));
+        assert_eq!(text_area.lines(), ["hi @alice"]);
+        assert!(completer.complete(&mut text_area, &names, false));
+        assert_eq!(text_area.lines(), ["hi @alicia"]);
+        assert!(completer.complete(&mut text_area, &names, false));
+        assert_eq!(text_area.lines(), ["hi @alice"]);
+        assert_eq!(text_area.cursor(), (0, 9));
+    }
+
+    #[test]
+    fn test_command_argument() {
+        let names = names(&["Bob", "carol"]);
+        let mut completer = NameCompleter::default();
+
+        let mut text_area = text_area(":msg b");
+        assert!(completer.complete(&mut text_area, &names, true));
+        assert_eq!(text_area.lines(), [":msg Bob"]);
+
+        // Only command lines complete arguments, and only for commands taking a name.
+        completer.reset();
+        let mut text_area = self::text_area(":msg b");
+        assert!(!completer.complete(&mut text_area, &names, false));
+        let mut text_area = self::text_area(":join b");
+        assert!(!completer.complete(&mut text_area, &names, true));
+        let mut text_area = self::text_area(":whois c");
+        assert!(completer.complete(&mut text_area, &names, true));
+        assert_eq!(text_area.lines(), [":whois carol"]);
+        completer.reset();
+        let mut text_area = self::text_area("hi b");
+        assert!(!completer.complete(&mut text_area, &names, false));
+        let mut text_area = self::text_area("hi @z");
+        assert!(!completer.complete(&mut text_area, &names, false));
+        assert_eq!(text_area.lines(), ["hi @z"]);
+    }
+
+    #[test]
+    fn test_command_name() {
+        let mut completer = NameCompleter::default();
+
+        let mut text_area = text_area(":un");
+        assert!(completer.complete(&mut text_area, &[], true));
+        assert_eq!(text_area.lines(), [":unignore"]);
+
+        completer.reset();
+        let mut text_area = self::text_area(":w");
+        assert!(completer.complete(&mut text_area, &[], true));
+        assert_eq!(text_area.lines(), [":w"]);
+        assert!(completer.complete(&mut text_area, &[], true));
+        assert_eq!(text_area.lines(), [":whois"]);
+
+        // Outside the command line a leading `:` is just text.
+        completer.reset();
+        let mut text_area = self::text_area(":un");
+        assert!(!completer.complete(&mut text_area, &[], false));
+        let mut text_area = self::text_area(":zz");
+        assert!(!completer.complete(&mut text_area, &[], true));
+    }
+}
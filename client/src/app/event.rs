@@ -1,4 +1,4 @@
-use common::ClientId;
+use common::{AdminCommand, ClientId, MessageId, Presence};
 pub use crossterm::event::Event as TermEvent;
 use crossterm::event::EventStream as TermEventStream;
 use futures::Stream;
@@ -8,45 +8,136 @@ use tokio::sync::mpsc::{Receiver, Sender};
 pub type EventSender = Sender<InteractiveEvent>;
 
 pub enum Event {
-    Interactive(InteractiveEvent),
+    /// An event of the tab at the index.
+    Interactive(usize, InteractiveEvent),
     Term(TermEvent),
 }
 
 pub enum InteractiveEvent {
-    RedrawRequest,
     ClientListUpdate {
-        clients: Vec<ClientId>,
+        clients: Vec<Presence>,
     },
     SendMessage {
         content: String,
+        reply_to: Option<MessageId>,
+    },
+    /// Ask the server to kick or ban someone, only works for admins.
+    Admin {
+        command: AdminCommand,
+    },
+    /// Send `content` only to users called `to`.
+    PrivateMessage {
+        to: String,
+        content: String,
+    },
+    /// Ask the server about users called `name`.
+    Whois {
+        name: String,
+    },
+    /// A private message from `sender`, or our own echoed back.
+    ReceivePrivateMessage {
+        sender: ClientId,
+        to: String,
+        content: String,
+    },
+    /// Ask the server to move us to another channel.
+    JoinChannel {
+        channel: String,
+    },
+    /// Server name and message of the day, sent after joining.
+    Welcome {
+        server_name: String,
+        motd: String,
+    },
+    /// Reconnect attempt under way after the connection dropped, `None` once it's over.
+    Reconnecting {
+        attempt: Option<u32>,
+    },
+    /// The server moved us into `channel`.
+    ChannelJoined {
+        channel: String,
+    },
+    /// Empty the local message view, server side history is untouched.
+    ClearMessages,
+    /// Show locally generated text in the message view.
+    SystemMessage {
+        content: String,
+    },
+    /// Show who we are and how we're connected.
+    ShowInfo,
+    /// Hide messages from users called `name`.
+    Ignore {
+        name: String,
+    },
+    /// Show messages from users called `name` again.
+    Unignore {
+        name: String,
+    },
+    /// Write the message view to a text file.
+    SaveTranscript {
+        path: String,
+    },
+    /// Replace the content of our most recent message.
+    EditLastMessage {
+        content: String,
+    },
+    /// Remove our most recent message.
+    DeleteLastMessage,
+    /// `sender` edited their message `id`.
+    MessageEdited {
+        id: MessageId,
+        sender: ClientId,
+        content: String,
+    },
+    /// `sender` deleted their message `id`.
+    MessageDeleted {
+        id: MessageId,
+        sender: ClientId,
+    },
+    /// The server broadcast a message we sent.
+    MessageAcked {
+        id: MessageId,
+    },
+    /// A message we sent couldn't be delivered, the write failed, the server refused it or no
+    /// ack arrived in time.
+    MessageFailed {
+        id: MessageId,
     },
     ReceiveMessage {
+        id: MessageId,
         sender: ClientId,
         content: String,
+        reply_to: Option<MessageId>,
     },
     Quit,
 }
 
 pub struct EventStream {
-    interactive_recv: Receiver<InteractiveEvent>,
-    interactive_send: EventSender,
+    /// One channel per tab, so events know which connection they belong to.
+    interactive_recv: Vec<Receiver<InteractiveEvent>>,
+    interactive_send: Vec<EventSender>,
+    /// Tab polled first next time, so a busy tab can't starve the others.
+    next_tab: usize,
     term_stream: TermEventStream,
 }
 
 impl EventStream {
-    pub fn new() -> Self {
+    pub fn new(tabs: usize) -> Self {
         let term_stream = TermEventStream::new();
-        let (interactive_send, interactive_recv) = tokio::sync::mpsc::channel(256);
+        let (interactive_send, interactive_recv) =
+            (0..tabs).map(|_| tokio::sync::mpsc::channel(256)).unzip();
 
         Self {
             interactive_recv,
             interactive_send,
+            next_tab: 0,
             term_stream,
         }
     }
 
-    pub fn event_sender(&self) -> &EventSender {
-        &self.interactive_send
+    /// Sends events of the tab at `tab`.
+    pub fn event_sender(&self, tab: usize) -> &EventSender {
+        &self.interactive_send[tab]
     }
 }
 
@@ -56,19 +147,27 @@ impl Stream for EventStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        let interactive = self.interactive_recv.poll_recv(cx);
-
-        if let std::task::Poll::Ready(Some(item)) = interactive {
-            return std::task::Poll::Ready(Some(Ok(Event::Interactive(item))));
+        let tabs = self.interactive_recv.len();
+        let mut interactive_pending = false;
+        for offset in 0..tabs {
+            let tab = (self.next_tab + offset) % tabs;
+            match self.interactive_recv[tab].poll_recv(cx) {
+                std::task::Poll::Ready(Some(item)) => {
+                    self.next_tab = (tab + 1) % tabs;
+                    return std::task::Poll::Ready(Some(Ok(Event::Interactive(tab, item))));
+                }
+                std::task::Poll::Ready(None) => (),
+                std::task::Poll::Pending => interactive_pending = true,
+            }
         }
 
         let term = self.term_stream.poll_next_unpin(cx);
 
         if let std::task::Poll::Ready(Some(item)) = term {
-            return std::task::Poll::Ready(Some(item.map(|event| Event::Term(event))));
+            return std::task::Poll::Ready(Some(item.map(Event::Term)));
         }
 
-        if interactive.is_pending() || term.is_pending() {
+        if interactive_pending || term.is_pending() {
             std::task::Poll::Pending
         } else {
             std::task::Poll::Ready(None)
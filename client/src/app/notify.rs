@@ -0,0 +1,73 @@
+use std::io::Write;
+
+use log::warn;
+
+use crate::config::{ClientConfig, NotifyMode};
+
+/// Whether `content` mentions `name` as a whole word, ignoring case and an optional `@`.
+pub fn mentions(content: &str, name: &str) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let content = content.to_lowercase();
+    let name = name.to_lowercase();
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    content.match_indices(&name).any(|(start, matched)| {
+        let before = content[..start].chars().next_back();
+        let after = content[start + matched.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+/// Alerts the user about a message from `sender_name` if the config asks for it.
+pub fn notify(config: &ClientConfig, own_name: &str, sender_name: &str, content: &str) {
+    let should_notify = match config.notify {
+        NotifyMode::All => true,
+        NotifyMode::Mentions => mentions(content, own_name),
+        NotifyMode::Off => false,
+    };
+    if should_notify {
+        alert(config, sender_name, content);
+    }
+}
+
+/// Like [`notify`] for a private message, which counts as mentioning us.
+pub fn notify_private(config: &ClientConfig, sender_name: &str, content: &str) {
+    if config.notify != NotifyMode::Off {
+        alert(config, sender_name, content);
+    }
+}
+
+fn alert(config: &ClientConfig, sender_name: &str, content: &str) {
+    if config.bell {
+        let mut stdout = std::io::stdout();
+        if let Err(err) = stdout.write_all(b"\x07").and_then(|()| stdout.flush()) {
+            warn!("Failed to ring terminal bell: {}", err);
+        }
+    }
+    if config.desktop_notifications {
+        desktop_notification(sender_name, content);
+    }
+}
+
+#[cfg(feature = "desktop-notifications")]
+fn desktop_notification(sender_name: &str, content: &str) {
+    let summary = format!("term-chat: {}", sender_name);
+    let content = content.to_owned();
+    // Showing a notification can block on the notification daemon.
+    std::thread::spawn(move || {
+        if let Err(err) = notify_rust::Notification::new()
+            .summary(&summary)
+            .body(&content)
+            .show()
+        {
+            warn!("Failed to show desktop notification: {}", err);
+        }
+    });
+}
+
+#[cfg(not(feature = "desktop-notifications"))]
+fn desktop_notification(_sender_name: &str, _content: &str) {
+    warn!("Built without desktop notification support.");
+}
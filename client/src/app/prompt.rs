@@ -0,0 +1,128 @@
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::{
+    DefaultTerminal,
+    layout::{Constraint, Flex, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph},
+};
+use tui_textarea::TextArea;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{app::AppError, theme::Theme};
+
+/// Longest name the prompt accepts, in characters.
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Why `name` can't be used, `None` if it's fine.
+pub fn name_error(name: &str) -> Option<&'static str> {
+    if name.trim().is_empty() {
+        Some("Name can't be empty")
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        Some("Name is too long")
+    } else if name.chars().any(char::is_control) {
+        Some("Name can't contain control characters")
+    } else {
+        None
+    }
+}
+
+/// Box of `width` by `height` in the middle of `area`.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let [area] = Layout::vertical([Constraint::Length(height)])
+        .flex(Flex::Center)
+        .areas(area);
+    let [area] = Layout::horizontal([Constraint::Length(width)])
+        .flex(Flex::Center)
+        .areas(area);
+    area
+}
+
+/// Shows that we're connecting to `server`, drawn once while connecting runs.
+pub fn draw_connecting(
+    terminal: &mut DefaultTerminal,
+    server: &str,
+    theme: &Theme,
+) -> std::io::Result<()> {
+    let text = format!("Connecting to {}…", server);
+    terminal.draw(|frame| {
+        let area = centered(frame.area(), text.width() as u16 + 4, 3);
+        let paragraph = Paragraph::new(Line::from(text.as_str()).centered()).block(
+            Block::bordered()
+                .border_style(theme.border_style())
+                .title("term-chat 🚀"),
+        );
+        frame.render_widget(paragraph, area);
+    })?;
+    Ok(())
+}
+
+/// Asks for a name in a small input box, for when none was given on the command line.
+/// Returns `None` if the user quit with Esc or Ctrl+C instead.
+pub async fn prompt_name(
+    terminal: &mut DefaultTerminal,
+    theme: &Theme,
+) -> Result<Option<String>, AppError> {
+    let mut events = EventStream::new();
+    let mut text_area = TextArea::default();
+    text_area.set_cursor_line_style(Style::new());
+    let mut error = None;
+
+    loop {
+        let hint = match error {
+            Some(error) => Line::from(error).fg(theme.error),
+            None => Line::from("Enter to join, Esc to quit").dim(),
+        };
+        text_area.set_block(
+            Block::bordered()
+                .border_style(theme.border_style())
+                .title("term-chat 🚀 Your name")
+                .title_bottom(hint.right_aligned()),
+        );
+        terminal.draw(|frame| frame.render_widget(&text_area, centered(frame.area(), 44, 3)))?;
+
+        let Some(event) = events.next().await else {
+            return Ok(None);
+        };
+        let TermEvent::Key(event) = event? else {
+            continue;
+        };
+        if event.kind != KeyEventKind::Press {
+            continue;
+        }
+        match event.code {
+            KeyCode::Esc => return Ok(None),
+            KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(None);
+            }
+            KeyCode::Enter => {
+                let name = text_area.lines()[0].trim().to_owned();
+                error = name_error(&name);
+                if error.is_none() {
+                    return Ok(Some(name));
+                }
+            }
+            _ => {
+                text_area.input(event);
+                error = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::app::prompt::{MAX_NAME_LENGTH, name_error};
+
+    #[test]
+    fn test_name_error() {
+        assert_eq!(name_error("alice"), None);
+        assert_eq!(name_error("日本語"), None);
+        assert!(name_error("").is_some());
+        assert!(name_error("   ").is_some());
+        assert!(name_error("al\x1bice").is_some());
+        assert_eq!(name_error(&"a".repeat(MAX_NAME_LENGTH)), None);
+        assert!(name_error(&"a".repeat(MAX_NAME_LENGTH + 1)).is_some());
+    }
+}
@@ -1,80 +1,484 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use common::{secure::SecureStream, ClientId, ClientMessage, ReadStream, ServerMessage, WriteSink};
-use futures::{SinkExt, StreamExt};
-use log::info;
+use common::{
+    ClientId, ClientMessage, PROTOCOL_VERSION, ResumeToken, ServerLimits, ServerMessage, Transport,
+    codec::CompressionSettings,
+    rate::RateLimiter,
+    secure::{CIPHER_SUITE, SecureStreamError},
+    stream::{ClientSink, ClientStream},
+};
+use futures::{SinkExt, StreamExt, stream::FuturesUnordered};
+use log::{info, warn};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-    net::TcpSocket,
+    net::{TcpSocket, TcpStream},
     sync::{Mutex, RwLock},
 };
-use tokio_util::bytes::Bytes;
 
-use crate::app::{AppError, vim::VimMode};
+use crate::{
+    app::{AppError, tls::Security, vim::VimMode},
+    config::ClientConfig,
+};
+
+/// Address of the term-chat server.
+pub const SERVER_HOST: &str = "www.banhana.org:6942";
+/// How long a connection attempt gets before the next address is tried alongside it.
+const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Where the server was reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Display for ServerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct AppState {
     pub mode: VimMode,
+    /// Channel we're in, rejoined if the session can't be resumed after a drop.
+    pub channel: Option<String>,
+    /// Names whose messages are hidden, only known to us.
+    pub ignored: HashSet<String>,
+    /// Names of the clients in our channel, for completion.
+    pub roster: Vec<String>,
 }
 
 pub struct AppResources {
-    pub id: ClientId,
-    pub read_msg: Mutex<ReadStream>,
-    pub write_msg: Mutex<WriteSink>,
+    /// Our identity as assigned by the server, changes if a reconnect can't resume.
+    id: std::sync::RwLock<ClientId>,
+    /// Address of the server we're connected to, may change on reconnect.
+    server_addr: std::sync::RwLock<ServerAddr>,
+    /// Host and port of the server, e.g. [`SERVER_HOST`].
+    host: String,
+    /// Unix socket to connect to instead of `host`.
+    unix_socket: Option<PathBuf>,
+    /// How TCP connections are secured, from the config.
+    security: Security,
+    /// Compression we apply to messages we send.
+    pub compression: CompressionSettings,
+    name: String,
+    resume_token: std::sync::Mutex<Option<ResumeToken>>,
+    /// What the server takes, may change on reconnect.
+    limits: std::sync::RwLock<ServerLimits>,
+    /// Mirrors the server's rate limit, so messages it would refuse aren't sent.
+    send_rate: std::sync::Mutex<RateLimiter>,
+    /// Set once we told the server we're leaving, so a closed connection isn't reconnected.
+    leaving: AtomicBool,
+    pub config: ClientConfig,
+    pub read_msg: Mutex<ClientStream>,
+    pub write_msg: Mutex<ClientSink>,
     pub state: RwLock<AppState>,
 }
 
 impl AppResources {
-    pub async fn new(name: String) -> Result<Self, AppError> {
-        let Some(server_addr) = tokio::net::lookup_host("www.banhana.org:6942")
-            .await
-            .unwrap()
-            .next()
-        else {
-            return Err(AppError::ServerError);
+    pub async fn new(
+        name: String,
+        channel: Option<String>,
+        host: String,
+        unix_socket: Option<PathBuf>,
+        security: Security,
+        config: ClientConfig,
+    ) -> Result<Self, AppError> {
+        let timeout = config.connect_timeout();
+        let compression = CompressionSettings::default();
+        let request = ClientMessage::JoinRequest {
+            name: name.clone(),
+            channel: channel.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            admin_token: config.admin_token.clone(),
+        };
+        let connect = async {
+            let (server_addr, mut write_msg, mut read_msg) =
+                connect(&host, unix_socket.clone(), &security, compression).await?;
+            let joined = join(&mut write_msg, &mut read_msg, request)
+                .await?
+                .ok_or(AppError::ServerError)?;
+            Ok::<_, AppError>((joined, server_addr, write_msg, read_msg))
         };
+        let (joined, server_addr, write_msg, read_msg) = tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| AppError::ConnectTimeout { timeout })??;
 
-        info!("Resolved server socket address: {}", server_addr);
+        let read_msg = Mutex::new(read_msg);
+        let write_msg = Mutex::new(write_msg);
 
-        let socket = match server_addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4(),
-            SocketAddr::V6(_) => TcpSocket::new_v6(),
-        }?;
+        let state = RwLock::new(AppState {
+            channel,
+            ..Default::default()
+        });
 
-        let stream = socket.connect(server_addr).await?;
+        Ok(Self {
+            id: std::sync::RwLock::new(joined.id),
+            server_addr: std::sync::RwLock::new(server_addr),
+            host,
+            unix_socket,
+            security,
+            compression,
+            name,
+            resume_token: std::sync::Mutex::new(joined.resume_token),
+            limits: std::sync::RwLock::new(joined.limits),
+            send_rate: std::sync::Mutex::new(RateLimiter::new(joined.limits.messages_per_second)),
+            leaving: AtomicBool::new(false),
+            config,
+            read_msg,
+            write_msg,
+            state,
+        })
+    }
 
-        let id = ClientId {
-            name: name.clone(),
-            addr: stream.local_addr().unwrap(),
+    pub fn id(&self) -> ClientId {
+        self.id.read().unwrap().clone()
+    }
+
+    pub fn limits(&self) -> ServerLimits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Checks a message of `len` characters against the server's limits before it's sent,
+    /// counting it towards the rate limit. Returns why the server would refuse it otherwise.
+    pub fn check_send(&self, len: usize) -> Result<(), String> {
+        let limits = self.limits();
+        if len > limits.max_message_length {
+            return Err(format!(
+                "{} characters is longer than the limit of {}",
+                len, limits.max_message_length
+            ));
+        }
+        match self.send_rate.lock().unwrap().try_acquire() {
+            true => Ok(()),
+            false => Err(format!(
+                "sending faster than {} messages a second",
+                limits.messages_per_second
+            )),
+        }
+    }
+
+    pub fn server_addr(&self) -> ServerAddr {
+        self.server_addr.read().unwrap().clone()
+    }
+
+    /// The server as given on the command line, for display.
+    pub fn target(&self) -> String {
+        match &self.unix_socket {
+            Some(path) => path.display().to_string(),
+            None => self.host.clone(),
+        }
+    }
+
+    /// What the connection is encrypted with, for display.
+    pub fn encryption(&self) -> &'static str {
+        match self.server_addr() {
+            ServerAddr::Tcp(_) => self.security.description(),
+            ServerAddr::Unix(_) => CIPHER_SUITE,
+        }
+    }
+
+    pub fn set_leaving(&self) {
+        self.leaving.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_leaving(&self) -> bool {
+        self.leaving.load(Ordering::Relaxed)
+    }
+
+    /// Reconnects after the connection dropped, resuming the session if the server still holds
+    /// it and joining anew otherwise. Returns whether the session was resumed.
+    pub async fn reconnect(&self) -> Result<bool, AppError> {
+        let timeout = self.config.connect_timeout();
+        let resume_token = *self.resume_token.lock().unwrap();
+        let reconnect = async {
+            let (server_addr, mut write_msg, mut read_msg) = connect(
+                &self.host,
+                self.unix_socket.clone(),
+                &self.security,
+                self.compression,
+            )
+            .await?;
+            let resumed = match resume_token {
+                Some(token) => {
+                    let request = ClientMessage::Resume { token };
+                    join(&mut write_msg, &mut read_msg, request).await?
+                }
+                None => None,
+            };
+            let accepted = match resumed {
+                Some(accepted) => (accepted, true),
+                None => {
+                    let request = ClientMessage::JoinRequest {
+                        name: self.name.clone(),
+                        channel: self.state.read().await.channel.clone(),
+                        protocol_version: PROTOCOL_VERSION,
+                        admin_token: self.config.admin_token.clone(),
+                    };
+                    let accepted = join(&mut write_msg, &mut read_msg, request)
+                        .await?
+                        .ok_or(AppError::ServerError)?;
+                    (accepted, false)
+                }
+            };
+            Ok::<_, AppError>((accepted, server_addr, write_msg, read_msg))
         };
+        let ((joined, resumed), server_addr, write_msg, read_msg) =
+            tokio::time::timeout(timeout, reconnect)
+                .await
+                .map_err(|_| AppError::ConnectTimeout { timeout })??;
 
-        let stream = SecureStream::handshake(stream).await.unwrap();
+        *self.id.write().unwrap() = joined.id;
+        *self.server_addr.write().unwrap() = server_addr;
+        *self.resume_token.lock().unwrap() = joined.resume_token;
+        if self.limits().messages_per_second != joined.limits.messages_per_second {
+            *self.send_rate.lock().unwrap() = RateLimiter::new(joined.limits.messages_per_second);
+        }
+        *self.limits.write().unwrap() = joined.limits;
+        *self.write_msg.lock().await = write_msg;
+        *self.read_msg.lock().await = read_msg;
+        Ok(resumed)
+    }
 
-        let (mut write_msg, mut read_msg) = stream.split();
+    /// Resources for tests, over a connection whose server end is already gone.
+    #[cfg(test)]
+    pub async fn disconnected(config: ClientConfig) -> Self {
+        use common::stream::split_message_stream;
 
-        let buf = serde_cbor::to_vec(&ClientMessage::JoinRequest { name }).unwrap();
+        let compression = CompressionSettings::default();
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let (local, remote) = tokio::join!(
+            split_message_stream::<ClientMessage, ServerMessage>(local, compression),
+            split_message_stream::<ServerMessage, ClientMessage>(remote, compression),
+        );
+        let (write_msg, read_msg) = local.unwrap();
+        drop(remote);
+        let name = "test".to_owned();
 
-        write_msg.send(Bytes::from(buf)).await?;
+        Self {
+            id: std::sync::RwLock::new(ClientId {
+                name: name.clone(),
+                addr: common::PeerAddr::Virtual(0),
+            }),
+            server_addr: std::sync::RwLock::new(ServerAddr::Tcp(([127, 0, 0, 1], 0).into())),
+            host: SERVER_HOST.to_owned(),
+            unix_socket: None,
+            security: Security::Handshake { pin: None },
+            compression,
+            name,
+            resume_token: std::sync::Mutex::new(None),
+            // The default server settings'.
+            limits: std::sync::RwLock::new(ServerLimits {
+                max_message_length: 4096,
+                messages_per_second: 0,
+                max_clients: 256,
+            }),
+            send_rate: std::sync::Mutex::new(RateLimiter::new(0)),
+            leaving: AtomicBool::new(false),
+            config,
+            read_msg: Mutex::new(read_msg),
+            write_msg: Mutex::new(write_msg),
+            state: RwLock::new(AppState::default()),
+        }
+    }
 
-        let Some(Ok(response)) = read_msg.next().await else {
-            return Err(AppError::ServerError);
+    /// Points reconnects at `host`, resuming the session of `token`.
+    #[cfg(test)]
+    pub fn reconnect_to(mut self, host: &str, token: ResumeToken) -> Self {
+        self.host = host.to_owned();
+        self.resume_token = std::sync::Mutex::new(Some(token));
+        self
+    }
+}
+
+/// Connects to the server at `host`, or over `unix_socket` if given, and secures the
+/// connection, returning the address connected to.
+pub async fn connect(
+    host: &str,
+    unix_socket: Option<PathBuf>,
+    security: &Security,
+    compression: CompressionSettings,
+) -> Result<(ServerAddr, ClientSink, ClientStream), AppError> {
+    #[cfg(not(unix))]
+    if let Some(path) = unix_socket {
+        return Err(AppError::Connect {
+            addr: ServerAddr::Unix(path),
+            source: io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            ),
+        });
+    }
+    #[cfg(unix)]
+    if let Some(path) = unix_socket {
+        return match UnixStream::connect(&path).await {
+            Ok(stream) => {
+                let addr = ServerAddr::Unix(path);
+                handshake(addr, stream, &security.unix(), compression).await
+            }
+            Err(source) => Err(AppError::Connect {
+                addr: ServerAddr::Unix(path),
+                source,
+            }),
         };
+    }
 
-        let response: ServerMessage = serde_cbor::de::from_slice(&response).unwrap();
+    let addrs: Vec<_> = tokio::net::lookup_host(host)
+        .await
+        .map_err(|source| AppError::Resolve {
+            host: host.to_owned(),
+            source,
+        })?
+        .collect();
+    if addrs.is_empty() {
+        return Err(AppError::Resolve {
+            host: host.to_owned(),
+            source: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+        });
+    }
 
-        if response != ServerMessage::AcceptJoin {
-            return Err(AppError::ServerError);
+    info!("Resolved server socket addresses: {:?}", addrs);
+
+    let (server_addr, stream) = connect_any(addrs).await?;
+    handshake(ServerAddr::Tcp(server_addr), stream, security, compression).await
+}
+
+async fn handshake(
+    addr: ServerAddr,
+    stream: impl Transport,
+    security: &Security,
+    compression: CompressionSettings,
+) -> Result<(ServerAddr, ClientSink, ClientStream), AppError> {
+    match security.split(stream, compression).await {
+        Ok((write_msg, read_msg)) => Ok((addr, write_msg, read_msg)),
+        Err(
+            source @ (SecureStreamError::KeyMismatch { .. }
+            | SecureStreamError::MissingIdentity
+            | SecureStreamError::IdentityVerificationFailed),
+        ) => Err(AppError::KeyMismatch { addr, source }),
+        Err(SecureStreamError::PlaintextRefused) => Err(AppError::InsecureRefused { addr }),
+        Err(source) => Err(AppError::Handshake { addr, source }),
+    }
+}
+
+/// Tries `addrs` alternating between IPv6 and IPv4, starting the next attempt early if one
+/// stalls, and returns the first connection made.
+async fn connect_any(addrs: Vec<SocketAddr>) -> Result<(SocketAddr, TcpStream), AppError> {
+    let tried = addrs.len();
+    let mut addrs = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.push(async move { (addr, connect_to(addr).await) });
+        } else if attempts.is_empty() {
+            break;
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => {
+                    info!("Connected to {}", addr);
+                    return Ok((addr, stream));
+                }
+                Err(err) => {
+                    warn!("Failed to connect to {}: {}", addr, err);
+                    last_error = Some((addr, err));
+                }
+            },
+            () = tokio::time::sleep(CONNECT_ATTEMPT_DELAY), if addrs.peek().is_some() => (),
         }
+    }
 
-        let read_msg = Mutex::new(read_msg);
-        let write_msg = Mutex::new(write_msg);
+    let (addr, source) = last_error.expect("at least one address was tried");
+    if tried == 1 {
+        Err(AppError::Connect {
+            addr: ServerAddr::Tcp(addr),
+            source,
+        })
+    } else {
+        Err(AppError::NoValidPorts {
+            tried,
+            addr,
+            source,
+        })
+    }
+}
 
-        let state = RwLock::new(AppState::default());
+async fn connect_to(addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }?;
+    socket.set_nodelay(true)?;
+    socket.set_keepalive(true)?;
+    socket.connect(addr).await
+}
 
-        Ok(Self {
+/// Reorders `addrs` to alternate address families, keeping the resolver's order within each
+/// and starting with the family it listed first.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+/// What the server told us on accepting a join or resume.
+pub struct Joined {
+    pub id: ClientId,
+    pub resume_token: Option<ResumeToken>,
+    pub limits: ServerLimits,
+}
+
+/// Sends a join or resume `request`, returning what the server told us once accepted, or
+/// `None` if a resume was rejected.
+pub async fn join(
+    write_msg: &mut ClientSink,
+    read_msg: &mut ClientStream,
+    request: ClientMessage,
+) -> Result<Option<Joined>, AppError> {
+    write_msg.send(request).await?;
+
+    let Some(Ok(response)) = read_msg.next().await else {
+        return Err(AppError::ServerError);
+    };
+
+    match response {
+        ServerMessage::AcceptJoin {
             id,
-            read_msg,
-            write_msg,
-            state,
-        })
+            resume_token,
+            limits,
+        } => Ok(Some(Joined {
+            id,
+            resume_token,
+            limits,
+        })),
+        ServerMessage::ResumeRejected => Ok(None),
+        ServerMessage::JoinRejected { reason } => Err(AppError::JoinRejected { reason }),
+        _ => Err(AppError::ServerError),
     }
 }
@@ -0,0 +1,152 @@
+use common::{
+    Transport,
+    codec::CompressionSettings,
+    secure::{CIPHER_SUITE, HandshakeOptions, Plaintext, SecureStreamError},
+    stream::{ClientSink, ClientStream, split_message_stream_with},
+};
+
+use crate::config::ClientConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("TLS is enabled in the config but the client was built without the `tls` feature.")]
+    Unsupported,
+    #[error("Could not set up TLS: {0}")]
+    Setup(String),
+}
+
+/// How TCP connections to the server are secured, Unix sockets always use the built-in
+/// handshake.
+#[derive(Clone)]
+pub enum Security {
+    /// The built-in handshake of [`common::secure::SecureStream`], checking the server's key
+    /// against `pin` if set.
+    Handshake { pin: Option<String> },
+    /// The built-in handshake without encryption, to inspect traffic while debugging. The
+    /// server has to allow it.
+    Insecure,
+    /// TLS, verifying the server's certificate for `host`.
+    #[cfg(feature = "tls")]
+    Tls {
+        connector: tokio_rustls::TlsConnector,
+        host: tokio_rustls::rustls::pki_types::ServerName<'static>,
+    },
+}
+
+impl Security {
+    /// Picks the security `config` asks for when connecting to `host`, a name with an optional
+    /// `:port`, expecting the server key of fingerprint `pin` if set.
+    #[cfg(feature = "tls")]
+    pub fn from_config(
+        config: &ClientConfig,
+        host: &str,
+        pin: Option<String>,
+    ) -> Result<Self, TlsError> {
+        use std::sync::Arc;
+
+        use tokio_rustls::rustls::{
+            ClientConfig as RustlsConfig, RootCertStore,
+            crypto::ring,
+            pki_types::{CertificateDer, ServerName, pem::PemObject},
+        };
+
+        if !config.tls {
+            return Ok(Self::Handshake { pin });
+        }
+        if pin.is_some() {
+            log::warn!("Ignoring the pinned server key, TLS checks the certificate instead.");
+        }
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        for err in native.errors {
+            log::warn!("Failed to load system certificates: {}", err);
+        }
+        roots.add_parsable_certificates(native.certs);
+        if let Some(path) = &config.tls_ca_file {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| TlsError::Setup(format!("reading {}: {}", path, err)))?;
+            for cert in certs {
+                roots
+                    .add(cert)
+                    .map_err(|err| TlsError::Setup(format!("adding {}: {}", path, err)))?;
+            }
+        }
+
+        let tls_config = RustlsConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| TlsError::Setup(err.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+        let host = ServerName::try_from(name.to_owned())
+            .map_err(|err| TlsError::Setup(format!("server name {}: {}", name, err)))?;
+        Ok(Self::Tls {
+            connector: Arc::new(tls_config).into(),
+            host,
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn from_config(
+        config: &ClientConfig,
+        _host: &str,
+        pin: Option<String>,
+    ) -> Result<Self, TlsError> {
+        if config.tls {
+            return Err(TlsError::Unsupported);
+        }
+        Ok(Self::Handshake { pin })
+    }
+
+    /// Security for Unix socket connections, which always use the built-in handshake.
+    pub fn unix(&self) -> Self {
+        match self {
+            Self::Handshake { .. } | Self::Insecure => self.clone(),
+            #[cfg(feature = "tls")]
+            Self::Tls { .. } => Self::Handshake { pin: None },
+        }
+    }
+
+    /// What connections secured this way are encrypted with, for display.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Handshake { .. } => CIPHER_SUITE,
+            Self::Insecure => "nothing (--insecure)",
+            #[cfg(feature = "tls")]
+            Self::Tls { .. } => "TLS",
+        }
+    }
+
+    /// Secures `stream` and splits it into message halves.
+    pub async fn split(
+        &self,
+        stream: impl Transport,
+        compression: CompressionSettings,
+    ) -> Result<(ClientSink, ClientStream), SecureStreamError> {
+        match self {
+            Self::Handshake { pin } => {
+                let options = HandshakeOptions {
+                    pin: pin.as_deref(),
+                    ..Default::default()
+                };
+                split_message_stream_with(stream, compression, options).await
+            }
+            Self::Insecure => {
+                let options = HandshakeOptions {
+                    plaintext: Plaintext::Require,
+                    ..Default::default()
+                };
+                split_message_stream_with(stream, compression, options).await
+            }
+            #[cfg(feature = "tls")]
+            Self::Tls { connector, host } => {
+                let stream = connector.connect(host.clone(), stream).await?;
+                Ok(common::stream::split_plain_message_stream(
+                    stream,
+                    compression,
+                ))
+            }
+        }
+    }
+}
@@ -1,19 +1,27 @@
 use std::sync::Arc;
 
+use common::{AdminCommand, MessageId};
 use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
-use log::{debug, info};
+use log::{debug, info, warn};
 use ratatui::{
     buffer::Buffer,
     layout::{Constraint, Layout, Rect},
-    style::{Color, Style, Stylize},
+    style::{Style, Stylize},
     text::Line,
     widgets::{Block, Widget},
 };
 use tui_textarea::{CursorMove, TextArea};
 
-use crate::app::{
-    event::{EventSender, InteractiveEvent},
-    resources::AppResources,
+use crate::{
+    app::{
+        clipboard::Clipboard,
+        command::{Command, CommandHistory, help_text},
+        complete::NameCompleter,
+        event::{EventSender, InteractiveEvent},
+        resources::AppResources,
+    },
+    config::LineNumbers,
+    theme::Theme,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -47,10 +55,7 @@ impl Action {
     }
 
     pub fn is_char(&self) -> bool {
-        match self {
-            Self::Char(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Char(_))
     }
 
     pub fn get_number(&self) -> Option<u32> {
@@ -61,15 +66,12 @@ impl Action {
     }
 
     pub fn is_number(&self) -> bool {
-        match self {
-            Self::Number(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Number(_))
     }
 
     pub fn from_key(code: KeyCode) -> Self {
         match code {
-            KeyCode::Char(num @ '0'..'9') => Self::Number(num as u32 - '0' as u32),
+            KeyCode::Char(num @ '0'..='9') => Self::Number(num as u32 - '0' as u32),
             KeyCode::Char(c) => Self::Char(c),
             _ => Self::Empty,
         }
@@ -84,11 +86,11 @@ impl Action {
             (Self::Empty, Self::Number(num)) => *self = Self::Number(num),
             (Self::Char(c), Self::Number(num)) => *self = Self::CharNumber(c, num),
             (Self::CharNumber(c, num_before), Self::Number(num)) => {
-                *self = Self::CharNumber(c, num_before * 10 + num)
+                *self = Self::CharNumber(c, append_digit(num_before, num))
             }
             (Self::CharNumber(_, _), Self::Char(c2)) => *self = Self::Char(c2),
             (Self::Number(num_before), Self::Number(num)) => {
-                *self = Self::Number(num_before * 10 + num)
+                *self = Self::Number(append_digit(num_before, num))
             }
             (_, Self::Empty) => *self = Self::Empty,
             (_, Self::CharNumber(_, _)) => unreachable!(),
@@ -96,14 +98,86 @@ impl Action {
     }
 }
 
+/// Appends `digit` to the count `num`, capped at [`MAX_COUNT`].
+fn append_digit(num: u32, digit: u32) -> u32 {
+    num.saturating_mul(10).saturating_add(digit).min(MAX_COUNT)
+}
+
+/// A `f`, `F`, `t` or `T` motion towards `target` on the cursor's line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Find {
+    kind: char,
+    target: char,
+}
+
+impl Find {
+    /// The same find in the other direction, as `,` repeats it.
+    fn reversed(self) -> Self {
+        let kind = match self.kind {
+            'f' => 'F',
+            'F' => 'f',
+            't' => 'T',
+            _ => 't',
+        };
+        Self { kind, ..self }
+    }
+
+    fn is_forward(self) -> bool {
+        self.kind.is_lowercase()
+    }
+
+    /// Column of `line` the cursor lands on making this find `count` times from `col`, if there
+    /// are that many matches. `repeat` steps over a match right next to the cursor, which `t` and
+    /// `T` would otherwise stop in front of again.
+    fn column(self, line: &str, col: usize, count: u32, repeat: bool) -> Option<usize> {
+        let till = matches!(self.kind, 't' | 'T');
+        let skip = usize::from(till && repeat);
+        let chars: Vec<char> = line.chars().collect();
+        let nth = count.max(1) as usize - 1;
+        if self.is_forward() {
+            let found = (col + 1 + skip..chars.len())
+                .filter(|&i| chars[i] == self.target)
+                .nth(nth)?;
+            Some(if till { found - 1 } else { found })
+        } else {
+            let found = (0..col.saturating_sub(skip))
+                .rev()
+                .filter(|&i| chars[i] == self.target)
+                .nth(nth)?;
+            Some(if till { found + 1 } else { found })
+        }
+    }
+}
+
 pub struct SendMessageWidget {
     resources: Arc<AppResources>,
     text_area: TextArea<'static>,
     command_text_area: TextArea<'static>,
     prev_action: Action,
     is_line_yank: bool,
+    /// Register selected with `"`, e.g. `+` for the system clipboard.
+    register: Option<char>,
+    clipboard: Clipboard,
+    /// Message the draft replies to, set with `r` on a selected message.
+    reply_to: Option<MessageId>,
+    line_numbers: LineNumbers,
+    /// Find waiting for its target character, e.g. `f` after typing `df`.
+    pending_find: Option<char>,
+    /// Last find made, repeated with `;` and `,`.
+    last_find: Option<Find>,
+    command_history: CommandHistory,
+    /// Command line left unfinished with Esc, brought back by the next `:`.
+    command_draft: Option<String>,
+    name_completer: NameCompleter,
+    theme: Theme,
 }
 
+/// Draft lines shown however short the draft is.
+const MIN_DRAFT_LINES: u16 = 6;
+
+/// Largest count a command takes, so a long run of digits can't stall the editor.
+const MAX_COUNT: u32 = 9999;
+
 fn key_to_cursor_move(code: KeyCode) -> Option<CursorMove> {
     match code {
         KeyCode::Char('h') | KeyCode::Left => Some(CursorMove::Back),
@@ -117,13 +191,13 @@ fn key_to_cursor_move(code: KeyCode) -> Option<CursorMove> {
 }
 
 impl SendMessageWidget {
-    pub fn new(resources: Arc<AppResources>) -> Self {
+    pub fn new(resources: Arc<AppResources>, theme: Theme) -> Self {
         let mut text_area = TextArea::new(Vec::new());
         text_area.set_cursor_line_style(Style::new().not_underlined());
         text_area.set_block(
             Block::bordered()
                 .title_top(Line::from("Normal").left_aligned())
-                .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                .border_style(theme.border_style())
                 .title_bottom(Line::from("Type :q to quit").right_aligned()),
         );
 
@@ -131,11 +205,17 @@ impl SendMessageWidget {
         command_text_area.set_block(
             Block::bordered()
                 .title_top(Line::from("Command").left_aligned())
-                .border_style(Style::new().fg(Color::Rgb(255, 242, 197))),
+                .border_style(theme.border_style()),
         );
 
         let prev_action = Action::Empty;
         let is_line_yank = false;
+        let line_numbers = resources.config.line_numbers;
+        let history_size = resources.config.command_history_size;
+        let command_history = match &resources.config.command_history_file {
+            Some(path) => CommandHistory::load(path, history_size),
+            None => CommandHistory::new(history_size),
+        };
 
         Self {
             resources,
@@ -143,15 +223,223 @@ impl SendMessageWidget {
             command_text_area,
             prev_action,
             is_line_yank,
+            register: None,
+            clipboard: Clipboard::new(),
+            reply_to: None,
+            line_numbers,
+            pending_find: None,
+            last_find: None,
+            command_history,
+            command_draft: None,
+            name_completer: NameCompleter::default(),
+            theme,
+        }
+    }
+    /// Rows the widget takes, growing with the draft up to the config's `max_draft_lines`.
+    pub fn height(&self) -> u16 {
+        let max_lines = self.resources.config.max_draft_lines.max(MIN_DRAFT_LINES);
+        let lines = u16::try_from(self.text_area.lines().len()).unwrap_or(u16::MAX);
+        // Plus the borders.
+        lines.clamp(MIN_DRAFT_LINES, max_lines) + 2
+    }
+    /// Copies the yank buffer into the register selected with `"`, if any.
+    fn yank_to_register(&mut self) {
+        if self.register.take() == Some('+') {
+            self.clipboard.set_text(self.text_area.yank_text());
+        }
+    }
+    /// Starts the operator `event` types, carrying over a count typed before it.
+    ///
+    /// Counts before and after the operator aren't multiplied as in vim, `2d3w` reads as `d23w`.
+    fn start_operator(&mut self, event: KeyEvent) {
+        match (self.prev_action, event.code) {
+            (Action::Number(count), KeyCode::Char(operator)) => {
+                self.prev_action = Action::CharNumber(operator, count);
+            }
+            _ => self.prev_action.update(event),
+        }
+    }
+    /// Selects `count` whole lines from the cursor's down, fewer if the draft ends first, and
+    /// returns their text along with where the cursor was.
+    fn select_lines(&mut self, count: u32) -> (String, (usize, usize)) {
+        let position = self.text_area.cursor();
+        let lines = self.text_area.lines();
+        let last = (position.0 + count.max(1) as usize).min(lines.len()) - 1;
+        let text = lines[position.0..=last].join("\n");
+        self.text_area
+            .move_cursor(CursorMove::Jump(position.0 as u16, 0));
+        self.text_area.start_selection();
+        self.text_area
+            .move_cursor(CursorMove::Jump(last as u16, u16::MAX));
+        (text, position)
+    }
+    /// Length of the current draft in characters, counting line breaks.
+    fn draft_len(&self) -> usize {
+        let lines = self.text_area.lines();
+        lines.iter().map(|line| line.chars().count()).sum::<usize>() + lines.len() - 1
+    }
+    /// Whether j/k/Enter should move through the message list instead of the draft.
+    ///
+    /// True in normal mode with an empty draft and no pending operator or register.
+    pub async fn navigates_messages(&self) -> bool {
+        self.resources.state.read().await.mode == VimMode::Normal
+            && self.draft_len() == 0
+            && self.prev_action == Action::Empty
+            && self.register.is_none()
+            && self.pending_find.is_none()
+    }
+    /// Prefills the draft with `content` quoted as `> [sender]: ...`.
+    pub fn quote(&mut self, sender: &str, content: &str) -> bool {
+        let mut quoted = String::new();
+        for (n, line) in content.lines().enumerate() {
+            if n == 0 {
+                quoted.push_str(&format!("> [{}]: {}\n", sender, line));
+            } else {
+                quoted.push_str(&format!("> {}\n", line));
+            }
+        }
+        self.paste_text(&quoted)
+    }
+    /// Makes the draft a reply to message `id`, prefilled with a mention of its sender.
+    pub fn reply(&mut self, id: MessageId, sender: &str) -> bool {
+        self.reply_to = Some(id);
+        self.paste_text(&format!("@{} ", sender))
+    }
+    /// Inserts `text` at the cursor as a single edit, truncated to fit the server's message
+    /// length limit.
+    pub fn paste_text(&mut self, text: &str) -> bool {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let max = self.resources.limits().max_message_length;
+        let remaining = max.saturating_sub(self.draft_len());
+        let text = match text.char_indices().nth(remaining) {
+            Some((end, _)) => {
+                warn!(
+                    "Paste of {} characters truncated to {} to fit the message length limit",
+                    text.chars().count(),
+                    remaining
+                );
+                &text[..end]
+            }
+            None => &text[..],
+        };
+        self.text_area.insert_str(text)
+    }
+    /// Inserts pasted text into the command box in command mode, otherwise into the send box.
+    pub async fn paste(&mut self, text: &str) -> bool {
+        let mode = self.resources.state.read().await.mode;
+        match mode {
+            VimMode::Command => {
+                // Commands are a single line.
+                let text = text.replace(['\r', '\n'], " ");
+                self.command_text_area.insert_str(text)
+            }
+            VimMode::Normal | VimMode::Insert | VimMode::Visual => self.paste_text(text),
+        }
+    }
+    /// Handles `"` and the register name following it, returns `None` for other keys.
+    fn register_input(&mut self, event: KeyEvent) -> Option<bool> {
+        if event.kind != KeyEventKind::Press {
+            return None;
+        }
+        match event.code {
+            KeyCode::Char(c) if self.prev_action == Action::Char('"') => {
+                self.register = Some(c);
+                self.prev_action.clear();
+                Some(false)
+            }
+            KeyCode::Char('"') => {
+                self.prev_action = Action::Char('"');
+                Some(false)
+            }
+            _ => None,
+        }
+    }
+    /// Handles the character following `f`, `F`, `t` or `T`, returns `None` if no find is pending.
+    async fn find_input(&mut self, event: KeyEvent) -> Option<bool> {
+        if event.kind != KeyEventKind::Press {
+            return None;
+        }
+        let kind = self.pending_find.take()?;
+        let KeyCode::Char(target) = event.code else {
+            self.prev_action.clear();
+            return Some(false);
+        };
+        let find = Find { kind, target };
+        self.last_find = Some(find);
+        Some(self.find(find, false).await)
+    }
+    /// Makes `find` with the pending count, applying the pending operator over it.
+    async fn find(&mut self, find: Find, repeat: bool) -> bool {
+        let (row, col) = self.text_area.cursor();
+        let count = self.prev_action.get_number().unwrap_or(1);
+        let line = &self.text_area.lines()[row];
+        let Some(target) = find.column(line, col, count, repeat) else {
+            self.prev_action.clear();
+            return false;
+        };
+        self.text_area
+            .move_cursor(CursorMove::Jump(row as u16, target as u16));
+        // As in vim, `df,` takes the comma with it while `dF,` leaves the cursor's character.
+        self.apply_operator((row, col), find.is_forward()).await;
+        true
+    }
+    /// Applies the operator pending in `prev_action`, if any, from `start` to where a motion
+    /// left the cursor, then clears it. `inclusive` takes in the character under the cursor.
+    async fn apply_operator(&mut self, start: (usize, usize), inclusive: bool) {
+        let operator = self.prev_action.get_char();
+        self.prev_action.clear();
+        if !matches!(operator, Some('d' | 'y' | 'c')) {
+            return;
+        }
+        let (row, col) = self.text_area.cursor();
+        let end = if inclusive { col + 1 } else { col };
+        self.text_area
+            .move_cursor(CursorMove::Jump(start.0 as u16, start.1 as u16));
+        self.text_area.start_selection();
+        // Jumping clamps to the line, where moving forward would wrap onto the next one.
+        self.text_area
+            .move_cursor(CursorMove::Jump(row as u16, end as u16));
+        if operator == Some('y') {
+            self.text_area.copy();
+            self.text_area
+                .move_cursor(CursorMove::Jump(start.0 as u16, start.1 as u16));
+        } else {
+            self.text_area.cut();
+        }
+        self.is_line_yank = false;
+        self.yank_to_register();
+        if operator == Some('c') {
+            self.enter_insert_mode().await;
         }
     }
+    /// Switches to insert mode, as `i` does.
+    async fn enter_insert_mode(&mut self) {
+        self.resources.state.write().await.mode = VimMode::Insert;
+        self.text_area.set_block(
+            Block::bordered()
+                .title_top(Line::from("Insert").left_aligned())
+                .border_style(self.theme.border_style())
+                .title_bottom(Line::from("Type :q to quit").right_aligned()),
+        );
+    }
+    /// Sends the draft, or keeps it and says why if the server would refuse it.
     async fn send_message(&mut self, event_sender: &EventSender) -> bool {
         debug!("Sending message");
+        if let Err(reason) = self.resources.check_send(self.draft_len()) {
+            event_sender
+                .send(InteractiveEvent::SystemMessage {
+                    content: format!("Couldn't send message: {}", reason),
+                })
+                .await
+                .unwrap();
+            return false;
+        }
         self.text_area.select_all();
         let need_rerender = self.text_area.cut();
         event_sender
             .send(InteractiveEvent::SendMessage {
                 content: self.text_area.yank_text(),
+                reply_to: self.reply_to.take(),
             })
             .await
             .unwrap();
@@ -159,6 +447,20 @@ impl SendMessageWidget {
         need_rerender
     }
     async fn normal_input(&mut self, event: KeyEvent, event_sender: &EventSender) -> bool {
+        if let Some(changed) = self.find_input(event).await {
+            return changed;
+        }
+        if let Some(changed) = self.register_input(event) {
+            return changed;
+        }
+        // `g` only pairs with another `g`, anything else drops it rather than running on its own.
+        if event.kind == KeyEventKind::Press
+            && self.prev_action.get_char() == Some('g')
+            && event.code != KeyCode::Char('g')
+        {
+            self.prev_action.clear();
+            return false;
+        }
         match event {
             KeyEvent {
                 code: KeyCode::Esc,
@@ -166,6 +468,8 @@ impl SendMessageWidget {
                 ..
             } => {
                 self.prev_action.clear();
+                self.register = None;
+                self.reply_to = None;
                 false
             }
             KeyEvent {
@@ -173,14 +477,12 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                self.resources.state.write().await.mode = VimMode::Insert;
-                self.text_area.move_cursor(CursorMove::Forward);
-                self.text_area.set_block(
-                    Block::bordered()
-                        .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
-                        .title_bottom(Line::from("Type :q to quit").right_aligned()),
-                );
+                // Forward would wrap onto the next line at the end of this one.
+                let (row, col) = self.text_area.cursor();
+                if col < self.text_area.lines()[row].chars().count() {
+                    self.text_area.move_cursor(CursorMove::Forward);
+                }
+                self.enter_insert_mode().await;
                 self.prev_action.clear();
                 true
             }
@@ -189,14 +491,8 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                self.resources.state.write().await.mode = VimMode::Insert;
                 self.text_area.move_cursor(CursorMove::End);
-                self.text_area.set_block(
-                    Block::bordered()
-                        .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
-                        .title_bottom(Line::from("Type :q to quit").right_aligned()),
-                );
+                self.enter_insert_mode().await;
                 self.prev_action.clear();
                 true
             }
@@ -205,13 +501,7 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                self.resources.state.write().await.mode = VimMode::Insert;
-                self.text_area.set_block(
-                    Block::bordered()
-                        .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
-                        .title_bottom(Line::from("Type :q to quit").right_aligned()),
-                );
+                self.enter_insert_mode().await;
                 self.prev_action.clear();
                 true
             }
@@ -220,14 +510,14 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                self.resources.state.write().await.mode = VimMode::Insert;
-                self.text_area.move_cursor(CursorMove::Head);
-                self.text_area.set_block(
-                    Block::bordered()
-                        .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
-                        .title_bottom(Line::from("Type :q to quit").right_aligned()),
-                );
+                let row = self.text_area.cursor().0;
+                let first_non_blank = self.text_area.lines()[row]
+                    .chars()
+                    .take_while(|c| c.is_whitespace())
+                    .count();
+                self.text_area
+                    .move_cursor(CursorMove::Jump(row as u16, first_non_blank as u16));
+                self.enter_insert_mode().await;
                 self.prev_action.clear();
                 true
             }
@@ -243,7 +533,7 @@ impl SendMessageWidget {
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 self.prev_action.clear();
@@ -261,7 +551,7 @@ impl SendMessageWidget {
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Insert").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 self.prev_action.clear();
@@ -276,7 +566,7 @@ impl SendMessageWidget {
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Visual").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 self.text_area.start_selection();
@@ -299,7 +589,7 @@ impl SendMessageWidget {
                 code: KeyCode::Char('0'..='9'),
                 kind: KeyEventKind::Press,
                 ..
-            } if self.prev_action.is_number() || event.code != KeyCode::Char('0') => {
+            } if self.prev_action.get_number().is_some() || event.code != KeyCode::Char('0') => {
                 self.prev_action.update(event);
                 false
             }
@@ -309,6 +599,7 @@ impl SendMessageWidget {
                 ..
             } => {
                 self.text_area.move_cursor(CursorMove::Head);
+                self.prev_action.clear();
                 true
             }
             KeyEvent {
@@ -334,6 +625,7 @@ impl SendMessageWidget {
                 ..
             } => {
                 self.text_area.move_cursor(CursorMove::Bottom);
+                self.prev_action.clear();
                 true
             }
             KeyEvent {
@@ -341,19 +633,25 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                if self.prev_action == Action::Char('d') {
-                    let position = self.text_area.cursor();
-                    self.text_area.move_cursor(CursorMove::Head);
-                    self.text_area.delete_line_by_end();
-                    self.text_area.move_cursor(CursorMove::Down);
-                    self.text_area.delete_newline();
+                if self.prev_action.get_char() == Some('d') {
+                    let count = self.prev_action.get_number().unwrap_or(1);
+                    let (text, position) = self.select_lines(count);
+                    self.text_area.cut();
+                    // The lines' content is gone, now the line they leave behind.
+                    if position.0 + 1 < self.text_area.lines().len() {
+                        self.text_area.delete_next_char();
+                    } else {
+                        self.text_area.delete_newline();
+                    }
                     self.text_area
                         .move_cursor(CursorMove::Jump(position.0 as u16, position.1 as u16));
+                    self.text_area.set_yank_text(text);
                     self.is_line_yank = true;
+                    self.yank_to_register();
                     self.prev_action.clear();
                     true
                 } else {
-                    self.prev_action.update(event);
+                    self.start_operator(event);
                     false
                 }
             }
@@ -362,19 +660,40 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                if self.prev_action == Action::Char('y') {
-                    let position = self.text_area.cursor();
-                    self.text_area.move_cursor(CursorMove::Head);
-                    self.text_area.start_selection();
-                    self.text_area.move_cursor(CursorMove::End);
-                    self.text_area.copy();
+                if self.prev_action.get_char() == Some('y') {
+                    let count = self.prev_action.get_number().unwrap_or(1);
+                    let (text, position) = self.select_lines(count);
+                    self.text_area.cancel_selection();
                     self.text_area
                         .move_cursor(CursorMove::Jump(position.0 as u16, position.1 as u16));
+                    self.text_area.set_yank_text(text);
                     self.is_line_yank = true;
+                    self.yank_to_register();
                     self.prev_action.clear();
                     true
                 } else {
-                    self.prev_action.update(event);
+                    self.start_operator(event);
+                    false
+                }
+            }
+            KeyEvent {
+                code: KeyCode::Char('c'),
+                kind: KeyEventKind::Press,
+                ..
+            } => {
+                if self.prev_action.get_char() == Some('c') {
+                    // Line-wise, one empty line is left in place of the changed ones.
+                    let count = self.prev_action.get_number().unwrap_or(1);
+                    let (text, _) = self.select_lines(count);
+                    self.text_area.cut();
+                    self.text_area.set_yank_text(text);
+                    self.is_line_yank = true;
+                    self.yank_to_register();
+                    self.prev_action.clear();
+                    self.enter_insert_mode().await;
+                    true
+                } else {
+                    self.start_operator(event);
                     false
                 }
             }
@@ -387,6 +706,15 @@ impl SendMessageWidget {
                     Action::Number(num) => num,
                     _ => 1,
                 };
+                if self.register.take() == Some('+') {
+                    if let Some(text) = self.clipboard.get_text() {
+                        for _ in 0..num {
+                            self.paste_text(&text);
+                        }
+                    }
+                    self.prev_action.clear();
+                    return true;
+                }
                 for _ in 0..num {
                     if self.is_line_yank {
                         self.text_area.move_cursor(CursorMove::End);
@@ -414,15 +742,8 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                self.command_text_area = TextArea::new(vec![":".to_owned()]);
-                self.command_text_area.move_cursor(CursorMove::End);
-                self.command_text_area
-                    .set_yank_text(self.text_area.yank_text());
-                self.command_text_area.set_block(
-                    Block::bordered()
-                        .title_top(Line::from("Command").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197))),
-                );
+                let line = self.command_draft.take().unwrap_or_else(|| ":".to_owned());
+                self.set_command_line(line);
                 self.resources.state.write().await.mode = VimMode::Command;
                 self.prev_action.clear();
                 true
@@ -443,38 +764,62 @@ impl SendMessageWidget {
                 ..
             } => {
                 let position = self.text_area.cursor();
-                if let Some('d') | Some('y') = self.prev_action.get_char() {
-                    self.text_area.start_selection();
-                }
+                let operator = self.prev_action.get_char();
+                // As in vim, `cw` keeps the space after the word.
+                let change_word = operator == Some('c') && event.code == KeyCode::Char('w');
+                let cursor_move = if change_word {
+                    CursorMove::WordEnd
+                } else {
+                    key_to_cursor_move(event.code).unwrap()
+                };
                 if let Some(num) = self.prev_action.get_number() {
-                    for _ in 0..num {
-                        self.text_area
-                            .move_cursor(key_to_cursor_move(event.code).unwrap());
-                    }
+                    self.move_cursor_times(cursor_move, num);
                     if let KeyCode::Char('j') | KeyCode::Char('k') = event.code {
                         self.text_area.move_cursor(CursorMove::End);
                     }
                 } else {
-                    self.text_area
-                        .move_cursor(key_to_cursor_move(event.code).unwrap());
+                    self.text_area.move_cursor(cursor_move);
                 }
-                if let Some('d') = self.prev_action.get_char() {
-                    self.text_area.cut();
-                    self.is_line_yank = false;
-                } else if let Some('y') = self.prev_action.get_char() {
-                    self.text_area.copy();
-                    self.is_line_yank = false;
-                    self.text_area
-                        .move_cursor(CursorMove::Jump(position.0 as u16, position.1 as u16));
+                // Word end lands on the last character of the word, which the change includes.
+                self.apply_operator(position, change_word).await;
+                true
+            }
+            KeyEvent {
+                code: KeyCode::Char(find @ ('f' | 'F' | 't' | 'T')),
+                kind: KeyEventKind::Press,
+                ..
+            } => {
+                self.pending_find = Some(find);
+                false
+            }
+            KeyEvent {
+                code: KeyCode::Char(repeat @ (';' | ',')),
+                kind: KeyEventKind::Press,
+                ..
+            } => match self.last_find {
+                Some(find) if repeat == ',' => self.find(find.reversed(), true).await,
+                Some(find) => self.find(find, true).await,
+                None => {
+                    self.prev_action.clear();
+                    false
                 }
+            },
+            // An unknown key cancels a pending count or operator, so it can't apply to the next.
+            KeyEvent {
+                kind: KeyEventKind::Press,
+                ..
+            } => {
                 self.prev_action.clear();
-                true
+                false
             }
             _ => false,
         }
     }
 
     async fn visual_input(&mut self, event: KeyEvent, _event_sender: &EventSender) -> bool {
+        if let Some(changed) = self.register_input(event) {
+            return changed;
+        }
         match event {
             KeyEvent {
                 code: KeyCode::Esc,
@@ -485,10 +830,11 @@ impl SendMessageWidget {
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Normal").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 self.prev_action.clear();
+                self.register = None;
                 self.text_area.cancel_selection();
                 true
             }
@@ -499,11 +845,12 @@ impl SendMessageWidget {
             } => {
                 self.is_line_yank = false;
                 self.text_area.copy();
+                self.yank_to_register();
                 self.resources.state.write().await.mode = VimMode::Normal;
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Normal").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 true
@@ -515,11 +862,12 @@ impl SendMessageWidget {
             } => {
                 self.is_line_yank = false;
                 self.text_area.cut();
+                self.yank_to_register();
                 self.resources.state.write().await.mode = VimMode::Normal;
                 self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Normal").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
                 true
@@ -540,10 +888,7 @@ impl SendMessageWidget {
                 ..
             } => {
                 if let Some(num) = self.prev_action.get_number() {
-                    for _ in 0..num {
-                        self.text_area
-                            .move_cursor(key_to_cursor_move(event.code).unwrap());
-                    }
+                    self.move_cursor_times(key_to_cursor_move(event.code).unwrap(), num);
                 } else {
                     self.text_area
                         .move_cursor(key_to_cursor_move(event.code).unwrap());
@@ -554,38 +899,162 @@ impl SendMessageWidget {
             _ => false,
         }
     }
+    /// Makes `cursor_move` up to `count` times, stopping once the cursor stops moving.
+    fn move_cursor_times(&mut self, cursor_move: CursorMove, count: u32) {
+        for _ in 0..count {
+            let before = self.text_area.cursor();
+            self.text_area.move_cursor(cursor_move);
+            if self.text_area.cursor() == before {
+                break;
+            }
+        }
+    }
+    /// Replaces the command line with `line`, the cursor at its end.
+    fn set_command_line(&mut self, line: String) {
+        let yank_text = self.text_area.yank_text();
+        self.command_text_area = TextArea::new(vec![line]);
+        self.command_text_area.move_cursor(CursorMove::End);
+        self.command_text_area.set_yank_text(yank_text);
+        self.command_text_area.set_block(
+            Block::bordered()
+                .title_top(Line::from("Command").left_aligned())
+                .border_style(self.theme.border_style()),
+        );
+    }
+    async fn run_command(&mut self, command: Command, event_sender: &EventSender) {
+        let event = match command {
+            Command::Quit => InteractiveEvent::Quit,
+            Command::Write => {
+                self.send_message(event_sender).await;
+                return;
+            }
+            Command::WriteQuit => {
+                self.send_message(event_sender).await;
+                InteractiveEvent::Quit
+            }
+            Command::Clear => InteractiveEvent::ClearMessages,
+            Command::Stats => InteractiveEvent::Admin {
+                command: AdminCommand::Stats,
+            },
+            Command::SetLineNumbers(line_numbers) => {
+                self.line_numbers = line_numbers;
+                return;
+            }
+            Command::Info => InteractiveEvent::ShowInfo,
+            Command::Help => InteractiveEvent::SystemMessage {
+                content: help_text(&self.resources.config.macros),
+            },
+            Command::Delete => InteractiveEvent::DeleteLastMessage,
+            Command::Edit { content } => InteractiveEvent::EditLastMessage { content },
+            Command::Save { path } => InteractiveEvent::SaveTranscript { path },
+            Command::Ignore { name } => InteractiveEvent::Ignore { name },
+            Command::Unignore { name } => InteractiveEvent::Unignore { name },
+            Command::Kick { name } => InteractiveEvent::Admin {
+                command: AdminCommand::Kick { name },
+            },
+            Command::Ban { name } => InteractiveEvent::Admin {
+                command: AdminCommand::Ban { name },
+            },
+            Command::Join { channel } => InteractiveEvent::JoinChannel { channel },
+            Command::Msg { name, content } => {
+                match self.resources.check_send(content.chars().count()) {
+                    Ok(()) => InteractiveEvent::PrivateMessage { to: name, content },
+                    Err(reason) => InteractiveEvent::SystemMessage {
+                        content: format!("Couldn't message {}: {}", name, reason),
+                    },
+                }
+            }
+            Command::Whois { name } => InteractiveEvent::Whois { name },
+            Command::Insert { text } => {
+                self.text_area.insert_str(text);
+                return;
+            }
+        };
+        event_sender.send(event).await.unwrap();
+    }
+    /// Completes a name on Tab, returns `None` for other keys or if there's no name to complete.
+    async fn completion_input(&mut self, event: KeyEvent, command_line: bool) -> Option<bool> {
+        if event.kind != KeyEventKind::Press {
+            return None;
+        }
+        if event.code != KeyCode::Tab {
+            self.name_completer.reset();
+            return None;
+        }
+        let names = self.resources.state.read().await.roster.clone();
+        let text_area = if command_line {
+            &mut self.command_text_area
+        } else {
+            &mut self.text_area
+        };
+        self.name_completer
+            .complete(text_area, &names, command_line)
+            .then_some(true)
+    }
     async fn command_input(&mut self, event: KeyEvent, event_sender: &EventSender) -> bool {
+        if let Some(changed) = self.completion_input(event, true).await {
+            return changed;
+        }
         match event {
             KeyEvent {
                 code: KeyCode::Esc,
                 kind: KeyEventKind::Press,
                 ..
             } => {
+                let line = self.command_text_area.lines()[0].clone();
+                self.command_draft = (line.trim() != ":").then_some(line);
                 self.command_text_area = TextArea::new(Vec::new());
+                self.command_history.stop_browsing();
                 self.resources.state.write().await.mode = VimMode::Normal;
                 true
             }
+            KeyEvent {
+                code: KeyCode::Up,
+                kind: KeyEventKind::Press,
+                ..
+            } => {
+                let typed = self.command_text_area.lines()[0].clone();
+                match self.command_history.older(&typed) {
+                    Some(line) => {
+                        let line = line.to_owned();
+                        self.set_command_line(line);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            KeyEvent {
+                code: KeyCode::Down,
+                kind: KeyEventKind::Press,
+                ..
+            } => match self.command_history.newer() {
+                Some(line) => {
+                    let line = line.to_owned();
+                    self.set_command_line(line);
+                    true
+                }
+                None => false,
+            },
             KeyEvent {
                 code: KeyCode::Enter,
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                let command = self.command_text_area.lines()[0].clone();
+                let line = self.command_text_area.lines()[0].clone();
 
-                info!("Entered command: {}", command);
+                info!("Entered command: {}", line);
+                self.command_history.push(&line);
 
-                match command.as_str() {
-                    ":q" => {
-                        event_sender.send(InteractiveEvent::Quit).await.unwrap();
-                    }
-                    ":w" => {
-                        self.send_message(event_sender).await;
+                match Command::parse_with_macros(&line, &self.resources.config.macros) {
+                    Some(Ok(command)) => self.run_command(command, event_sender).await,
+                    Some(Err(err)) => {
+                        let content = err.to_string();
+                        event_sender
+                            .send(InteractiveEvent::SystemMessage { content })
+                            .await
+                            .unwrap();
                     }
-                    ":wq" | ":qw" => {
-                        self.send_message(event_sender).await;
-                        event_sender.send(InteractiveEvent::Quit).await.unwrap();
-                    }
-                    _ => {}
+                    None => (),
                 }
                 self.command_text_area = TextArea::new(Vec::new());
                 self.resources.state.write().await.mode = VimMode::Normal;
@@ -603,8 +1072,18 @@ impl SendMessageWidget {
                 kind: KeyEventKind::Press,
                 ..
             } => {
-                let result = self.command_text_area.input(event);
-                if self.command_text_area.cursor().0 == 0 {
+                self.command_history.stop_browsing();
+                // The leading `:` stays, the cursor never goes before it.
+                if event.code == KeyCode::Backspace && self.command_text_area.cursor().1 <= 1 {
+                    return false;
+                }
+                let mut result = self.command_text_area.input(event);
+                if !self.command_text_area.lines()[0].starts_with(':') {
+                    // Deleting words or to the line start can still reach it.
+                    self.command_text_area.undo();
+                    result = false;
+                }
+                if self.command_text_area.cursor().1 == 0 {
                     self.command_text_area.move_cursor(CursorMove::Forward);
                 }
                 result
@@ -613,12 +1092,15 @@ impl SendMessageWidget {
         }
     }
     async fn insert_input(&mut self, event: KeyEvent, _event_sender: &EventSender) -> bool {
+        if let Some(changed) = self.completion_input(event, false).await {
+            return changed;
+        }
         if event.code == KeyCode::Esc {
             self.resources.state.write().await.mode = VimMode::Normal;
             self.text_area.set_block(
                     Block::bordered()
                         .title_top(Line::from("Normal").left_aligned())
-                        .border_style(Style::new().fg(Color::Rgb(255, 242, 197)))
+                        .border_style(self.theme.border_style())
                         .title_bottom(Line::from("Type :q to quit").right_aligned()),
                 );
             true
@@ -642,19 +1124,338 @@ impl SendMessageWidget {
     }
 }
 
+impl SendMessageWidget {
+    /// Shows the draft's length against the server's limit on the top border, e.g. `42/4096`,
+    /// in the error color once it's too long.
+    fn render_counter(&self, area: Rect, buf: &mut Buffer) {
+        let (len, max) = (self.draft_len(), self.resources.limits().max_message_length);
+        let counter = format!(" {}/{} ", len, max);
+        let style = match len > max {
+            true => Style::new().fg(self.theme.error),
+            false => self.theme.border_style(),
+        };
+        // Clear of the corner and the mode title.
+        let width = counter.len() as u16;
+        if area.width >= width + 12 {
+            buf.set_string(area.right() - width - 1, area.y, counter, style);
+        }
+    }
+
+    /// Rewrites the absolute line numbers rendered in `area` as distances from the cursor line.
+    ///
+    /// The text area only draws absolute numbers and keeps its scroll position to itself, so
+    /// the rendered numbers are read back to tell which line each row shows.
+    fn relativize_line_numbers(&self, area: Rect, buf: &mut Buffer) {
+        let area = match self.text_area.block() {
+            Some(block) => block.inner(area),
+            None => area,
+        };
+        let digits = self.text_area.lines().len().to_string().len();
+        // Laid out as right-aligned digits after a space, followed by a space.
+        let width = (digits + 2).min(area.width as usize) as u16;
+        let cursor_row = self.text_area.cursor().0;
+        for y in area.top()..area.bottom() {
+            let gutter: String = (area.x..area.x + width)
+                .map(|x| buf[(x, y)].symbol())
+                .collect();
+            let Ok(number) = gutter.trim().parse::<usize>() else {
+                continue;
+            };
+            let shown = match (number - 1).abs_diff(cursor_row) {
+                0 => number,
+                distance => distance,
+            };
+            let style = buf[(area.x, y)].style();
+            let text = format!("{:>width$} ", shown, width = digits + 1);
+            buf.set_stringn(area.x, y, text, width as usize, style);
+        }
+    }
+}
+
 impl Widget for &mut SendMessageWidget {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        self.text_area
-            .set_line_number_style(Style::new().fg(Color::Blue));
+        match self.line_numbers {
+            LineNumbers::Off => self.text_area.remove_line_number(),
+            LineNumbers::Absolute | LineNumbers::Relative => self
+                .text_area
+                .set_line_number_style(Style::new().fg(self.theme.line_numbers)),
+        }
 
-        if self.command_text_area.is_empty() {
+        let text_area = if self.command_text_area.is_empty() {
             self.text_area.render(area, buf);
+            area
         } else {
             let layout = Layout::vertical([Constraint::Fill(1), Constraint::Length(3)]);
             let [text_area, command_area] = layout.areas(area);
 
             self.text_area.render(text_area, buf);
             self.command_text_area.render(command_area, buf);
+            text_area
+        };
+        if self.line_numbers == LineNumbers::Relative {
+            self.relativize_line_numbers(text_area, buf);
+        }
+        self.render_counter(text_area, buf);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use tokio::sync::mpsc::{self, Receiver};
+    use tui_textarea::TextArea;
+
+    use crate::{
+        app::{
+            event::{EventSender, InteractiveEvent},
+            resources::AppResources,
+            vim::{Action, MAX_COUNT, SendMessageWidget},
+        },
+        config::ClientConfig,
+        theme::Theme,
+    };
+
+    async fn widget(
+        lines: &[&str],
+    ) -> (SendMessageWidget, EventSender, Receiver<InteractiveEvent>) {
+        let resources = Arc::new(AppResources::disconnected(ClientConfig::default()).await);
+        let mut widget = SendMessageWidget::new(resources, Theme::default());
+        widget.text_area = TextArea::from(lines.iter().map(|line| line.to_string()));
+        let (event_sender, event_receiver) = mpsc::channel(16);
+        (widget, event_sender, event_receiver)
+    }
+
+    async fn press(widget: &mut SendMessageWidget, event_sender: &EventSender, code: KeyCode) {
+        let event = KeyEvent::new(code, KeyModifiers::NONE);
+        widget.input(event, event_sender).await;
+    }
+
+    async fn type_keys(widget: &mut SendMessageWidget, event_sender: &EventSender, keys: &str) {
+        for c in keys.chars() {
+            let event = KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE);
+            widget.input(event, event_sender).await;
+        }
+    }
+
+    #[test]
+    fn test_digits_are_numbers() {
+        assert_eq!(Action::from_key(KeyCode::Char('0')), Action::Number(0));
+        assert_eq!(Action::from_key(KeyCode::Char('9')), Action::Number(9));
+        assert_eq!(Action::from_key(KeyCode::Char('d')), Action::Char('d'));
+    }
+
+    #[tokio::test]
+    async fn test_height_grows_with_draft() {
+        let (mut widget, _, _) = widget(&["one line"]).await;
+        assert_eq!(widget.height(), 8);
+        widget.text_area = TextArea::from(vec!["line".to_owned(); 9]);
+        assert_eq!(widget.height(), 11);
+        // Scrolls rather than growing past the most lines.
+        widget.text_area = TextArea::from(vec!["line".to_owned(); 40]);
+        assert_eq!(widget.height(), 14);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_after_g_resets() {
+        let (mut widget, event_sender, _events) = widget(&["one", "two", "three"]).await;
+        type_keys(&mut widget, &event_sender, "jgx").await;
+        assert_eq!(widget.prev_action, Action::Empty);
+
+        type_keys(&mut widget, &event_sender, "dd").await;
+        assert_eq!(widget.text_area.lines(), ["one", "three"]);
+
+        // A dangling `g` followed by `g` would otherwise jump to the top.
+        type_keys(&mut widget, &event_sender, "gxg").await;
+        assert_eq!(widget.text_area.cursor().0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key_cancels_operator() {
+        let (mut widget, event_sender, _events) = widget(&["one two", "three"]).await;
+        type_keys(&mut widget, &event_sender, "dxw").await;
+        assert_eq!(widget.text_area.lines(), ["one two", "three"]);
+        assert_eq!(widget.text_area.cursor(), (0, 4));
+
+        type_keys(&mut widget, &event_sender, "3xj").await;
+        assert_eq!(widget.text_area.cursor().0, 1);
+
+        type_keys(&mut widget, &event_sender, "dGk").await;
+        assert_eq!(widget.text_area.lines(), ["one two", "three"]);
+        assert_eq!(widget.prev_action, Action::Empty);
+    }
+
+    #[tokio::test]
+    async fn test_counted_line_operators() {
+        let (mut widget, event_sender, _events) = widget(&["a", "b", "c", "d", "e"]).await;
+        type_keys(&mut widget, &event_sender, "j2dd").await;
+        assert_eq!(widget.text_area.lines(), ["a", "d", "e"]);
+        assert_eq!(widget.text_area.yank_text(), "b\nc");
+
+        type_keys(&mut widget, &event_sender, "3yyGp").await;
+        assert_eq!(widget.text_area.lines(), ["a", "d", "e", "d", "e"]);
+
+        // Counts past the end of the draft stop at its last line.
+        type_keys(&mut widget, &event_sender, "k9dd").await;
+        assert_eq!(widget.text_area.lines(), ["a", "d", "e"]);
+
+        type_keys(&mut widget, &event_sender, "gg2cc").await;
+        assert_eq!(widget.text_area.lines(), ["", "e"]);
+    }
+
+    #[tokio::test]
+    async fn test_count_applies_to_operator_motion() {
+        let (mut widget, event_sender, _events) =
+            widget(&["a b c d e f g h i j k l m n o p"]).await;
+        type_keys(&mut widget, &event_sender, "d2w").await;
+        assert_eq!(widget.text_area.lines(), ["c d e f g h i j k l m n o p"]);
+
+        type_keys(&mut widget, &event_sender, "2dw").await;
+        assert_eq!(widget.text_area.lines(), ["e f g h i j k l m n o p"]);
+
+        type_keys(&mut widget, &event_sender, "d10w").await;
+        assert_eq!(widget.text_area.lines(), ["o p"]);
+    }
+
+    #[tokio::test]
+    async fn test_large_count_is_capped() {
+        let (mut widget, event_sender, _events) = widget(&["a", "b", "c"]).await;
+        type_keys(&mut widget, &event_sender, "99999999999").await;
+        assert_eq!(widget.prev_action, Action::Number(MAX_COUNT));
+        type_keys(&mut widget, &event_sender, "j").await;
+        assert_eq!(widget.text_area.cursor(), (2, 1));
+
+        widget.text_area = TextArea::from(["a b c"]);
+        type_keys(&mut widget, &event_sender, "d99999999999").await;
+        assert_eq!(widget.prev_action, Action::CharNumber('d', MAX_COUNT));
+        type_keys(&mut widget, &event_sender, "w").await;
+        assert_eq!(widget.text_area.lines(), [""]);
+    }
+
+    #[tokio::test]
+    async fn test_find_motions() {
+        let (mut widget, event_sender, _events) = widget(&["a,b,c,d,e"]).await;
+        type_keys(&mut widget, &event_sender, "f,").await;
+        assert_eq!(widget.text_area.cursor(), (0, 1));
+        type_keys(&mut widget, &event_sender, ";").await;
+        assert_eq!(widget.text_area.cursor(), (0, 3));
+        type_keys(&mut widget, &event_sender, ",").await;
+        assert_eq!(widget.text_area.cursor(), (0, 1));
+        type_keys(&mut widget, &event_sender, "2t,").await;
+        assert_eq!(widget.text_area.cursor(), (0, 4));
+        // Repeating a till steps past the match it stopped in front of.
+        type_keys(&mut widget, &event_sender, ";").await;
+        assert_eq!(widget.text_area.cursor(), (0, 6));
+        type_keys(&mut widget, &event_sender, "Fa").await;
+        assert_eq!(widget.text_area.cursor(), (0, 0));
+        type_keys(&mut widget, &event_sender, "3f,T,").await;
+        assert_eq!(widget.text_area.cursor(), (0, 4));
+        // No match leaves the cursor where it is.
+        type_keys(&mut widget, &event_sender, "fz").await;
+        assert_eq!(widget.text_area.cursor(), (0, 4));
+    }
+
+    #[tokio::test]
+    async fn test_find_with_operators() {
+        let (mut widget, event_sender, _events) = widget(&["one, two, three"]).await;
+        type_keys(&mut widget, &event_sender, "df,").await;
+        assert_eq!(widget.text_area.lines(), [" two, three"]);
+        assert_eq!(widget.text_area.yank_text(), "one,");
+
+        type_keys(&mut widget, &event_sender, "ldt,").await;
+        assert_eq!(widget.text_area.lines(), [" , three"]);
+
+        type_keys(&mut widget, &event_sender, "$hdF,").await;
+        assert_eq!(widget.text_area.lines(), [" e"]);
+
+        type_keys(&mut widget, &event_sender, "dfz").await;
+        assert_eq!(widget.text_area.lines(), [" e"]);
+        assert_eq!(widget.prev_action, Action::Empty);
+    }
+
+    #[tokio::test]
+    async fn test_command_prefix_stays() {
+        let (mut widget, event_sender, _events) = widget(&[""]).await;
+        type_keys(&mut widget, &event_sender, ":q").await;
+        for _ in 0..3 {
+            press(&mut widget, &event_sender, KeyCode::Backspace).await;
         }
+        assert_eq!(widget.command_text_area.lines(), [":"]);
+        assert_eq!(widget.command_text_area.cursor(), (0, 1));
+
+        type_keys(&mut widget, &event_sender, "clear").await;
+        press(&mut widget, &event_sender, KeyCode::Left).await;
+        for _ in 0..6 {
+            press(&mut widget, &event_sender, KeyCode::Left).await;
+        }
+        assert_eq!(widget.command_text_area.cursor(), (0, 1));
+        press(&mut widget, &event_sender, KeyCode::Backspace).await;
+        assert_eq!(widget.command_text_area.lines(), [":clear"]);
+
+        let delete_to_head = KeyEvent::new(KeyCode::Char('j'), KeyModifiers::CONTROL);
+        widget.input(delete_to_head, &event_sender).await;
+        assert_eq!(widget.command_text_area.lines(), [":clear"]);
+    }
+
+    #[tokio::test]
+    async fn test_command_draft_survives_esc() {
+        let (mut widget, event_sender, mut events) = widget(&["draft"]).await;
+        type_keys(&mut widget, &event_sender, ":join ru").await;
+        press(&mut widget, &event_sender, KeyCode::Esc).await;
+        assert!(widget.command_text_area.is_empty());
+
+        type_keys(&mut widget, &event_sender, ":").await;
+        assert_eq!(widget.command_text_area.lines(), [":join ru"]);
+        assert_eq!(widget.command_text_area.cursor(), (0, 8));
+        type_keys(&mut widget, &event_sender, "st").await;
+        press(&mut widget, &event_sender, KeyCode::Enter).await;
+        let Ok(InteractiveEvent::JoinChannel { channel }) = events.try_recv() else {
+            panic!("expected JoinChannel");
+        };
+        assert_eq!(channel, "rust");
+
+        // Run commands aren't brought back, nor is the draft touched.
+        type_keys(&mut widget, &event_sender, ":").await;
+        assert_eq!(widget.command_text_area.lines(), [":"]);
+        assert_eq!(widget.text_area.lines(), ["draft"]);
+    }
+
+    #[tokio::test]
+    async fn test_command_history_keys() {
+        let (mut widget, event_sender, _events) = widget(&[""]).await;
+        for command in [":join a", ":clear"] {
+            type_keys(&mut widget, &event_sender, command).await;
+            press(&mut widget, &event_sender, KeyCode::Enter).await;
+        }
+
+        type_keys(&mut widget, &event_sender, ":").await;
+        press(&mut widget, &event_sender, KeyCode::Up).await;
+        assert_eq!(widget.command_text_area.lines(), [":clear"]);
+        assert_eq!(widget.command_text_area.cursor(), (0, 6));
+        press(&mut widget, &event_sender, KeyCode::Up).await;
+        assert_eq!(widget.command_text_area.lines(), [":join a"]);
+        press(&mut widget, &event_sender, KeyCode::Down).await;
+        press(&mut widget, &event_sender, KeyCode::Down).await;
+        assert_eq!(widget.command_text_area.lines(), [":"]);
+    }
+
+    #[tokio::test]
+    async fn test_tab_completes_names() {
+        let (mut widget, event_sender, _events) = widget(&[""]).await;
+        widget.resources.state.write().await.roster = vec!["alice".to_owned(), "bob".to_owned()];
+
+        type_keys(&mut widget, &event_sender, "ihi @b").await;
+        press(&mut widget, &event_sender, KeyCode::Tab).await;
+        assert_eq!(widget.text_area.lines(), ["hi @bob"]);
+        // Without a name to complete Tab indents as usual.
+        type_keys(&mut widget, &event_sender, " ").await;
+        press(&mut widget, &event_sender, KeyCode::Tab).await;
+        assert_eq!(widget.text_area.lines(), ["hi @bob     "]);
+
+        press(&mut widget, &event_sender, KeyCode::Esc).await;
+        type_keys(&mut widget, &event_sender, ":msg a").await;
+        press(&mut widget, &event_sender, KeyCode::Tab).await;
+        assert_eq!(widget.command_text_area.lines(), [":msg alice"]);
     }
 }
@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse config file: {0}")]
+    Parse(#[from] ron::error::SpannedError),
+    #[error("Failed to serialize state: {0}")]
+    Serialize(#[from] ron::Error),
+    #[error("Failed to load theme {name}, it's neither built in nor a readable theme file: {reason}")]
+    Theme { name: String, reason: String },
+}
+
+/// Which incoming messages alert the user.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotifyMode {
+    /// Every message from someone else.
+    All,
+    /// Only messages mentioning our name.
+    #[default]
+    Mentions,
+    Off,
+}
+
+/// Line numbers shown beside the draft.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LineNumbers {
+    Off,
+    #[default]
+    Absolute,
+    /// Distance from the cursor line, which keeps its own number, as vim's `relativenumber`.
+    Relative,
+}
+
+/// Shown in the message pane once joined.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Banner {
+    Off,
+    /// The term-chat logo.
+    #[default]
+    Logo,
+    /// Text of your own, may span several lines.
+    Custom(String),
+}
+
+impl Banner {
+    const LOGO: &str = r" _                                  _           _
+| |_ ___ _ __ _ __ ___         ___| |__   __ _| |_
+| __/ _ \ '__| '_ ` _ \ _____ / __| '_ \ / _` | __|
+| ||  __/ |  | | | | | |_____| (__| | | | (_| | |_
+ \__\___|_|  |_| |_| |_|      \___|_| |_|\__,_|\__|";
+
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Self::Off => None,
+            Self::Logo => Some(Self::LOGO),
+            Self::Custom(text) => Some(text),
+        }
+    }
+}
+
+/// Client settings loaded from `client-config.ron`, missing fields take their default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub notify: NotifyMode,
+    /// Ring the terminal bell on notification.
+    pub bell: bool,
+    /// Show a desktop notification, needs the `desktop-notifications` feature.
+    pub desktop_notifications: bool,
+    /// Seconds to wait for connecting, the handshake and joining before giving up.
+    pub connect_timeout_secs: u64,
+    /// Sent when joining to be allowed admin commands, must match the server's admin token.
+    pub admin_token: Option<String>,
+    /// Line numbers in the draft, changed at runtime with `:set number` and friends.
+    pub line_numbers: LineNumbers,
+    /// File entered `:` commands are kept in across runs, they're forgotten on exit if `None`.
+    pub command_history_file: Option<String>,
+    /// Most `:` commands remembered.
+    pub command_history_size: usize,
+    /// Columns of the user list, changed at runtime with Alt+Left and Alt+Right or by dragging
+    /// its border. A resized width is saved to `client-state.ron` beside the config on exit and
+    /// takes over from this one, this file is never written.
+    pub sidebar_width: u16,
+    /// Draft lines shown before it scrolls, the draft grows up to this many as it gets longer.
+    pub max_draft_lines: u16,
+    /// Shown in the message pane once joined.
+    pub banner: Banner,
+    /// Show the server's message of the day once joined.
+    pub show_motd: bool,
+    /// Colors, `dark` (or `default`), `light`, `solarized` or the path of a RON theme file like
+    /// `theme.ron`. `auto` picks light or dark from the terminal's `COLORFGBG` if set, dark
+    /// otherwise.
+    pub theme: String,
+    /// Most redraws per second, lower it to save CPU and bandwidth e.g. over SSH.
+    pub max_fps: u32,
+    /// Text macros, `:name` inserts the text in the draft.
+    pub macros: BTreeMap<String, String>,
+    /// Connect over TLS instead of the built-in handshake, needs the `tls` feature and a server
+    /// with TLS set up.
+    pub tls: bool,
+    /// PEM file of extra certificates to trust for TLS, e.g. a self-signed server's.
+    pub tls_ca_file: Option<String>,
+    /// Fingerprints of server identity keys as they log on startup, by the server as given to
+    /// --server or the path of its Unix socket. Connecting is aborted if the server presents
+    /// another key. Only checked by the built-in handshake, not over TLS.
+    pub server_fingerprints: BTreeMap<String, String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            notify: NotifyMode::default(),
+            bell: true,
+            desktop_notifications: false,
+            connect_timeout_secs: 10,
+            admin_token: None,
+            line_numbers: LineNumbers::default(),
+            command_history_file: None,
+            command_history_size: 100,
+            sidebar_width: 26,
+            max_draft_lines: 12,
+            banner: Banner::default(),
+            show_motd: true,
+            theme: "auto".to_owned(),
+            max_fps: 30,
+            macros: BTreeMap::from([("shrug".to_owned(), "¯\\_(ツ)_/¯".to_owned())]),
+            tls: false,
+            tls_ca_file: None,
+            server_fingerprints: BTreeMap::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Loads the config at `path`, falling back to defaults if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(ron::de::from_bytes(&bytes)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fingerprint of the key to expect from `server`, `pin` from the command line if given.
+    pub fn server_fingerprint(&self, server: &str, pin: Option<&str>) -> Option<String> {
+        pin.or_else(|| self.server_fingerprints.get(server).map(String::as_str))
+            .map(str::to_owned)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    /// Shortest time between two draws, from `max_fps`.
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.max_fps.max(1)
+    }
+}
+
+/// What the client remembers across runs, kept out of the config so it's never rewritten.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientState {
+    /// Columns the user list was resized to, the config's `sidebar_width` if `None`.
+    pub sidebar_width: Option<u16>,
+}
+
+impl ClientState {
+    /// The state file beside the config at `config_path`.
+    pub fn path(config_path: &Path) -> PathBuf {
+        config_path.with_file_name("client-state.ron")
+    }
+
+    /// Loads the state at `path`, empty if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(ron::de::from_bytes(&bytes)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes the state to `path`, replacing what's there.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        let contents = ron::ser::to_string_pretty(self, PrettyConfig::default())?;
+        std::fs::write(path, contents + "\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, path::Path, time::Duration};
+
+    use crate::config::{ClientConfig, ClientState, LineNumbers};
+
+    #[test]
+    fn test_parse() {
+        let config: ClientConfig = ron::de::from_str(
+            r#"(sidebar_width: 40, line_numbers: Relative, banner: Custom("hello\nthere"))"#,
+        )
+        .unwrap();
+        assert_eq!(config.sidebar_width, 40);
+        assert_eq!(config.line_numbers, LineNumbers::Relative);
+        assert_eq!(config.banner.text(), Some("hello\nthere"));
+        assert_eq!(config.max_fps, ClientConfig::default().max_fps);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let path = std::env::temp_dir().join(format!("term-chat-state-{}.ron", std::process::id()));
+        assert_eq!(ClientState::load(&path).unwrap(), ClientState::default());
+
+        let state = ClientState {
+            sidebar_width: Some(40),
+        };
+        state.save(&path).unwrap();
+        let loaded = ClientState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, state);
+    }
+
+    #[test]
+    fn test_state_path() {
+        assert_eq!(
+            ClientState::path(Path::new("client-config.ron")),
+            Path::new("client-state.ron")
+        );
+        assert_eq!(
+            ClientState::path(Path::new("/etc/term-chat/config.ron")),
+            Path::new("/etc/term-chat/client-state.ron")
+        );
+    }
+
+    #[test]
+    fn test_frame_interval() {
+        let config = |max_fps| ClientConfig {
+            max_fps,
+            ..Default::default()
+        };
+        assert_eq!(
+            config(30).frame_interval(),
+            Duration::from_nanos(33_333_333)
+        );
+        assert_eq!(config(1).frame_interval(), Duration::from_secs(1));
+        // 0 would mean never drawing.
+        assert_eq!(config(0).frame_interval(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_server_fingerprint() {
+        let config = ClientConfig {
+            server_fingerprints: BTreeMap::from([("a:1".to_owned(), "aa".to_owned())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.server_fingerprint("a:1", None).as_deref(),
+            Some("aa")
+        );
+        assert_eq!(config.server_fingerprint("b:1", None), None);
+        assert_eq!(
+            config.server_fingerprint("a:1", Some("bb")).as_deref(),
+            Some("bb")
+        );
+    }
+
+    #[test]
+    fn test_bundled_config() {
+        let config = ClientConfig::load("client-config.ron").unwrap();
+        assert_eq!(config.macros, ClientConfig::default().macros);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::{
+    ClientMessage, MessageId, PROTOCOL_VERSION, ServerMessage,
+    codec::CompressionSettings,
+    stream::{ClientSink, ClientStream},
+};
+use futures::{SinkExt, StreamExt};
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::{
+    CommandArgs,
+    app::{
+        AppError,
+        resources::{connect, join},
+        tls::Security,
+    },
+    config::ClientConfig,
+};
+
+/// Channel the load test talks in, so nobody else gets flooded.
+const CHANNEL: &str = "loadtest";
+/// How long clients keep listening after the last message was sent.
+const DRAIN: Duration = Duration::from_secs(2);
+
+/// What `--loadtest` runs.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadTest {
+    pub clients: usize,
+    /// Messages each client sends per second.
+    pub rate: f64,
+    /// How long clients keep sending.
+    pub duration: Duration,
+}
+
+/// When each message was sent, shared by all clients to time its arrival.
+type SentAt = Arc<Mutex<HashMap<MessageId, Instant>>>;
+
+/// Runs the load test `args` ask for, with the connection settings of the chat.
+pub async fn run_from_args(args: CommandArgs) -> Result<(), AppError> {
+    let config = ClientConfig::load(&args.config)?;
+    if args.insecure && config.tls {
+        return Err(AppError::InsecureTls);
+    }
+    #[cfg(unix)]
+    let unix_socket = args.unix;
+    #[cfg(not(unix))]
+    let unix_socket: Option<PathBuf> = None;
+    // One server is load tested, the Unix socket if given or else the first --server, clap
+    // requires one of them. The host is unused for the Unix socket.
+    let host = args.servers.first().cloned().unwrap_or_default();
+    let server = match &unix_socket {
+        Some(path) => path.display().to_string(),
+        None => host.clone(),
+    };
+    let pin = config.server_fingerprint(&server, args.pin.as_deref());
+    let security = match (args.insecure, &unix_socket) {
+        (true, _) => Security::Insecure,
+        // Unix sockets always use the built-in handshake, there's no host to check TLS for.
+        (false, Some(_)) => Security::Handshake { pin },
+        (false, None) => Security::from_config(&config, &host, pin)?,
+    };
+    let test = LoadTest {
+        clients: args.loadtest.unwrap_or(1) as usize,
+        rate: args.rate,
+        duration: Duration::from_secs_f64(args.duration),
+    };
+    run(test, host, unix_socket, security, config).await
+}
+
+/// Connects `test.clients` headless clients to one channel, has them send at `test.rate` and
+/// prints how quickly the broadcasts reach everyone.
+pub async fn run(
+    test: LoadTest,
+    host: String,
+    unix_socket: Option<PathBuf>,
+    security: Security,
+    config: ClientConfig,
+) -> Result<(), AppError> {
+    let compression = CompressionSettings::default();
+    let mut connections = Vec::with_capacity(test.clients);
+    for i in 0..test.clients {
+        let attempt = async {
+            let (_, mut write_msg, mut read_msg) =
+                connect(&host, unix_socket.clone(), &security, compression).await?;
+            let request = ClientMessage::JoinRequest {
+                name: format!("loadtest-{}", i),
+                channel: Some(CHANNEL.to_owned()),
+                protocol_version: PROTOCOL_VERSION,
+                admin_token: None,
+            };
+            join(&mut write_msg, &mut read_msg, request)
+                .await?
+                .ok_or(AppError::ServerError)?;
+            Ok::<_, AppError>((write_msg, read_msg))
+        };
+        let timeout = config.connect_timeout();
+        let connection = tokio::time::timeout(timeout, attempt)
+            .await
+            .map_err(|_| AppError::ConnectTimeout { timeout })??;
+        connections.push(connection);
+    }
+    println!(
+        "Connected {} clients, sending {} messages/s each for {:?}",
+        test.clients, test.rate, test.duration
+    );
+
+    let sent_at = SentAt::default();
+    let start = Instant::now();
+    let period = Duration::from_secs_f64(1.0 / test.rate);
+    let stop_sending = start + test.duration;
+    let stop_receiving = stop_sending + DRAIN;
+    let mut senders = Vec::with_capacity(test.clients);
+    let mut receivers = Vec::with_capacity(test.clients);
+    for (i, (write_msg, read_msg)) in connections.into_iter().enumerate() {
+        // Spread the clients over the period rather than sending in bursts.
+        let first = start + period.mul_f64(i as f64 / test.clients as f64);
+        senders.push(tokio::spawn(send_loop(
+            write_msg,
+            first,
+            period,
+            stop_sending,
+            Arc::clone(&sent_at),
+        )));
+        receivers.push(tokio::spawn(receive_loop(
+            read_msg,
+            stop_receiving,
+            Arc::clone(&sent_at),
+        )));
+    }
+
+    let mut sent = 0;
+    let mut write_halves = Vec::with_capacity(test.clients);
+    for sender in senders {
+        let (write_msg, count) = sender.await??;
+        sent += count;
+        write_halves.push(write_msg);
+    }
+    let mut latencies = Vec::new();
+    for receiver in receivers {
+        latencies.extend(receiver.await??);
+    }
+    for mut write_msg in write_halves {
+        let _ = write_msg.send(ClientMessage::Leave).await;
+    }
+
+    latencies.sort_unstable();
+    print_summary(test, sent, &latencies);
+    Ok(())
+}
+
+/// Sends a message every `period` from `first` until `stop`, returns the sink and how many
+/// messages were sent.
+async fn send_loop(
+    mut write_msg: ClientSink,
+    first: Instant,
+    period: Duration,
+    stop: Instant,
+    sent_at: SentAt,
+) -> Result<(ClientSink, usize), AppError> {
+    let mut interval = tokio::time::interval_at(first, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut count = 0;
+    loop {
+        let now = interval.tick().await;
+        if now >= stop {
+            break;
+        }
+        let id = MessageId::random();
+        sent_at.lock().unwrap().insert(id, Instant::now());
+        write_msg
+            .send(ClientMessage::SendMessage {
+                id,
+                message: format!("load test message {}", count),
+                reply_to: None,
+            })
+            .await?;
+        count += 1;
+    }
+    Ok((write_msg, count))
+}
+
+/// Collects how long each broadcast took to arrive, until `stop`.
+async fn receive_loop(
+    mut read_msg: ClientStream,
+    stop: Instant,
+    sent_at: SentAt,
+) -> Result<Vec<Duration>, AppError> {
+    let mut latencies = Vec::new();
+    loop {
+        let message = tokio::select! {
+            message = read_msg.next() => message,
+            () = tokio::time::sleep_until(stop) => break,
+        };
+        let messages = match message {
+            Some(Ok(ServerMessage::Batch(messages))) => messages,
+            Some(Ok(message)) => vec![message],
+            Some(Err(err)) => return Err(err.into()),
+            None => break,
+        };
+        for message in messages {
+            if let ServerMessage::ReceiveMessage { id, .. } = message {
+                if let Some(sent) = sent_at.lock().unwrap().get(&id) {
+                    latencies.push(sent.elapsed());
+                }
+            }
+        }
+    }
+    Ok(latencies)
+}
+
+/// Latency at or below which `fraction` of the `sorted` latencies fall.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+fn print_summary(test: LoadTest, sent: usize, latencies: &[Duration]) {
+    // Everyone in the channel gets each message, the sender included.
+    let expected = sent * test.clients;
+    let received = latencies.len();
+    let throughput = received as f64 / test.duration.as_secs_f64();
+    println!();
+    println!(
+        "{:>8} {:>8} {:>10} {:>10} {:>12} {:>10} {:>10} {:>10}",
+        "clients", "sent", "expected", "received", "msg/s", "p50", "p99", "max"
+    );
+    println!(
+        "{:>8} {:>8} {:>10} {:>10} {:>12.1} {:>10.2?} {:>10.2?} {:>10.2?}",
+        test.clients,
+        sent,
+        expected,
+        received,
+        throughput,
+        percentile(latencies, 0.5),
+        percentile(latencies, 0.99),
+        latencies.last().copied().unwrap_or_default(),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use crate::{CommandArgs, loadtest::percentile};
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(51));
+        assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 1.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_needs_server() {
+        assert!(CommandArgs::try_parse_from(["term-chat", "--loadtest", "4"]).is_err());
+        let args = CommandArgs::try_parse_from(["term-chat", "--loadtest", "4", "--server", "a:1"])
+            .unwrap();
+        assert_eq!(args.servers, ["a:1"]);
+        #[cfg(unix)]
+        CommandArgs::try_parse_from(["term-chat", "--loadtest", "4", "--unix", "a.sock"]).unwrap();
+        // Without --loadtest the chat falls back to the term-chat server.
+        CommandArgs::try_parse_from(["term-chat"]).unwrap();
+    }
+}
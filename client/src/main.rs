@@ -7,11 +7,62 @@ use log::error;
 use crate::app::run_app;
 
 pub mod app;
+pub mod config;
+pub mod loadtest;
+pub mod theme;
 
 /// Client for term-chat
 #[derive(clap::Parser)]
+#[command(group(clap::ArgGroup::new("target").multiple(true)))]
 pub struct CommandArgs {
-    name: String,
+    /// Name to join with, asked for if omitted.
+    name: Option<String>,
+    /// Channel to join, the server's default channel if omitted.
+    #[arg(long)]
+    channel: Option<String>,
+    /// Server to connect to as host:port, repeat to open a tab for each. The term-chat server
+    /// if neither this nor --unix is given.
+    #[arg(long = "server", value_name = "HOST", group = "target")]
+    servers: Vec<String>,
+    /// Connect to a server's Unix socket at this path, in a tab of its own next to those of
+    /// --server.
+    #[cfg(unix)]
+    #[arg(long, group = "target")]
+    unix: Option<std::path::PathBuf>,
+    /// Fingerprint of the server key to expect, overriding `server_fingerprints` in the config.
+    /// Only for a single server.
+    #[arg(long, value_name = "FINGERPRINT")]
+    pin: Option<String>,
+    /// Connect without encryption to inspect traffic while debugging, the server has to allow
+    /// it with `allow_insecure`.
+    #[arg(long, conflicts_with = "pin")]
+    insecure: bool,
+    /// Path to the client config file.
+    #[arg(long, default_value = "client-config.ron")]
+    config: String,
+    /// Connect this many headless clients and report broadcast latency instead of opening the
+    /// chat, needs --server or --unix.
+    #[arg(
+        long,
+        value_name = "CLIENTS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "target"
+    )]
+    loadtest: Option<u32>,
+    /// Messages each load test client sends per second.
+    #[arg(long, default_value_t = 1.0, value_parser = positive, requires = "loadtest")]
+    rate: f64,
+    /// Seconds the load test clients keep sending.
+    #[arg(long, default_value_t = 30.0, value_parser = positive, requires = "loadtest")]
+    duration: f64,
+}
+
+fn positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Ok(_) => Err("must be greater than zero".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
 }
 
 fn main() -> ExitCode {
@@ -36,10 +87,15 @@ fn main() -> ExitCode {
         }
     };
 
-    if let Err(err) = rt.block_on(run_app(args)) {
+    let result = match args.loadtest {
+        Some(_) => rt.block_on(loadtest::run_from_args(args)),
+        None => rt.block_on(run_app(args)),
+    };
+    if let Err(err) = result {
         error!("Error occurred: {}", err);
+        eprintln!("{}", err);
         return ExitCode::FAILURE;
     }
 
-    return ExitCode::SUCCESS;
+    ExitCode::SUCCESS
 }
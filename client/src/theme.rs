@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use ratatui::style::{Color, Style};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+
+/// Colors of the chat, from a built-in theme or a RON theme file. Colors are names such as
+/// `"cyan"`, hex such as `"#fff2c5"` or indices into the terminal's palette such as `"42"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    /// Borders of all panes.
+    pub border: Color,
+    /// The title row.
+    pub title: Color,
+    /// Message text.
+    pub text: Color,
+    /// Names of others above their messages.
+    pub sender: Color,
+    /// Our own name above our messages.
+    pub own_message: Color,
+    /// Behind the names above messages.
+    pub header_background: Color,
+    /// System messages and reply previews.
+    pub system_message: Color,
+    /// Behind the selected message or user.
+    pub selection: Color,
+    /// Users idle for a while.
+    pub away: Color,
+    /// Line numbers beside the draft.
+    pub line_numbers: Color,
+    /// Messages not yet confirmed by the server and the edited marker.
+    pub muted: Color,
+    /// Delivered marks.
+    pub success: Color,
+    /// Failed messages and input errors.
+    pub error: Color,
+}
+
+/// The dark theme.
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            border: Color::Rgb(255, 242, 197),
+            title: Color::Rgb(255, 242, 197),
+            text: Color::Reset,
+            sender: Color::Cyan,
+            own_message: Color::LightGreen,
+            header_background: Color::Black,
+            system_message: Color::DarkGray,
+            selection: Color::Blue,
+            away: Color::DarkGray,
+            line_numbers: Color::Blue,
+            muted: Color::DarkGray,
+            success: Color::Green,
+            error: Color::Red,
+        }
+    }
+}
+
+impl Theme {
+    /// For terminals with a light background.
+    pub fn light() -> Self {
+        let green = Color::Rgb(0, 128, 0);
+        Self {
+            border: Color::Rgb(135, 95, 0),
+            title: Color::Rgb(135, 95, 0),
+            text: Color::Reset,
+            sender: Color::Blue,
+            own_message: green,
+            header_background: Color::Reset,
+            system_message: Color::DarkGray,
+            selection: Color::Rgb(200, 220, 255),
+            away: Color::DarkGray,
+            line_numbers: Color::Blue,
+            muted: Color::DarkGray,
+            success: green,
+            error: Color::Red,
+        }
+    }
+
+    /// The light theme if the terminal reports a light background, the dark one otherwise.
+    pub fn auto() -> Self {
+        match light_background(std::env::var("COLORFGBG").ok().as_deref()) {
+            true => Self::light(),
+            false => Self::default(),
+        }
+    }
+
+    /// The solarized dark palette.
+    pub fn solarized() -> Self {
+        let base01 = Color::Rgb(88, 110, 117);
+        Self {
+            border: Color::Rgb(147, 161, 161),
+            title: Color::Rgb(181, 137, 0),
+            text: Color::Reset,
+            sender: Color::Rgb(38, 139, 210),
+            own_message: Color::Rgb(133, 153, 0),
+            header_background: Color::Reset,
+            system_message: base01,
+            selection: Color::Rgb(7, 54, 66),
+            away: base01,
+            line_numbers: base01,
+            muted: base01,
+            success: Color::Rgb(133, 153, 0),
+            error: Color::Rgb(220, 50, 47),
+        }
+    }
+
+    /// The built-in theme called `name`, or else the theme in the file at `name`.
+    pub fn load(name: &str) -> Result<Self, ConfigError> {
+        match name {
+            "auto" => Ok(Self::auto()),
+            "dark" | "default" => Ok(Self::default()),
+            "light" => Ok(Self::light()),
+            "solarized" => Ok(Self::solarized()),
+            path => Self::load_file(path).map_err(|err| ConfigError::Theme {
+                name: name.to_owned(),
+                reason: err.to_string(),
+            }),
+        }
+    }
+
+    fn load_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let bytes = std::fs::read(path)?;
+        Ok(ron::de::from_bytes(&bytes)?)
+    }
+
+    pub fn border_style(&self) -> Style {
+        Style::new().fg(self.border)
+    }
+
+    pub fn selection_style(&self) -> Style {
+        Style::new().bg(self.selection)
+    }
+}
+
+/// Whether `colorfgbg`, the `COLORFGBG` variable some terminals set to e.g. `15;0`, ends in a
+/// light background color. Unknown backgrounds count as dark.
+fn light_background(colorfgbg: Option<&str>) -> bool {
+    let background = colorfgbg
+        .and_then(|colors| colors.rsplit(';').next())
+        .and_then(|color| color.parse::<u8>().ok());
+    matches!(background, Some(7 | 9..=15))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::theme::{Theme, light_background};
+
+    #[test]
+    fn test_bundled_theme() {
+        assert_eq!(Theme::load("theme.ron").unwrap(), Theme::default());
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(Theme::load("dark").unwrap(), Theme::default());
+        assert_eq!(Theme::load("default").unwrap(), Theme::default());
+        assert_eq!(Theme::load("light").unwrap(), Theme::light());
+        assert_eq!(Theme::load("solarized").unwrap(), Theme::solarized());
+        let err = Theme::load("no-such-theme.ron").unwrap_err();
+        assert!(err.to_string().contains("no-such-theme.ron"));
+    }
+
+    #[test]
+    fn test_light_background() {
+        assert!(light_background(Some("0;15")));
+        assert!(light_background(Some("0;default;7")));
+        assert!(!light_background(Some("15;0")));
+        assert!(!light_background(Some("15;default")));
+        assert!(!light_background(None));
+    }
+}
@@ -0,0 +1,118 @@
+//! Fans messages out to many clients over loopback TCP, flushing after every message versus
+//! feeding a batch and flushing once, as the server's writer tasks do.
+//!
+//! Run with `cargo bench -p common --bench broadcast`. Batching saves one write syscall per
+//! message and client, but encryption dominates the time, so the difference is small next to
+//! the run to run noise of loopback TCP.
+
+use std::time::{Duration, Instant};
+
+use common::{
+    ClientId, ClientMessage, MessageId, PeerAddr, ServerMessage,
+    codec::CompressionSettings,
+    stream::{EncodedMessage, ServerSink, split_message_stream},
+};
+use criterion::{Criterion, SamplingMode, criterion_group, criterion_main};
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CLIENTS: usize = 64;
+const MESSAGES: usize = 1000;
+const BATCH: usize = 32;
+
+async fn connect_clients(count: usize) -> (Vec<ServerSink>, Vec<tokio::task::JoinHandle<()>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut sinks = Vec::with_capacity(count);
+    let mut readers = Vec::with_capacity(count);
+    for _ in 0..count {
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            split_message_stream::<ClientMessage, ServerMessage>(
+                stream,
+                CompressionSettings::default(),
+            )
+            .await
+            .unwrap()
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let (sink, _stream) = split_message_stream::<ServerMessage, ClientMessage>(
+            stream,
+            CompressionSettings::default(),
+        )
+        .await
+        .unwrap();
+        let (_send, mut recv) = client.await.unwrap();
+        readers.push(tokio::spawn(async move {
+            for _ in 0..MESSAGES {
+                recv.next().await.unwrap().unwrap();
+            }
+        }));
+        sinks.push(sink);
+    }
+    (sinks, readers)
+}
+
+async fn broadcast(batched: bool) -> Duration {
+    let (sinks, readers) = connect_clients(CLIENTS).await;
+    let message = EncodedMessage::new(&ServerMessage::ReceiveMessage {
+        id: MessageId(0),
+        sender: ClientId {
+            name: "bench".to_owned(),
+            addr: PeerAddr::Virtual(0),
+        },
+        message: "x".repeat(200),
+        reply_to: None,
+    })
+    .unwrap();
+
+    let start = Instant::now();
+    let writers = sinks.into_iter().map(|mut sink| {
+        let message = message.clone();
+        tokio::spawn(async move {
+            for i in 0..MESSAGES {
+                if batched {
+                    sink.feed_encoded(message.clone()).await.unwrap();
+                    if (i + 1) % BATCH == 0 {
+                        sink.flush().await.unwrap();
+                    }
+                } else {
+                    sink.send_encoded(message.clone()).await.unwrap();
+                }
+            }
+            sink.flush().await.unwrap();
+            sink
+        })
+    });
+    let writers = futures::future::join_all(writers).await;
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    drop(writers);
+    elapsed
+}
+
+fn bench_broadcast(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Each run takes about half a second, so a few runs of one iteration each.
+    let mut group = c.benchmark_group("broadcast");
+    group
+        .sampling_mode(SamplingMode::Flat)
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(10));
+    for (name, batched) in [("flush per message", false), ("flush per batch", true)] {
+        group.bench_function(name, |bench| {
+            // Connecting the clients is left out of the measurement.
+            bench.iter_custom(|iters| (0..iters).map(|_| rt.block_on(broadcast(batched))).sum());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_broadcast);
+criterion_main!(benches);
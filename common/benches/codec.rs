@@ -0,0 +1,113 @@
+//! Round trips `ServerMessage::ReceiveMessage` of growing sizes through the codec stack over an
+//! in-memory pipe: plain CBOR framing, compressed framing and the encrypted stream on top.
+//!
+//! Run with `cargo bench -p common --bench codec`.
+
+use bytes::Bytes;
+use common::{
+    ClientId, MessageId, PeerAddr, ServerMessage,
+    codec::{CborStream, CompressedCborStream},
+    secure::SecureStream,
+};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::{io::DuplexStream, runtime::Runtime};
+
+/// Message lengths in characters, from a short line to a large paste.
+const SIZES: [usize; 4] = [16, 256, 4 * 1024, 64 * 1024];
+
+/// Big enough for the largest frame, so a send never waits on the receiving side.
+const PIPE_CAPACITY: usize = 1024 * 1024;
+
+fn message(len: usize) -> ServerMessage {
+    // Words rather than a single repeated byte, so compression has realistic work to do.
+    let text = "the quick brown fox jumps over the lazy dog "
+        .chars()
+        .cycle()
+        .take(len)
+        .collect();
+    ServerMessage::ReceiveMessage {
+        id: MessageId(42),
+        sender: ClientId {
+            name: "alice".to_owned(),
+            addr: PeerAddr::Virtual(0),
+        },
+        message: text,
+        reply_to: None,
+    }
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+/// Sends `item` through `send` and waits for it on `recv`.
+async fn round_trip<T, Tx, Rx, E>(send: &mut Tx, recv: &mut Rx, item: T)
+where
+    Tx: Sink<T> + Unpin,
+    Tx::Error: std::fmt::Debug,
+    Rx: Stream<Item = Result<T, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    send.send(item).await.unwrap();
+    recv.next().await.unwrap().unwrap();
+}
+
+fn bench_framing(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("framing");
+    for len in SIZES {
+        let message = message(len);
+        let encoded_len = serde_cbor::to_vec(&message).unwrap().len();
+        group.throughput(Throughput::Bytes(encoded_len as u64));
+
+        let (a, b) = tokio::io::duplex(PIPE_CAPACITY);
+        let mut send = CborStream::<DuplexStream, ServerMessage>::new(a);
+        let mut recv = CborStream::<DuplexStream, ServerMessage>::new(b);
+        group.bench_with_input(BenchmarkId::new("cbor", len), &message, |bench, message| {
+            bench.iter(|| rt.block_on(round_trip(&mut send, &mut recv, message.clone())));
+        });
+
+        let (a, b) = tokio::io::duplex(PIPE_CAPACITY);
+        let mut send = CompressedCborStream::<DuplexStream, ServerMessage>::new(a);
+        let mut recv = CompressedCborStream::<DuplexStream, ServerMessage>::new(b);
+        group.bench_with_input(
+            BenchmarkId::new("compressed", len),
+            &message,
+            |bench, message| {
+                bench.iter(|| rt.block_on(round_trip(&mut send, &mut recv, message.clone())));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_secure(c: &mut Criterion) {
+    let rt = runtime();
+    let (mut send, mut recv) = rt.block_on(async {
+        let (a, b) = tokio::io::duplex(PIPE_CAPACITY);
+        let server = tokio::spawn(SecureStream::handshake(b));
+        let client = SecureStream::handshake(a).await.unwrap();
+        (client, server.await.unwrap().unwrap())
+    });
+
+    let mut group = c.benchmark_group("secure");
+    for len in SIZES {
+        let payload = Bytes::from(serde_cbor::to_vec(&message(len)).unwrap());
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("encrypted", len),
+            &payload,
+            |bench, payload| {
+                bench.iter(|| rt.block_on(round_trip(&mut send, &mut recv, payload.clone())));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_framing, bench_secure);
+criterion_main!(benches);
@@ -1,20 +1,192 @@
 use std::{marker::PhantomData, task::Poll};
 
 use futures::{Sink, Stream};
-use serde::{Serialize, de::DeserializeOwned};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::{
-    bytes::Bytes,
+    bytes::{BufMut, Bytes, BytesMut},
     codec::{Framed, LengthDelimitedCodec},
 };
 
-pub struct CompressedCborStream<S, Item>
-where
-    S: AsyncWrite + AsyncRead,
-    Item: DeserializeOwned + Serialize,
-{
-    inner: Framed<S, tokio_util::codec::LengthDelimitedCodec>,
-    _phantom: PhantomData<Item>,
+/// Frame header byte for a payload stored as-is.
+const FRAME_UNCOMPRESSED: u8 = 0;
+/// Frame header byte for an LZ4 compressed payload.
+const FRAME_LZ4: u8 = 1;
+/// Frame header byte for a zstd compressed payload.
+const FRAME_ZSTD: u8 = 2;
+
+/// Payloads smaller than this many bytes are not worth compressing.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 128;
+
+/// Compression algorithm used for frames above the threshold.
+///
+/// Frames record which algorithm produced them, so peers don't need to agree on this.
+#[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgorithm {
+    /// Fast with moderate ratios.
+    #[default]
+    Lz4,
+    /// Slower but compresses chat text considerably better.
+    Zstd,
+}
+
+/// Compression level, trading CPU for bandwidth.
+#[derive(Debug, Default, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionLevel {
+    #[default]
+    Default,
+    /// Faster compression with the given acceleration, higher is faster.
+    Fast(i32),
+    /// Better compression with the given level, higher compresses more.
+    High(i32),
+}
+
+impl CompressionLevel {
+    /// Level in zstd's scale, where negative levels trade ratio for speed.
+    fn zstd_level(self) -> i32 {
+        match self {
+            CompressionLevel::Default => zstd::DEFAULT_COMPRESSION_LEVEL,
+            CompressionLevel::Fast(acceleration) => -acceleration.max(1),
+            CompressionLevel::High(level) => level,
+        }
+    }
+}
+
+impl From<CompressionLevel> for lz4::block::CompressionMode {
+    fn from(value: CompressionLevel) -> Self {
+        match value {
+            CompressionLevel::Default => Self::DEFAULT,
+            CompressionLevel::Fast(acceleration) => Self::FAST(acceleration),
+            CompressionLevel::High(level) => Self::HIGHCOMPRESSION(level),
+        }
+    }
+}
+
+/// Send-side compression options for [`CompressedCborStream`].
+///
+/// The receiving side needs no configuration since decoding doesn't depend on these.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    /// Only compress payloads of at least this many bytes, `0` compresses everything.
+    pub threshold: usize,
+    #[serde(default)]
+    pub algorithm: CompressionAlgorithm,
+    pub level: CompressionLevel,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            algorithm: CompressionAlgorithm::default(),
+            level: CompressionLevel::default(),
+        }
+    }
+}
+
+/// Upper bound on the uncompressed size a frame may claim, guarding against allocation bombs.
+pub const MAX_UNCOMPRESSED_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Wraps an encoded payload in a frame, compressing it if it's above the threshold.
+///
+/// Compressed frames are laid out as `[algorithm header][u32 little-endian uncompressed length][data]`,
+/// uncompressed ones as `[FRAME_UNCOMPRESSED][payload]`.
+///
+/// The frame is built in `buffer`, whose allocation is reused once the returned frame is
+/// dropped.
+pub(crate) fn encode_frame(
+    payload: &[u8],
+    compression: CompressionSettings,
+    buffer: &mut BytesMut,
+) -> std::io::Result<Bytes> {
+    if payload.len() < compression.threshold {
+        buffer.reserve(payload.len() + 1);
+        buffer.put_u8(FRAME_UNCOMPRESSED);
+        buffer.extend_from_slice(payload);
+        return Ok(buffer.split().freeze());
+    }
+
+    let uncompressed_len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_UNCOMPRESSED_FRAME_SIZE)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Payload too large to frame",
+            )
+        })?;
+    let (header, compressed_bytes) = match compression.algorithm {
+        CompressionAlgorithm::Lz4 => (
+            FRAME_LZ4,
+            lz4::block::compress(payload, Some(compression.level.into()), false)?,
+        ),
+        CompressionAlgorithm::Zstd => (
+            FRAME_ZSTD,
+            zstd::bulk::compress(payload, compression.level.zstd_level())?,
+        ),
+    };
+
+    buffer.reserve(compressed_bytes.len() + 5);
+    buffer.put_u8(header);
+    buffer.put_u32_le(uncompressed_len);
+    buffer.extend_from_slice(&compressed_bytes);
+    Ok(buffer.split().freeze())
+}
+
+/// Extracts the payload from a frame produced by [`encode_frame`].
+pub(crate) fn decode_frame(frame: &[u8]) -> std::io::Result<Vec<u8>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    match frame.split_first() {
+        Some((&FRAME_UNCOMPRESSED, payload)) => Ok(payload.to_vec()),
+        Some((&header @ (FRAME_LZ4 | FRAME_ZSTD), rest)) => {
+            let Some((len_bytes, compressed)) = rest.split_first_chunk::<4>() else {
+                return Err(invalid(
+                    "Truncated frame: missing uncompressed length".to_owned(),
+                ));
+            };
+            let uncompressed_len = u32::from_le_bytes(*len_bytes) as usize;
+            if uncompressed_len > MAX_UNCOMPRESSED_FRAME_SIZE {
+                return Err(invalid(format!(
+                    "Frame claims {} uncompressed bytes, limit is {}",
+                    uncompressed_len, MAX_UNCOMPRESSED_FRAME_SIZE
+                )));
+            }
+            let payload = if header == FRAME_LZ4 {
+                lz4::block::decompress(compressed, Some(uncompressed_len as i32))
+            } else {
+                zstd::bulk::decompress(compressed, uncompressed_len)
+            }
+            .map_err(|err| invalid(format!("Corrupt compressed frame: {}", err)))?;
+            if payload.len() != uncompressed_len {
+                return Err(invalid(format!(
+                    "Frame decompressed to {} bytes, expected {}",
+                    payload.len(),
+                    uncompressed_len
+                )));
+            }
+            Ok(payload)
+        }
+        Some((header, _)) => Err(invalid(format!("Unknown frame header: {}", header))),
+        None => Err(invalid("Empty frame".to_owned())),
+    }
+}
+
+pin_project! {
+    /// Length delimited CBOR stream with LZ4 or zstd compression.
+    ///
+    /// Each frame starts with a one byte header saying whether the rest of the frame is compressed,
+    /// see [`encode_frame`] for the layout.
+    pub struct CompressedCborStream<S, Item> {
+        #[pin]
+        inner: Framed<S, tokio_util::codec::LengthDelimitedCodec>,
+        compression: CompressionSettings,
+        // Reused for every item sent, rather than allocating per item.
+        payload: Vec<u8>,
+        frame: BytesMut,
+        _phantom: PhantomData<Item>,
+    }
 }
 
 impl<S, Item> CompressedCborStream<S, Item>
@@ -23,8 +195,26 @@ where
     Item: DeserializeOwned + Serialize,
 {
     pub fn new(inner: S) -> Self {
+        Self::with_compression(inner, CompressionSettings::default())
+    }
+
+    /// Only compress payloads of at least `compression_threshold` bytes, `0` compresses everything.
+    pub fn with_threshold(inner: S, compression_threshold: usize) -> Self {
+        Self::with_compression(
+            inner,
+            CompressionSettings {
+                threshold: compression_threshold,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_compression(inner: S, compression: CompressionSettings) -> Self {
         Self {
             inner: Framed::new(inner, LengthDelimitedCodec::new()),
+            compression,
+            payload: Vec::new(),
+            frame: BytesMut::new(),
             _phantom: PhantomData,
         }
     }
@@ -37,17 +227,17 @@ where
 {
     type Item = std::io::Result<Item>;
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_next(cx) {
+        match self.project().inner.poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(bytes)) => {
                 let bytes = bytes?;
-                let uncompressed_bytes = lz4::block::decompress(&bytes, None)?;
+                let payload = decode_frame(&bytes)?;
                 Poll::Ready(Some(Ok(
-                    serde_cbor::from_slice(&uncompressed_bytes).map_err(std::io::Error::other)?
+                    serde_cbor::from_slice(&payload).map_err(std::io::Error::other)?
                 )))
             }
         }
@@ -61,39 +251,38 @@ where
 {
     type Error = std::io::Error;
     fn poll_ready(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_ready(cx)
+        self.project().inner.poll_ready(cx)
     }
     fn poll_close(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_close(cx)
+        self.project().inner.poll_close(cx)
     }
     fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_flush(cx)
+        self.project().inner.poll_flush(cx)
     }
-    fn start_send(mut self: std::pin::Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
-        let bytes = serde_cbor::ser::to_vec(&item).map_err(std::io::Error::other)?;
-        let compressed_bytes =
-            lz4::block::compress(&bytes, Some(lz4::block::CompressionMode::DEFAULT), true)?;
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .start_send(Bytes::from_owner(compressed_bytes))
+    fn start_send(self: std::pin::Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        let this = self.project();
+        this.payload.clear();
+        serde_cbor::to_writer(&mut *this.payload, &item).map_err(std::io::Error::other)?;
+        let frame = encode_frame(this.payload, *this.compression, this.frame)?;
+        this.inner.start_send(frame)
     }
 }
 
-pub struct CborStream<S, Item>
-where
-    S: AsyncWrite + AsyncRead,
-    Item: DeserializeOwned + Serialize,
-{
-    inner: Framed<S, tokio_util::codec::LengthDelimitedCodec>,
-    _phantom: PhantomData<Item>,
+pin_project! {
+    pub struct CborStream<S, Item> {
+        #[pin]
+        inner: Framed<S, tokio_util::codec::LengthDelimitedCodec>,
+        _phantom: PhantomData<Item>,
+    }
 }
 
 impl<S, Item> CborStream<S, Item>
@@ -116,10 +305,10 @@ where
 {
     type Item = std::io::Result<Item>;
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_next(cx) {
+        match self.project().inner.poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(bytes)) => {
@@ -139,27 +328,26 @@ where
 {
     type Error = std::io::Error;
     fn poll_ready(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_ready(cx)
+        self.project().inner.poll_ready(cx)
     }
     fn poll_close(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_close(cx)
+        self.project().inner.poll_close(cx)
     }
     fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_flush(cx)
+        self.project().inner.poll_flush(cx)
     }
-    fn start_send(mut self: std::pin::Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+    fn start_send(self: std::pin::Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
         let bytes = serde_cbor::ser::to_vec(&item).map_err(std::io::Error::other)?;
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .start_send(Bytes::from_owner(bytes))
+        self.project().inner.start_send(Bytes::from_owner(bytes))
     }
 }
 
@@ -168,7 +356,15 @@ mod test {
     use futures::{SinkExt, StreamExt};
     use serde::{Deserialize, Serialize};
 
-    use crate::codec::{CborStream, CompressedCborStream};
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Framed, LengthDelimitedCodec},
+    };
+
+    use crate::codec::{
+        CborStream, CompressedCborStream, CompressionAlgorithm, CompressionLevel,
+        CompressionSettings, FRAME_LZ4, FRAME_UNCOMPRESSED, FRAME_ZSTD, decode_frame, encode_frame,
+    };
 
     #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
     struct TestEnum {
@@ -177,7 +373,7 @@ mod test {
         void: (),
     }
 
-        #[test]
+    #[test]
     fn test_compressed_cbor_stream() {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_io()
@@ -189,12 +385,15 @@ mod test {
             number: 69,
             void: (),
         };
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
         let client = {
             let test_enum = test_enum.clone();
             rt.spawn(async move {
-                let stream = tokio::net::TcpStream::connect("localhost:1234")
-                    .await
-                    .unwrap();
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
 
                 let cbor_stream = CompressedCborStream::new(stream);
 
@@ -212,10 +411,6 @@ mod test {
         let server = {
             rt.spawn(async move {
                 let test_enum = test_enum.clone();
-                let listener = tokio::net::TcpListener::bind("localhost:1234")
-                    .await
-                    .unwrap();
-
                 let (stream, _) = listener.accept().await.unwrap();
 
                 let cbor_stream = CompressedCborStream::new(stream);
@@ -249,12 +444,15 @@ mod test {
             number: 69,
             void: (),
         };
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
         let client = {
             let test_enum = test_enum.clone();
             rt.spawn(async move {
-                let stream = tokio::net::TcpStream::connect("localhost:1234")
-                    .await
-                    .unwrap();
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
 
                 let cbor_stream = CborStream::new(stream);
 
@@ -272,10 +470,6 @@ mod test {
         let server = {
             rt.spawn(async move {
                 let test_enum = test_enum.clone();
-                let listener = tokio::net::TcpListener::bind("localhost:1234")
-                    .await
-                    .unwrap();
-
                 let (stream, _) = listener.accept().await.unwrap();
 
                 let cbor_stream = CborStream::new(stream);
@@ -296,4 +490,265 @@ mod test {
             server.await.unwrap();
         });
     }
+
+    /// Sends `item` through a [`CompressedCborStream`] and returns the raw frame plus the decoded item.
+    fn round_trip_frame(item: TestEnum, compression: CompressionSettings) -> (Vec<u8>, TestEnum) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async move {
+            let (a, b) = tokio::io::duplex(64 * 1024);
+            let mut send = CompressedCborStream::<_, TestEnum>::with_compression(a, compression);
+            let mut raw = Framed::new(b, LengthDelimitedCodec::new());
+
+            send.send(item).await.unwrap();
+            let frame = raw.next().await.unwrap().unwrap();
+
+            let (c, d) = tokio::io::duplex(64 * 1024);
+            let mut raw = Framed::new(c, LengthDelimitedCodec::new());
+            let mut recv = CompressedCborStream::<_, TestEnum>::new(d);
+            raw.send(frame.clone().freeze()).await.unwrap();
+            let decoded = recv.next().await.unwrap().unwrap();
+
+            (frame.to_vec(), decoded)
+        })
+    }
+
+    #[test]
+    fn test_small_payload_uncompressed() {
+        let test_enum = TestEnum {
+            string: "hi".to_owned(),
+            number: 1,
+            void: (),
+        };
+
+        let (frame, decoded) = round_trip_frame(test_enum.clone(), CompressionSettings::default());
+
+        assert_eq!(frame[0], FRAME_UNCOMPRESSED);
+        assert_eq!(decoded, test_enum);
+    }
+
+    #[test]
+    fn test_large_payload_compressed() {
+        let test_enum = TestEnum {
+            string: "hello ".repeat(512),
+            number: 2,
+            void: (),
+        };
+
+        let (frame, decoded) = round_trip_frame(test_enum.clone(), CompressionSettings::default());
+
+        assert_eq!(frame[0], FRAME_LZ4);
+        assert!(frame.len() < test_enum.string.len());
+        assert_eq!(decoded, test_enum);
+    }
+
+    #[test]
+    fn test_zero_threshold_always_compresses() {
+        let test_enum = TestEnum {
+            string: "hi".to_owned(),
+            number: 1,
+            void: (),
+        };
+
+        let (frame, decoded) = round_trip_frame(
+            test_enum.clone(),
+            CompressionSettings {
+                threshold: 0,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(frame[0], FRAME_LZ4);
+        assert_eq!(decoded, test_enum);
+    }
+
+    #[test]
+    fn test_compression_levels_decode() {
+        let test_enum = TestEnum {
+            string: "hello ".repeat(512),
+            number: 3,
+            void: (),
+        };
+
+        for level in [
+            CompressionLevel::Default,
+            CompressionLevel::Fast(8),
+            CompressionLevel::High(9),
+        ] {
+            let (frame, decoded) = round_trip_frame(
+                test_enum.clone(),
+                CompressionSettings {
+                    level,
+                    ..Default::default()
+                },
+            );
+
+            assert_eq!(frame[0], FRAME_LZ4);
+            assert_eq!(decoded, test_enum);
+        }
+    }
+
+    #[test]
+    fn test_decode_frame_from_encoder() {
+        let payload = "hello ".repeat(512).into_bytes();
+
+        let frame = encode_frame(
+            &payload,
+            CompressionSettings::default(),
+            &mut BytesMut::new(),
+        )
+        .unwrap();
+
+        assert_eq!(frame[0], FRAME_LZ4);
+        assert_eq!(
+            u32::from_le_bytes(frame[1..5].try_into().unwrap()) as usize,
+            payload.len()
+        );
+        assert_eq!(decode_frame(&frame).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_frame_buffer_is_reused() {
+        let payload = b"hello";
+        let mut buffer = BytesMut::new();
+
+        let frame = encode_frame(payload, CompressionSettings::default(), &mut buffer).unwrap();
+        let allocation = frame.as_ptr();
+        drop(frame);
+        let frame = encode_frame(payload, CompressionSettings::default(), &mut buffer).unwrap();
+
+        assert_eq!(frame.as_ptr(), allocation);
+        assert_eq!(decode_frame(&frame).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_decode_truncated_frame() {
+        let payload = "hello ".repeat(512).into_bytes();
+        let frame = encode_frame(
+            &payload,
+            CompressionSettings::default(),
+            &mut BytesMut::new(),
+        )
+        .unwrap();
+
+        for len in [0, 1, 3, 5, frame.len() / 2, frame.len() - 1] {
+            let err = decode_frame(&frame[..len]).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_zstd_cbor_stream() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let test_enum = TestEnum {
+            string: "Bro ".repeat(256),
+            number: 69,
+            void: (),
+        };
+        let compression = CompressionSettings {
+            algorithm: CompressionAlgorithm::Zstd,
+            ..Default::default()
+        };
+
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = {
+            let test_enum = test_enum.clone();
+            rt.spawn(async move {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+                let cbor_stream = CompressedCborStream::with_compression(stream, compression);
+
+                let (mut send, mut recv) = cbor_stream.split();
+
+                send.send(test_enum.clone()).await.unwrap();
+
+                assert_eq!(
+                    recv.next().await.transpose().unwrap(),
+                    Some(test_enum.clone())
+                );
+            })
+        };
+
+        let server = {
+            rt.spawn(async move {
+                let test_enum = test_enum.clone();
+                let (stream, _) = listener.accept().await.unwrap();
+
+                let cbor_stream = CompressedCborStream::with_compression(stream, compression);
+
+                let (mut send, mut recv) = cbor_stream.split();
+
+                send.send(test_enum.clone()).await.unwrap();
+
+                assert_eq!(
+                    recv.next().await.transpose().unwrap(),
+                    Some(test_enum.clone())
+                );
+            })
+        };
+
+        rt.block_on(async {
+            client.await.unwrap();
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_zstd_levels_decode() {
+        let test_enum = TestEnum {
+            string: "hello ".repeat(512),
+            number: 3,
+            void: (),
+        };
+
+        for level in [
+            CompressionLevel::Default,
+            CompressionLevel::Fast(4),
+            CompressionLevel::High(19),
+        ] {
+            let (frame, decoded) = round_trip_frame(
+                test_enum.clone(),
+                CompressionSettings {
+                    algorithm: CompressionAlgorithm::Zstd,
+                    level,
+                    ..Default::default()
+                },
+            );
+
+            assert_eq!(frame[0], FRAME_ZSTD);
+            assert_eq!(decoded, test_enum);
+        }
+    }
+
+    #[test]
+    fn test_zstd_smaller_than_lz4() {
+        let payload = "the quick brown fox jumps over the lazy dog, ".repeat(64);
+        let payload = payload.as_bytes();
+
+        let mut buffer = BytesMut::new();
+        let lz4_frame = encode_frame(payload, CompressionSettings::default(), &mut buffer).unwrap();
+        let zstd_frame = encode_frame(
+            payload,
+            CompressionSettings {
+                algorithm: CompressionAlgorithm::Zstd,
+                ..Default::default()
+            },
+            &mut buffer,
+        )
+        .unwrap();
+
+        assert!(zstd_frame.len() <= lz4_frame.len());
+        assert_eq!(decode_frame(&zstd_frame).unwrap(), payload);
+    }
 }
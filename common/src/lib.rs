@@ -1,52 +1,308 @@
-use std::{fmt::Display, net::SocketAddr};
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+};
 
 use bytes::Bytes;
-use futures::stream::{SplitSink, SplitStream};
+use futures::{Sink, Stream};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::secure::SecureStream;
+use crate::secure::SecureStreamError;
 
 pub mod secure;
 pub mod codec;
+pub mod rate;
+pub mod stream;
 
-pub type ReadStream = SplitStream<SecureStream<TcpStream, Bytes>>;
-pub type WriteSink = SplitSink<SecureStream<TcpStream, Bytes>, Bytes>;
+/// A connection messages can be carried over, e.g. a TCP or Unix stream.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+/// Receiving half of a framed connection, secured by [`secure::SecureStream`] or by the
+/// transport itself.
+pub type ReadStream = Pin<Box<dyn Stream<Item = Result<Bytes, SecureStreamError>> + Send>>;
+/// Sending half of a framed connection.
+pub type WriteSink = Pin<Box<dyn Sink<Bytes, Error = SecureStreamError> + Send>>;
+
+/// Version of the message enums, bumped on incompatible changes.
+pub const PROTOCOL_VERSION: u32 = 10;
+
+/// Channel clients are placed in when they don't ask for one.
+pub const DEFAULT_CHANNEL: &str = "general";
 
 /// Message coming from the client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
     JoinRequest {
         name: String,
+        /// Channel to start in, [`DEFAULT_CHANNEL`] if `None`.
+        #[serde(default)]
+        channel: Option<String>,
+        /// [`PROTOCOL_VERSION`] of the client, missing from clients predating it.
+        #[serde(default)]
+        protocol_version: u32,
+        /// Makes the client an admin if it matches the server's admin token.
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    /// Leave the current channel and move to another.
+    JoinChannel {
+        channel: String,
     },
     /// Ask the server to broadcast a message for you.
     SendMessage {
+        /// Echoed back in [`ServerMessage::ReceiveMessage`] so the sender can match it.
+        id: MessageId,
         message: String,
+        /// Message this one replies to.
+        #[serde(default)]
+        reply_to: Option<MessageId>,
+    },
+    /// Replace the content of a message you sent.
+    EditMessage {
+        id: MessageId,
+        new_content: String,
+    },
+    /// Remove a message you sent.
+    DeleteMessage {
+        id: MessageId,
+    },
+    /// Leaving, sent before closing the connection.
+    Leave,
+    /// Reattach to a session that dropped, instead of [`ClientMessage::JoinRequest`].
+    Resume {
+        token: ResumeToken,
+    },
+    /// Moderation, only accepted from admins.
+    Admin(AdminCommand),
+    /// Send `message` only to the clients called `to`, whatever channel they're in.
+    PrivateMessage {
+        to: String,
+        message: String,
+    },
+    /// Ask for [`ServerMessage::WhoisResult`] about every client called `name`.
+    Whois {
+        name: String,
+    },
+}
+
+/// Commands for admins, they apply to every client with the given name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    /// Disconnect the client.
+    Kick {
+        name: String,
+    },
+    /// Disconnect the client and refuse its name and address from now on.
+    Ban {
+        name: String,
     },
+    /// Ask for [`ServerMessage::Stats`].
+    Stats,
 }
 
 /// Message coming from the server.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ServerMessage {
-    AcceptJoin,
+    /// The join request was accepted, `id` is how the server identifies us.
+    AcceptJoin {
+        id: ClientId,
+        /// Lets a dropped connection reattach with [`ClientMessage::Resume`], `None` if the
+        /// server doesn't allow resuming.
+        #[serde(default)]
+        resume_token: Option<ResumeToken>,
+        limits: ServerLimits,
+    },
+    /// The resume token is unknown or its grace window lapsed, a normal join is still possible.
+    ResumeRejected,
+    /// The join request was refused, the connection is closed afterwards.
+    JoinRejected {
+        reason: String,
+    },
+    /// Sent after joining when the server has a name or message of the day configured.
+    Welcome {
+        server_name: String,
+        motd: String,
+    },
+    /// The client is now in `channel`, messages and client lists are scoped to it.
+    ChannelJoined {
+        channel: String,
+    },
+    /// Clients in the current channel.
     ClientListUpdate {
-        clients: Vec<ClientId>,
+        clients: Vec<Presence>,
+    },
+    /// A message we sent with [`ClientMessage::SendMessage`] was broadcast.
+    Ack {
+        id: MessageId,
+    },
+    /// `sender` changed the content of their message `id`.
+    MessageEdited {
+        id: MessageId,
+        sender: ClientId,
+        content: String,
+    },
+    /// `sender` removed their message `id`.
+    MessageDeleted {
+        id: MessageId,
+        sender: ClientId,
+    },
+    /// Message `id` was refused and not broadcast.
+    MessageRejected {
+        id: MessageId,
+        reason: String,
+    },
+    /// An edit or delete of message `id` was refused.
+    EditRejected {
+        id: MessageId,
+        reason: String,
+    },
+    /// An admin removed us, the connection is closed afterwards.
+    Kicked {
+        reason: String,
+    },
+    /// The server is closing the connection for `reason`, e.g. an idle timeout.
+    Error {
+        reason: String,
+    },
+    /// Answer to [`AdminCommand::Stats`].
+    Stats {
+        client_count: u64,
+        uptime_secs: u64,
+        /// Messages broadcast since the server started.
+        total_messages: u64,
+    },
+    /// An [`AdminCommand`] was refused.
+    AdminRejected {
+        reason: String,
+    },
+    /// A [`ClientMessage::PrivateMessage`], sent to its recipients and echoed to its sender.
+    PrivateMessage {
+        sender: ClientId,
+        to: String,
+        message: String,
+    },
+    /// A [`ClientMessage::PrivateMessage`] had nobody to go to.
+    PrivateMessageRejected {
+        to: String,
+        reason: String,
+    },
+    /// Details of a client, answering [`ClientMessage::Whois`].
+    WhoisResult {
+        id: ClientId,
+        /// Unix time in seconds the client joined at.
+        joined_at: u64,
+        /// Sent nothing for long enough to count as idle.
+        away: bool,
+        /// Seconds since the client last sent anything.
+        last_active: u64,
+    },
+    /// A [`ClientMessage::Whois`] found nobody.
+    WhoisRejected {
+        name: String,
+        reason: String,
     },
     /// Client receives a messsage.
     ReceiveMessage {
+        id: MessageId,
         sender: ClientId,
         message: String,
+        #[serde(default)]
+        reply_to: Option<MessageId>,
     },
+    /// Several messages sent within the server's `batch_window_ms`, to be handled in order.
+    Batch(Vec<ServerMessage>),
 }
 
 #[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct ClientId {
     pub name: String,
-    pub addr: SocketAddr,
+    pub addr: PeerAddr,
 }
 
 impl Display for ClientId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}@{}", self.name, self.addr)
     }
-}
\ No newline at end of file
+}
+
+/// What the server takes from clients, sent in [`ServerMessage::AcceptJoin`] so drafts can be
+/// checked before sending.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ServerLimits {
+    /// Longest message, edit or private message, in characters.
+    pub max_message_length: usize,
+    /// Messages and private messages a client may send per second, 0 for no limit.
+    pub messages_per_second: u32,
+    /// Most clients joined at once.
+    pub max_clients: usize,
+}
+
+/// A client in a [`ServerMessage::ClientListUpdate`].
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub id: ClientId,
+    /// Seconds since the client last sent anything, as of the update.
+    pub last_active: u64,
+}
+
+/// Where a peer connected from.
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Peers on a Unix socket are unnamed, so they're told apart by connection number.
+    Unix(u64),
+    /// In-memory connections, e.g. [`tokio::io::duplex`] pipes in tests.
+    Virtual(u64),
+}
+
+impl PeerAddr {
+    /// IP address of TCP peers, `None` for the others.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Tcp(addr) => Some(addr.ip()),
+            Self::Unix(_) | Self::Virtual(_) => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(connection) => write!(f, "unix#{}", connection),
+            Self::Virtual(connection) => write!(f, "virtual#{}", connection),
+        }
+    }
+}
+
+/// Identifies a message, generated by the client that sends it.
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageId(pub u64);
+
+impl MessageId {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+/// Single-use secret a client presents to resume its session after a drop.
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ResumeToken(pub [u8; 16]);
+
+impl ResumeToken {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Instant;
+
+/// Token bucket allowing `per_second` events a second on average, in bursts of up to a second's
+/// worth. A rate of 0 allows everything.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_second: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second,
+            tokens: f64::from(per_second),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is left, returns whether the event is allowed.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        if self.per_second == 0 {
+            return true;
+        }
+        let rate = f64::from(self.per_second);
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::rate::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        limiter.last_refill = start;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+        // Half a second refills one token.
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+        // A long pause refills no more than a second's worth.
+        let much_later = later + Duration::from_secs(60);
+        assert!(limiter.try_acquire_at(much_later));
+        assert!(limiter.try_acquire_at(much_later));
+        assert!(!limiter.try_acquire_at(much_later));
+    }
+
+    #[test]
+    fn test_zero_rate_is_unlimited() {
+        let mut limiter = RateLimiter::new(0);
+        assert!((0..1000).all(|_| limiter.try_acquire()));
+    }
+}
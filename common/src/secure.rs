@@ -1,118 +1,429 @@
-use std::{io, marker::PhantomData, task::Poll};
+use std::{io, task::Poll};
 
-use crate::codec::CompressedCborStream;
+use crate::codec::{CompressedCborStream, CompressionSettings};
 use aes_gcm::{
     Aes256Gcm, KeyInit, Nonce,
     aead::{Aead, OsRng, Payload},
 };
+use bytes::Bytes;
 use futures::{Sink, SinkExt, Stream, StreamExt};
 use hkdf::Hkdf;
-use p521::{PublicKey, ecdh::EphemeralSecret};
+use p521::{
+    PublicKey, SecretKey,
+    ecdh::EphemeralSecret,
+    ecdsa::{
+        Signature, SigningKey, VerifyingKey,
+        signature::{Signer, Verifier},
+    },
+    elliptic_curve::pkcs8::LineEnding,
+};
+use pin_project_lite::pin_project;
 use rand::TryRngCore;
-use serde::{Deserialize, Serialize, de::DeserializeOwned};
-use sha2::Sha512;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+/// Version of the handshake and framing, both sides must agree on it.
+pub const HANDSHAKE_VERSION: u32 = 4;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
-    Handshake { public_key: PublicKey },
-    Encrypted { data: Vec<u8>, nonce: [u8; 12] },
+    Handshake {
+        public_key: PublicKey,
+        /// Missing from peers predating versioning, which read as version 0.
+        #[serde(default)]
+        version: u32,
+        /// Sent by servers with an identity key, missing from clients and older servers.
+        #[serde(default)]
+        identity: Option<Box<SignedIdentity>>,
+        /// Whether this side would go without encryption, see [`Plaintext`].
+        #[serde(default)]
+        plaintext: bool,
+    },
+    /// Payloads are CBOR byte strings rather than arrays of integers, so they're embedded as-is.
+    Encrypted { data: Bytes, nonce: [u8; 12] },
+    /// A frame of a connection both sides agreed to leave unencrypted.
+    Plain { data: Bytes },
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum SecureStreamError {
     #[error("Expected handshake, received: {:?}", message_received)]
     ExpectedHandshake { message_received: Message },
+    #[error("Protocol version mismatch: we speak {local}, the peer speaks {remote}.")]
+    VersionMismatch { local: u32, remote: u32 },
     #[error("Already handshaked, received: {:?}", handshake_message)]
     AlreadyHandshaked { handshake_message: Message },
     #[error("Failed to decrypt message.")]
     FailedDecryption { bytes: Vec<u8> },
     #[error("Failed to encrypt message.")]
     FailedEncryption { bytes: Vec<u8> },
+    #[error(
+        "The server's key {presented} doesn't match the pinned {pinned}, the connection may be intercepted."
+    )]
+    KeyMismatch { pinned: String, presented: String },
+    #[error("The server has no identity key to check against the pinned one.")]
+    MissingIdentity,
+    #[error("The server's handshake signature is invalid, the connection may be intercepted.")]
+    IdentityVerificationFailed,
+    #[error("The peer doesn't allow connections without encryption.")]
+    PlaintextRefused,
+    #[error("Received a frame not matching the connection's encryption.")]
+    EncryptionMismatch,
     #[error(transparent)]
     Io(#[from] io::Error),
 }
 
-pub struct SecureStream<S, Item>
+/// Key exchange, key derivation and cipher used by every [`SecureStream`].
+pub const CIPHER_SUITE: &str = "ECDH P-521, HKDF-SHA512, AES-256-GCM";
+
+/// Fingerprint of a peer's key as shown for pinning, the hex SHA-256 of its SEC1 encoding.
+pub fn fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key.to_sec1_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `pin` is `fingerprint`, ignoring case and `:` separators.
+pub fn pin_matches(pin: &str, fingerprint: &str) -> bool {
+    let pin = pin
+        .chars()
+        .filter(|&c| c != ':')
+        .map(|c| c.to_ascii_lowercase());
+    pin.eq(fingerprint.chars())
+}
+
+/// Whether secrets `a` and `b` are equal, taking as long wherever they differ.
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    // Comparing digests keeps the time independent of the lengths too.
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    let mut diff = 0;
+    for (a, b) in a.iter().zip(&b) {
+        diff |= a ^ b;
+    }
+    diff == 0
+}
+
+/// A server's long-lived key, it signs every handshake so clients can pin the server by its
+/// [`fingerprint`].
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+    public_key: PublicKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_secret(SecretKey::random(&mut OsRng))
+    }
+
+    fn from_secret(secret: SecretKey) -> Self {
+        let public_key = secret.public_key();
+        let key =
+            SigningKey::from_bytes(&secret.to_bytes()).expect("secret keys are valid scalars");
+        Self { key, public_key }
+    }
+
+    /// Reads a key written by [`Identity::to_pem`], `None` if it isn't one.
+    pub fn from_pem(pem: &str) -> Option<Self> {
+        SecretKey::from_sec1_pem(pem).ok().map(Self::from_secret)
+    }
+
+    /// The secret key as SEC1 PEM.
+    pub fn to_pem(&self) -> String {
+        let secret = SecretKey::from_bytes(&self.key.to_bytes()).expect("signing keys are valid");
+        secret
+            .to_sec1_pem(LineEnding::LF)
+            .expect("valid keys encode")
+            .to_string()
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    fn sign(&self, transcript: &[u8]) -> SignedIdentity {
+        let signature: Signature = self.key.sign(transcript);
+        SignedIdentity {
+            public_key: self.public_key,
+            signature: signature.to_vec(),
+        }
+    }
+}
+
+/// A server's identity key with its signature of the handshake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedIdentity {
+    pub public_key: PublicKey,
+    pub signature: Vec<u8>,
+}
+
+impl SignedIdentity {
+    fn verify(&self, transcript: &[u8]) -> bool {
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        VerifyingKey::from_sec1_bytes(&self.public_key.to_sec1_bytes())
+            .is_ok_and(|key| key.verify(transcript, &signature).is_ok())
+    }
+}
+
+/// Whether a side goes without encryption, to inspect traffic while debugging. A connection is
+/// only left unencrypted if both sides opt in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Plaintext {
+    #[default]
+    Refuse,
+    /// Go without encryption if the peer requires it, for servers.
+    Allow,
+    /// Fail unless the peer allows going without encryption, for clients.
+    Require,
+}
+
+/// What a side brings to the handshake beyond the key exchange. The default accepts any server,
+/// still checking its signature if it sends one.
+#[derive(Clone, Copy, Default)]
+pub struct HandshakeOptions<'a> {
+    /// Fingerprint the server's identity key must have, for clients.
+    pub pin: Option<&'a str>,
+    /// Key to sign the handshake with, for servers.
+    pub identity: Option<&'a Identity>,
+    pub plaintext: Plaintext,
+}
+
+/// The peer's half of the handshake.
+struct PeerHandshake {
+    public_key: PublicKey,
+    identity: Option<Box<SignedIdentity>>,
+    plaintext: bool,
+}
+
+/// What the server's identity signs, binding both sides' keys of this handshake and whether
+/// they would go without encryption, so neither can be swapped in transit.
+fn transcript(
+    version: u32,
+    client_key: &PublicKey,
+    client_plaintext: bool,
+    server_key: &PublicKey,
+    server_plaintext: bool,
+) -> Vec<u8> {
+    let mut transcript = format!("term-chat handshake v{}", version).into_bytes();
+    transcript.extend_from_slice(&client_key.to_sec1_bytes());
+    transcript.push(client_plaintext.into());
+    transcript.extend_from_slice(&server_key.to_sec1_bytes());
+    transcript.push(server_plaintext.into());
+    transcript
+}
+
+async fn receive_handshake<S>(
+    inner: &mut CompressedCborStream<S, Message>,
+    version: u32,
+) -> Result<PeerHandshake, SecureStreamError>
 where
     S: AsyncRead + AsyncWrite + Unpin,
-    Item: Serialize + DeserializeOwned,
 {
-    inner: CompressedCborStream<S, Message>,
-    aes: Aes256Gcm,
-    _phantom: PhantomData<Item>,
+    match inner.next().await {
+        Some(Ok(Message::Handshake {
+            version: remote, ..
+        })) if remote != version => Err(SecureStreamError::VersionMismatch {
+            local: version,
+            remote,
+        }),
+        Some(Ok(Message::Handshake {
+            public_key,
+            identity,
+            plaintext,
+            ..
+        })) => Ok(PeerHandshake {
+            public_key,
+            identity,
+            plaintext,
+        }),
+        Some(Ok(message)) => Err(SecureStreamError::ExpectedHandshake {
+            message_received: message,
+        }),
+        Some(Err(err)) => Err(err.into()),
+        None => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Closed before sending handshake back.",
+        )
+        .into()),
+    }
 }
 
-impl<S, Item> SecureStream<S, Item>
+pin_project! {
+    /// Encrypted stream of payloads, each already serialised by the caller so messages are only
+    /// encoded once, see [`crate::stream::split_message_stream`].
+    pub struct SecureStream<S> {
+        #[pin]
+        inner: CompressedCborStream<S, Message>,
+        // `None` if both sides agreed to go without encryption.
+        aes: Option<Aes256Gcm>,
+    }
+}
+
+impl<S> SecureStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
-    Item: Serialize + DeserializeOwned,
 {
     pub async fn handshake(inner: S) -> Result<Self, SecureStreamError> {
-        let mut inner = CompressedCborStream::new(inner);
+        Self::handshake_with_compression(inner, CompressionSettings::default()).await
+    }
+
+    /// Like [`SecureStream::handshake`], using `compression` for frames sent by this side.
+    pub async fn handshake_with_compression(
+        inner: S,
+        compression: CompressionSettings,
+    ) -> Result<Self, SecureStreamError> {
+        Self::handshake_with_options(inner, compression, HandshakeOptions::default()).await
+    }
+
+    /// Like [`SecureStream::handshake_with_compression`] with `options` to pin or sign the
+    /// handshake or go without encryption.
+    pub async fn handshake_with_options(
+        inner: S,
+        compression: CompressionSettings,
+        options: HandshakeOptions<'_>,
+    ) -> Result<Self, SecureStreamError> {
+        Self::handshake_with_version(inner, compression, HANDSHAKE_VERSION, options).await
+    }
+
+    async fn handshake_with_version(
+        inner: S,
+        compression: CompressionSettings,
+        version: u32,
+        options: HandshakeOptions<'_>,
+    ) -> Result<Self, SecureStreamError> {
         let secret = EphemeralSecret::random(&mut OsRng);
+        Self::handshake_with_secret(inner, compression, version, secret, options).await
+    }
 
-        inner
-            .send(Message::Handshake {
-                public_key: secret.public_key(),
-            })
-            .await?;
+    async fn handshake_with_secret(
+        inner: S,
+        compression: CompressionSettings,
+        version: u32,
+        secret: EphemeralSecret,
+        options: HandshakeOptions<'_>,
+    ) -> Result<Self, SecureStreamError> {
+        let mut inner = CompressedCborStream::with_compression(inner, compression);
+        let offers_plaintext = options.plaintext != Plaintext::Refuse;
+        let handshake = |identity| Message::Handshake {
+            public_key: secret.public_key(),
+            version,
+            identity,
+            plaintext: offers_plaintext,
+        };
 
-        let shared_secret = match inner.next().await {
-            Some(Ok(Message::Handshake { public_key })) => secret.diffie_hellman(&public_key),
-            Some(Ok(message)) => {
-                return Err(SecureStreamError::ExpectedHandshake {
-                    message_received: message,
-                });
+        let peer = match options.identity {
+            Some(identity) => {
+                // The signature covers the client's key, so it has to arrive first.
+                let received = receive_handshake(&mut inner, version).await;
+                if let Err(SecureStreamError::VersionMismatch { .. }) = received {
+                    // Answer anyway so the client learns of the mismatch too.
+                    inner.send(handshake(None)).await?;
+                }
+                let peer = received?;
+                let transcript = transcript(
+                    version,
+                    &peer.public_key,
+                    peer.plaintext,
+                    &secret.public_key(),
+                    offers_plaintext,
+                );
+                let signed = identity.sign(&transcript);
+                inner.send(handshake(Some(Box::new(signed)))).await?;
+                PeerHandshake {
+                    identity: None,
+                    ..peer
+                }
             }
-            Some(Err(err)) => return Err(err.into()),
             None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Closed before sending handshake back.",
-                )
-                .into());
+                inner.send(handshake(None)).await?;
+                receive_handshake(&mut inner, version).await?
             }
         };
 
+        if let Some(signed) = &peer.identity {
+            let transcript = transcript(
+                version,
+                &secret.public_key(),
+                offers_plaintext,
+                &peer.public_key,
+                peer.plaintext,
+            );
+            if !signed.verify(&transcript) {
+                return Err(SecureStreamError::IdentityVerificationFailed);
+            }
+        }
+        if let Some(pin) = options.pin {
+            let Some(signed) = &peer.identity else {
+                return Err(SecureStreamError::MissingIdentity);
+            };
+            let presented = fingerprint(&signed.public_key);
+            if !pin_matches(pin, &presented) {
+                return Err(SecureStreamError::KeyMismatch {
+                    pinned: pin.to_owned(),
+                    presented,
+                });
+            }
+        }
+
+        let plaintext = offers_plaintext && peer.plaintext;
+        if plaintext {
+            return Ok(Self { inner, aes: None });
+        }
+        if options.plaintext == Plaintext::Require {
+            return Err(SecureStreamError::PlaintextRefused);
+        }
+
+        let shared_secret = secret.diffie_hellman(&peer.public_key);
+
         let hk = Hkdf::<Sha512>::new(None, shared_secret.raw_secret_bytes());
         let mut key_bytes = [0u8; 32];
-        hk.expand(b"handshake context", &mut key_bytes).unwrap();
+        let context = format!("handshake context v{}", version);
+        hk.expand(context.as_bytes(), &mut key_bytes).unwrap();
 
         let aes = Aes256Gcm::new_from_slice(&key_bytes).unwrap();
 
         Ok(Self {
             inner,
-            aes,
-            _phantom: PhantomData,
+            aes: Some(aes),
         })
     }
+
+    /// Whether frames are encrypted, `false` only if both sides agreed to go without.
+    pub fn is_encrypted(&self) -> bool {
+        self.aes.is_some()
+    }
 }
 
-impl<S, Item> Stream for SecureStream<S, Item>
+impl<S> Stream for SecureStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
-    Item: Serialize + DeserializeOwned,
 {
-    type Item = Result<Item, SecureStreamError>;
+    type Item = Result<Bytes, SecureStreamError>;
     fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }.poll_next(cx) {
+        let this = self.project();
+        match this.inner.poll_next(cx) {
             Poll::Ready(Some(msg)) => {
                 let msg = msg?;
-                match msg {
-                    Message::Handshake { .. } => {
-                        return Poll::Ready(Some(Err(io::Error::other(
-                            SecureStreamError::AlreadyHandshaked {
-                                handshake_message: msg,
-                            },
-                        )
-                        .into())));
-                    }
-                    Message::Encrypted { data, nonce } => {
-                        let Ok(message) = self.aes.decrypt(
+                match (msg, &*this.aes) {
+                    (msg @ Message::Handshake { .. }, _) => Poll::Ready(Some(Err(
+                        io::Error::other(SecureStreamError::AlreadyHandshaked {
+                            handshake_message: msg,
+                        })
+                        .into(),
+                    ))),
+                    (Message::Encrypted { data, nonce }, Some(aes)) => {
+                        let Ok(message) = aes.decrypt(
                             Nonce::from_slice(&nonce),
                             Payload {
                                 msg: &data,
@@ -120,13 +431,15 @@ where
                             },
                         ) else {
                             return Poll::Ready(Some(Err(SecureStreamError::FailedDecryption {
-                                bytes: data,
+                                bytes: data.to_vec(),
                             })));
                         };
-                        let item = serde_cbor::de::from_slice(&message)
-                            .map_err(|err| std::io::Error::new(io::ErrorKind::InvalidData, err))?;
-
-                        return Poll::Ready(Some(Ok(item)));
+                        Poll::Ready(Some(Ok(Bytes::from(message))))
+                    }
+                    (Message::Plain { data }, None) => Poll::Ready(Some(Ok(data))),
+                    // Never accept plain frames once encryption was agreed, nor the reverse.
+                    (Message::Encrypted { .. } | Message::Plain { .. }, _) => {
+                        Poll::Ready(Some(Err(SecureStreamError::EncryptionMismatch)))
                     }
                 }
             }
@@ -136,70 +449,77 @@ where
     }
 }
 
-impl<S, Item> Sink<Item> for SecureStream<S, Item>
+impl<S> Sink<Bytes> for SecureStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
-    Item: Serialize + DeserializeOwned,
 {
     type Error = SecureStreamError;
     fn poll_ready(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .poll_ready(cx)
-            .map_err(Into::into)
+        self.project().inner.poll_ready(cx).map_err(Into::into)
     }
     fn poll_close(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .poll_close(cx)
-            .map_err(Into::into)
+        self.project().inner.poll_close(cx).map_err(Into::into)
     }
     fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
+        self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .poll_flush(cx)
-            .map_err(Into::into)
+        self.project().inner.poll_flush(cx).map_err(Into::into)
     }
-    fn start_send(mut self: std::pin::Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
-        let bytes = serde_cbor::ser::to_vec(&item).map_err(std::io::Error::other)?;
+    fn start_send(self: std::pin::Pin<&mut Self>, payload: Bytes) -> Result<(), Self::Error> {
+        let this = self.project();
+        let Some(aes) = this.aes else {
+            return this
+                .inner
+                .start_send(Message::Plain { data: payload })
+                .map_err(Into::into);
+        };
 
         let mut nonce = [0u8; 12];
         rand::rngs::OsRng.try_fill_bytes(&mut nonce).unwrap();
 
-        let encrypted_bytes = self
-            .aes
+        let encrypted_bytes = aes
             .encrypt(
                 Nonce::from_slice(&nonce),
                 Payload {
-                    msg: &bytes,
+                    msg: &payload,
                     aad: b"",
                 },
             )
-            .map_err(|_| SecureStreamError::FailedEncryption { bytes })?;
+            .map_err(|_| SecureStreamError::FailedEncryption {
+                bytes: payload.to_vec(),
+            })?;
 
         let message = Message::Encrypted {
             nonce,
-            data: encrypted_bytes,
+            data: Bytes::from(encrypted_bytes),
         };
 
-        unsafe { self.as_mut().map_unchecked_mut(|this| &mut this.inner) }
-            .start_send(message)
-            .map_err(Into::into)
+        this.inner.start_send(message).map_err(Into::into)
     }
 }
 
 #[cfg(test)]
 mod test {
+    use aes_gcm::aead::OsRng;
+    use bytes::Bytes;
     use futures::{SinkExt, StreamExt};
+    use p521::ecdh::EphemeralSecret;
     use serde::{Deserialize, Serialize};
 
-    use crate::secure::SecureStream;
+    use crate::{
+        codec::{CompressedCborStream, CompressionSettings},
+        secure::{
+            HANDSHAKE_VERSION, HandshakeOptions, Identity, Message, Plaintext, SecureStream,
+            SecureStreamError, pin_matches, secrets_match, transcript,
+        },
+    };
 
     #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
     struct TestStruct {
@@ -215,17 +535,23 @@ mod test {
             .build()
             .unwrap();
 
-        let test_enum = TestStruct {
-            string: "Bro".to_owned(),
-            number: 69,
-            void: (),
-        };
+        let test_enum = Bytes::from(
+            serde_cbor::to_vec(&TestStruct {
+                string: "Bro".to_owned(),
+                number: 69,
+                void: (),
+            })
+            .unwrap(),
+        );
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
         let client = {
             let test_enum = test_enum.clone();
             rt.spawn(async move {
-                let stream = tokio::net::TcpStream::connect("localhost:1234")
-                    .await
-                    .unwrap();
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
 
                 let stream = SecureStream::handshake(stream).await.unwrap();
 
@@ -243,10 +569,6 @@ mod test {
         let server = {
             rt.spawn(async move {
                 let test_enum = test_enum.clone();
-                let listener = tokio::net::TcpListener::bind("localhost:1234")
-                    .await
-                    .unwrap();
-
                 let (stream, _) = listener.accept().await.unwrap();
 
                 let stream = SecureStream::handshake(stream).await.unwrap();
@@ -267,4 +589,331 @@ mod test {
             server.await.unwrap();
         });
     }
+
+    #[test]
+    fn test_version_mismatch() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = rt.spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            SecureStream::handshake(stream).await
+        });
+
+        let server = rt.spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            SecureStream::handshake_with_version(
+                stream,
+                CompressionSettings::default(),
+                HANDSHAKE_VERSION + 1,
+                HandshakeOptions::default(),
+            )
+            .await
+        });
+
+        rt.block_on(async {
+            match client.await.unwrap() {
+                Err(SecureStreamError::VersionMismatch { local, remote }) => {
+                    assert_eq!((local, remote), (HANDSHAKE_VERSION, HANDSHAKE_VERSION + 1))
+                }
+                _ => panic!("expected a version mismatch"),
+            }
+            match server.await.unwrap() {
+                Err(SecureStreamError::VersionMismatch { local, remote }) => {
+                    assert_eq!((local, remote), (HANDSHAKE_VERSION + 1, HANDSHAKE_VERSION))
+                }
+                _ => panic!("expected a version mismatch"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_pin_matches() {
+        assert!(pin_matches("ab:CD:ef", "abcdef"));
+        assert!(!pin_matches("abcd", "abcdef"));
+        assert!(!pin_matches("abcdef00", "abcdef"));
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("secret", "secret"));
+        assert!(!secrets_match("secret", "secreT"));
+        assert!(!secrets_match("secret", "secret "));
+        assert!(!secrets_match("", "secret"));
+    }
+
+    #[test]
+    fn test_pinned_identity() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        // Connects pinning `pin` to a server signing with `identity`, if any.
+        let handshake = |identity: Option<Identity>, pin: Option<String>| async move {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(async move {
+                let options = HandshakeOptions {
+                    identity: identity.as_ref(),
+                    ..Default::default()
+                };
+                SecureStream::handshake_with_options(
+                    server,
+                    CompressionSettings::default(),
+                    options,
+                )
+                .await
+                .map(drop)
+            });
+            let options = HandshakeOptions {
+                pin: pin.as_deref(),
+                ..Default::default()
+            };
+            let client = SecureStream::handshake_with_options(
+                client,
+                CompressionSettings::default(),
+                options,
+            )
+            .await;
+            server.abort();
+            client
+        };
+
+        rt.block_on(async {
+            let identity = Identity::generate();
+            let pin = identity.fingerprint().to_uppercase();
+            assert!(handshake(Some(identity.clone()), Some(pin)).await.is_ok());
+            assert!(handshake(Some(identity), None).await.is_ok());
+            assert!(handshake(None, None).await.is_ok());
+
+            let pin = Identity::generate().fingerprint();
+            assert!(matches!(
+                handshake(Some(Identity::generate()), Some(pin.clone())).await,
+                Err(SecureStreamError::KeyMismatch { .. })
+            ));
+            assert!(matches!(
+                handshake(None, Some(pin)).await,
+                Err(SecureStreamError::MissingIdentity)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_plaintext_needs_both_sides() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let test_struct = Bytes::from(
+            serde_cbor::to_vec(&TestStruct {
+                string: "plain".to_owned(),
+                number: 1,
+                void: (),
+            })
+            .unwrap(),
+        );
+        // Connects a client choosing `client` to a server choosing `server`.
+        let handshake = |client: Plaintext, server: Plaintext| async move {
+            let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(async move {
+                let options = HandshakeOptions {
+                    plaintext: server,
+                    ..Default::default()
+                };
+                SecureStream::handshake_with_options(
+                    server_end,
+                    CompressionSettings::default(),
+                    options,
+                )
+                .await
+            });
+            let options = HandshakeOptions {
+                plaintext: client,
+                ..Default::default()
+            };
+            let client = SecureStream::handshake_with_options(
+                client_end,
+                CompressionSettings::default(),
+                options,
+            )
+            .await;
+            (client, server.await.unwrap())
+        };
+
+        rt.block_on(async {
+            let (client, server) = handshake(Plaintext::Require, Plaintext::Allow).await;
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            assert!(!client.is_encrypted() && !server.is_encrypted());
+            client.send(test_struct.clone()).await.unwrap();
+            assert_eq!(server.next().await.unwrap().unwrap(), test_struct);
+
+            let (client, server) = handshake(Plaintext::Refuse, Plaintext::Allow).await;
+            assert!(client.unwrap().is_encrypted() && server.unwrap().is_encrypted());
+
+            let (client, server) = handshake(Plaintext::Require, Plaintext::Refuse).await;
+            assert!(matches!(client, Err(SecureStreamError::PlaintextRefused)));
+            assert!(server.unwrap().is_encrypted());
+        });
+    }
+
+    #[test]
+    fn test_payload_is_embedded_as_is() {
+        let test_struct = TestStruct {
+            string: "once".to_owned(),
+            number: 3,
+            void: (),
+        };
+        let payload = serde_cbor::to_vec(&test_struct).unwrap();
+        let envelope = serde_cbor::to_vec(&Message::Plain {
+            data: Bytes::from(payload.clone()),
+        })
+        .unwrap();
+
+        // The payload shows up verbatim, so a single decode of it recovers the message.
+        let start = envelope
+            .windows(payload.len())
+            .position(|window| window == payload)
+            .expect("payload should be a byte string in the envelope");
+        let decoded: TestStruct =
+            serde_cbor::from_slice(&envelope[start..start + payload.len()]).unwrap();
+        assert_eq!(decoded, test_struct);
+    }
+
+    #[test]
+    fn test_plain_frames_are_rejected_when_encrypted() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(SecureStream::handshake(server));
+            let mut client =
+                CompressedCborStream::<_, Message>::with_compression(client, Default::default());
+            let Some(Ok(Message::Handshake { public_key, .. })) = client.next().await else {
+                panic!("expected the server's handshake");
+            };
+            // Take part in the key exchange, then try to slip in a plain frame.
+            client
+                .send(Message::Handshake {
+                    public_key,
+                    version: HANDSHAKE_VERSION,
+                    identity: None,
+                    plaintext: true,
+                })
+                .await
+                .unwrap();
+            let mut server = server.await.unwrap().unwrap();
+            assert!(server.is_encrypted());
+            let data = Bytes::from(
+                serde_cbor::to_vec(&TestStruct {
+                    string: "sneaky".to_owned(),
+                    number: 2,
+                    void: (),
+                })
+                .unwrap(),
+            );
+            client.send(Message::Plain { data }).await.unwrap();
+            assert!(matches!(
+                server.next().await,
+                Some(Err(SecureStreamError::EncryptionMismatch))
+            ));
+        });
+    }
+
+    #[test]
+    fn test_substituted_key_fails_verification() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            // A man in the middle replaying the server's signature with its own key.
+            let server = tokio::spawn(async move {
+                let mut server = CompressedCborStream::<_, Message>::with_compression(
+                    server,
+                    CompressionSettings::default(),
+                );
+                let Some(Ok(Message::Handshake {
+                    public_key: client_key,
+                    ..
+                })) = server.next().await
+                else {
+                    panic!("expected the client's handshake");
+                };
+                let identity = Identity::generate();
+                let server_key = EphemeralSecret::random(&mut OsRng).public_key();
+                let signed = identity.sign(&transcript(
+                    HANDSHAKE_VERSION,
+                    &client_key,
+                    false,
+                    &server_key,
+                    false,
+                ));
+                let public_key = EphemeralSecret::random(&mut OsRng).public_key();
+                server
+                    .send(Message::Handshake {
+                        public_key,
+                        version: HANDSHAKE_VERSION,
+                        identity: Some(Box::new(signed)),
+                        plaintext: false,
+                    })
+                    .await
+                    .unwrap();
+                server
+            });
+
+            let client = SecureStream::handshake(client).await;
+            assert!(matches!(
+                client,
+                Err(SecureStreamError::IdentityVerificationFailed)
+            ));
+            drop(server.await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_signature_covers_handshake() {
+        let identity = Identity::generate();
+        let client = EphemeralSecret::random(&mut OsRng).public_key();
+        let server = EphemeralSecret::random(&mut OsRng).public_key();
+        let transcript = |client, client_plaintext, server, server_plaintext| {
+            transcript(
+                HANDSHAKE_VERSION,
+                client,
+                client_plaintext,
+                server,
+                server_plaintext,
+            )
+        };
+        let signed = identity.sign(&transcript(&client, false, &server, false));
+        assert!(signed.verify(&transcript(&client, false, &server, false)));
+
+        // A signature replayed into a handshake with other keys doesn't verify.
+        let other = EphemeralSecret::random(&mut OsRng).public_key();
+        assert!(!signed.verify(&transcript(&other, false, &server, false)));
+        assert!(!signed.verify(&transcript(&client, false, &other, false)));
+        // Nor does one whose plaintext offers were flipped in transit.
+        assert!(!signed.verify(&transcript(&client, true, &server, false)));
+        assert!(!signed.verify(&transcript(&client, false, &server, true)));
+    }
+
+    #[test]
+    fn test_identity_pem_round_trip() {
+        let identity = Identity::generate();
+        let loaded = Identity::from_pem(&identity.to_pem()).unwrap();
+        assert_eq!(loaded.fingerprint(), identity.fingerprint());
+        assert!(Identity::from_pem("not a key").is_none());
+    }
 }
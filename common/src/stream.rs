@@ -0,0 +1,503 @@
+use std::{
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{
+    ClientMessage, ReadStream, ServerMessage, Transport, WriteSink,
+    codec::{CompressionSettings, decode_frame, encode_frame},
+    secure::{HandshakeOptions, SecureStream, SecureStreamError},
+};
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt, TryStreamExt, future};
+use serde::{Serialize, de::DeserializeOwned};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Sending half used by clients.
+pub type ClientSink = MessageSink<ClientMessage>;
+/// Receiving half used by clients.
+pub type ClientStream = MessageStream<ServerMessage>;
+/// Sending half used by the server for each client.
+pub type ServerSink = MessageSink<ServerMessage>;
+/// Receiving half used by the server for each client.
+pub type ServerStream = MessageStream<ClientMessage>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MessageError {
+    #[error(transparent)]
+    Transport(#[from] SecureStreamError),
+    #[error("Failed to serialise message: {0}")]
+    Encode(serde_cbor::Error),
+    #[error("Failed to deserialise message: {0}")]
+    Decode(serde_cbor::Error),
+}
+
+impl MessageError {
+    /// Whether the connection is still usable, i.e. only this message was bad.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::Transport(_))
+    }
+
+    /// Whether the peer went away, which is expected rather than an error worth reporting.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Self::Transport(SecureStreamError::Io(err)) => matches!(
+                err.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// A message serialised ahead of time, so it can be sent to many peers while encoding it once.
+#[derive(Debug, PartialEq, Eq)]
+pub struct EncodedMessage<T> {
+    bytes: Bytes,
+    _phantom: PhantomData<fn(T)>,
+}
+
+impl<T: Serialize> EncodedMessage<T> {
+    pub fn new(message: &T) -> Result<Self, serde_cbor::Error> {
+        let bytes = serde_cbor::to_vec(message)?;
+        Ok(Self {
+            bytes: Bytes::from(bytes),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Size of the serialised message in bytes.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+impl EncodedMessage<ServerMessage> {
+    /// Joins already encoded messages into a [`ServerMessage::Batch`] without decoding them.
+    pub fn batch(messages: &[Self]) -> Self {
+        let len = messages.iter().map(|message| message.len()).sum::<usize>();
+        let mut bytes = Vec::with_capacity(len + 16);
+        // serde_cbor writes a newtype variant as a map from its name to the value.
+        bytes.push(0xa1);
+        bytes.push(0x60 | b"Batch".len() as u8);
+        bytes.extend_from_slice(b"Batch");
+        match u32::try_from(messages.len()) {
+            Ok(count @ 0..=23) => bytes.push(0x80 | count as u8),
+            Ok(count @ 24..=0xff) => bytes.extend_from_slice(&[0x98, count as u8]),
+            Ok(count @ 0x100..=0xffff) => {
+                bytes.push(0x99);
+                bytes.extend_from_slice(&(count as u16).to_be_bytes());
+            }
+            Ok(count) => {
+                bytes.push(0x9a);
+                bytes.extend_from_slice(&count.to_be_bytes());
+            }
+            Err(_) => unreachable!("more than u32::MAX messages in a batch"),
+        }
+        for message in messages {
+            bytes.extend_from_slice(&message.bytes);
+        }
+        Self {
+            bytes: Bytes::from(bytes),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for EncodedMessage<T> {
+    fn clone(&self) -> Self {
+        Self {
+            bytes: self.bytes.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// Sending half of a connection, accepts `T` or an [`EncodedMessage<T>`].
+pub struct MessageSink<T> {
+    inner: WriteSink,
+    _phantom: PhantomData<fn(T)>,
+}
+
+/// Receiving half of a connection, yields `T`.
+pub struct MessageStream<T> {
+    inner: ReadStream,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+/// Performs the secure handshake on `stream` and splits it into typed halves.
+///
+/// `Out` is what this side sends and `In` what it receives, e.g. `ClientMessage` and
+/// `ServerMessage` on the client.
+pub async fn split_message_stream<Out, In>(
+    stream: impl Transport,
+    compression: CompressionSettings,
+) -> Result<(MessageSink<Out>, MessageStream<In>), SecureStreamError>
+where
+    Out: Serialize,
+    In: DeserializeOwned,
+{
+    let stream: Box<dyn Transport> = Box::new(stream);
+    let stream = SecureStream::handshake_with_compression(stream, compression).await?;
+    Ok(split_framed(stream))
+}
+
+/// Like [`split_message_stream`] with `options` to pin or sign the handshake or go without
+/// encryption.
+pub async fn split_message_stream_with<Out, In>(
+    stream: impl Transport,
+    compression: CompressionSettings,
+    options: HandshakeOptions<'_>,
+) -> Result<(MessageSink<Out>, MessageStream<In>), SecureStreamError>
+where
+    Out: Serialize,
+    In: DeserializeOwned,
+{
+    let stream: Box<dyn Transport> = Box::new(stream);
+    let stream = SecureStream::handshake_with_options(stream, compression, options).await?;
+    Ok(split_framed(stream))
+}
+
+/// Like [`split_message_stream`] without the secure handshake, for transports that are
+/// encrypted already such as TLS.
+pub fn split_plain_message_stream<Out, In>(
+    stream: impl Transport,
+    compression: CompressionSettings,
+) -> (MessageSink<Out>, MessageStream<In>)
+where
+    Out: Serialize,
+    In: DeserializeOwned,
+{
+    let stream: Box<dyn Transport> = Box::new(stream);
+    // Payloads are CBOR already, so only frame and compress them.
+    let stream = Framed::new(stream, LengthDelimitedCodec::new())
+        .with({
+            let mut buffer = BytesMut::new();
+            move |payload: Bytes| future::ready(encode_frame(&payload, compression, &mut buffer))
+        })
+        .sink_map_err(SecureStreamError::from)
+        .map(|frame| {
+            frame
+                .and_then(|frame| decode_frame(&frame))
+                .map(Bytes::from)
+        })
+        .map_err(SecureStreamError::from);
+    split_framed(stream)
+}
+
+/// Splits any secured, framed connection into typed halves, for transports beyond the ones
+/// above.
+pub fn split_framed<S, Out, In>(stream: S) -> (MessageSink<Out>, MessageStream<In>)
+where
+    S: Stream<Item = Result<Bytes, SecureStreamError>>
+        + Sink<Bytes, Error = SecureStreamError>
+        + Send
+        + 'static,
+{
+    let (inner_sink, inner_stream) = stream.split();
+    (
+        MessageSink {
+            inner: Box::pin(inner_sink),
+            _phantom: PhantomData,
+        },
+        MessageStream {
+            inner: Box::pin(inner_stream),
+            _phantom: PhantomData,
+        },
+    )
+}
+
+impl<T> MessageSink<T> {
+    /// Sends a message serialised with [`EncodedMessage::new`].
+    pub async fn send_encoded(&mut self, message: EncodedMessage<T>) -> Result<(), MessageError> {
+        Ok(self.inner.send(message.bytes).await?)
+    }
+
+    /// Like [`MessageSink::send_encoded`] without flushing, to write several messages at once.
+    pub async fn feed_encoded(&mut self, message: EncodedMessage<T>) -> Result<(), MessageError> {
+        Ok(self.inner.feed(message.bytes).await?)
+    }
+}
+
+impl<T: Serialize> Sink<T> for MessageSink<T> {
+    type Error = MessageError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready_unpin(cx).map_err(MessageError::from)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let message = EncodedMessage::new(&item).map_err(MessageError::Encode)?;
+        Ok(self.inner.start_send_unpin(message.bytes)?)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx).map_err(MessageError::from)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx).map_err(MessageError::from)
+    }
+}
+
+impl<T: DeserializeOwned> Stream for MessageStream<T> {
+    type Item = Result<T, MessageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let bytes = match self.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(bytes))) => bytes,
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err.into()))),
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(Some(
+            serde_cbor::from_slice(&bytes).map_err(MessageError::Decode),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    use crate::{
+        ClientMessage, ServerMessage,
+        codec::{CompressedCborStream, CompressionSettings, decode_frame},
+        secure::{HANDSHAKE_VERSION, HandshakeOptions, Message, Plaintext},
+        stream::{
+            EncodedMessage, MessageError, split_message_stream, split_message_stream_with,
+            split_plain_message_stream,
+        },
+    };
+
+    #[test]
+    fn test_typed_halves() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = rt.spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut send, mut recv) = split_message_stream::<ClientMessage, ServerMessage>(
+                stream,
+                CompressionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+            send.send(ClientMessage::JoinChannel {
+                channel: "general".to_owned(),
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(
+                recv.next().await.transpose().unwrap(),
+                Some(ServerMessage::ChannelJoined {
+                    channel: "general".to_owned()
+                })
+            );
+        });
+
+        let server = rt.spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut send, mut recv) = split_message_stream::<ServerMessage, ClientMessage>(
+                stream,
+                CompressionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+            let Some(Ok(ClientMessage::JoinChannel { channel })) = recv.next().await else {
+                panic!("expected a JoinChannel message");
+            };
+            let response = EncodedMessage::new(&ServerMessage::ChannelJoined { channel }).unwrap();
+            send.send_encoded(response).await.unwrap();
+        });
+
+        rt.block_on(async {
+            client.await.unwrap();
+            server.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_plain_halves() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (mut client_send, mut client_recv) =
+                split_plain_message_stream::<ClientMessage, ServerMessage>(
+                    client,
+                    CompressionSettings::default(),
+                );
+            let (mut server_send, mut server_recv) =
+                split_plain_message_stream::<ServerMessage, ClientMessage>(
+                    server,
+                    CompressionSettings::default(),
+                );
+
+            client_send
+                .send(ClientMessage::JoinChannel {
+                    channel: "general".to_owned(),
+                })
+                .await
+                .unwrap();
+            let Some(Ok(ClientMessage::JoinChannel { channel })) = server_recv.next().await else {
+                panic!("expected a JoinChannel message");
+            };
+            server_send
+                .send(ServerMessage::ChannelJoined { channel })
+                .await
+                .unwrap();
+            assert_eq!(
+                client_recv.next().await.transpose().unwrap(),
+                Some(ServerMessage::ChannelJoined {
+                    channel: "general".to_owned()
+                })
+            );
+        });
+    }
+
+    #[test]
+    fn test_payload_is_encoded_once() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let message = ClientMessage::JoinChannel {
+            channel: "general".to_owned(),
+        };
+        let cbor = serde_cbor::to_vec(&message).unwrap();
+
+        rt.block_on(async {
+            // Handshake without encryption so the peer can read the payload as sent.
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let server = tokio::spawn(async move {
+                let mut server = CompressedCborStream::<_, Message>::new(server);
+                let Some(Ok(Message::Handshake { public_key, .. })) = server.next().await else {
+                    panic!("expected the client's handshake");
+                };
+                server
+                    .send(Message::Handshake {
+                        public_key,
+                        version: HANDSHAKE_VERSION,
+                        identity: None,
+                        plaintext: true,
+                    })
+                    .await
+                    .unwrap();
+                server.next().await
+            });
+            let options = HandshakeOptions {
+                plaintext: Plaintext::Require,
+                ..Default::default()
+            };
+            let (mut send, _recv) = split_message_stream_with::<ClientMessage, ServerMessage>(
+                client,
+                CompressionSettings::default(),
+                options,
+            )
+            .await
+            .unwrap();
+            send.send(message.clone()).await.unwrap();
+            let Some(Ok(Message::Plain { data })) = server.await.unwrap() else {
+                panic!("expected a plain message");
+            };
+            assert_eq!(data, cbor);
+
+            // Without the handshake the frame carries the payload directly.
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (mut send, _recv) = split_plain_message_stream::<ClientMessage, ServerMessage>(
+                client,
+                CompressionSettings::default(),
+            );
+            send.send(message).await.unwrap();
+            let mut server = Framed::new(server, LengthDelimitedCodec::new());
+            let frame = server.next().await.unwrap().unwrap();
+            assert_eq!(decode_frame(&frame).unwrap(), cbor);
+        });
+    }
+
+    #[test]
+    fn test_batch_matches_serde() {
+        let message = |n: usize| ServerMessage::ChannelJoined {
+            channel: format!("channel {}", n),
+        };
+        for count in [0, 1, 23, 24, 255, 256] {
+            let messages: Vec<_> = (0..count).map(message).collect();
+            let encoded: Vec<_> = messages
+                .iter()
+                .map(|message| EncodedMessage::new(message).unwrap())
+                .collect();
+            assert_eq!(
+                EncodedMessage::batch(&encoded),
+                EncodedMessage::new(&ServerMessage::Batch(messages)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_error_is_recoverable() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let listener = rt
+            .block_on(tokio::net::TcpListener::bind("localhost:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = rt.spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (mut send, _recv) =
+                split_message_stream::<u64, ServerMessage>(stream, CompressionSettings::default())
+                    .await
+                    .unwrap();
+
+            send.send(42).await.unwrap();
+            send.send(7).await.unwrap();
+        });
+
+        let server = rt.spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (_send, mut recv) = split_message_stream::<ServerMessage, ClientMessage>(
+                stream,
+                CompressionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+            for _ in 0..2 {
+                let err = recv.next().await.unwrap().unwrap_err();
+                assert!(matches!(err, MessageError::Decode(_)));
+                assert!(err.is_recoverable());
+            }
+        });
+
+        rt.block_on(async {
+            client.await.unwrap();
+            server.await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Whether `ip` may connect: denied blocks win, and an empty `allow` lets everyone else in.
+pub fn is_allowed(ip: IpAddr, allow: &[IpNet], deny: &[IpNet]) -> bool {
+    // IPv4 peers of a dual-stack listener show up as IPv4-mapped IPv6 addresses.
+    let ip = ip.to_canonical();
+    if deny.iter().any(|net| net.contains(&ip)) {
+        return false;
+    }
+    allow.is_empty() || allow.iter().any(|net| net.contains(&ip))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use crate::access::is_allowed;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_v4_matching() {
+        let allow = nets(&["10.1.0.0/16"]);
+        assert!(is_allowed(ip("10.1.2.3"), &allow, &[]));
+        assert!(!is_allowed(ip("10.2.0.1"), &allow, &[]));
+        assert!(is_allowed(ip("::ffff:10.1.0.9"), &allow, &[]));
+        assert!(!is_allowed(ip("::1"), &allow, &[]));
+
+        let single = nets(&["192.0.2.1/32"]);
+        assert!(is_allowed(ip("192.0.2.1"), &single, &[]));
+        assert!(!is_allowed(ip("192.0.2.2"), &single, &[]));
+    }
+
+    #[test]
+    fn test_v6_matching() {
+        let allow = nets(&["2001:db8::/32"]);
+        assert!(is_allowed(ip("2001:db8:ffff::1"), &allow, &[]));
+        assert!(!is_allowed(ip("2001:db9::1"), &allow, &[]));
+        assert!(!is_allowed(ip("10.0.0.1"), &allow, &[]));
+    }
+
+    #[test]
+    fn test_deny_takes_precedence() {
+        let allow = nets(&["10.0.0.0/8"]);
+        let deny = nets(&["10.0.0.66/32"]);
+        assert!(is_allowed(ip("10.0.0.1"), &allow, &deny));
+        assert!(!is_allowed(ip("10.0.0.66"), &allow, &deny));
+        assert!(!is_allowed(ip("192.0.2.1"), &allow, &deny));
+        // An empty allow list lets everything not denied in.
+        assert!(is_allowed(ip("192.0.2.1"), &[], &deny));
+    }
+
+    #[test]
+    fn test_settings_format() {
+        let parsed: Vec<IpNet> = ron::from_str(r#"["10.0.0.0/8", "fd00::/8"]"#).unwrap();
+        assert_eq!(parsed, nets(&["10.0.0.0/8", "fd00::/8"]));
+        assert!(ron::from_str::<Vec<IpNet>>(r#"["10.0.0.0/33"]"#).is_err());
+    }
+}
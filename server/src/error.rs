@@ -0,0 +1,60 @@
+use std::{
+    io,
+    net::{AddrParseError, SocketAddr},
+    path::PathBuf,
+};
+
+/// Everything that can stop the server from starting or keep running.
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("Failed to bind {address}: {source}")]
+    Bind {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    #[error("Failed to bind Unix socket {}: {source}", path.display())]
+    BindUnix { path: PathBuf, source: io::Error },
+    #[error("Failed to build tokio runtime: {0}")]
+    Runtime(io::Error),
+    #[error("Failed to read settings file {}: {source}", path.display())]
+    ReadSettings { path: PathBuf, source: io::Error },
+    #[error(
+        "Settings file {} already exists, pass --overwrite to replace it",
+        path.display()
+    )]
+    SettingsExist { path: PathBuf },
+    #[error("Failed to write settings file {}: {source}", path.display())]
+    WriteSettings { path: PathBuf, source: io::Error },
+    #[error("Failed to parse settings file {}: {source}", path.display())]
+    ParseSettings {
+        path: PathBuf,
+        source: Box<ron::error::SpannedError>,
+    },
+    #[error("Failed to serialise settings: {0}")]
+    SerialiseSettings(#[from] ron::Error),
+    #[error("Invalid listen address {address:?}: {source}")]
+    InvalidAddress {
+        address: String,
+        source: AddrParseError,
+    },
+    #[error("Failed to read identity key {}: {source}", path.display())]
+    ReadIdentity { path: PathBuf, source: io::Error },
+    #[error("Identity key {} is not a P-521 SEC1 PEM key", path.display())]
+    InvalidIdentity { path: PathBuf },
+    #[error("Failed to write identity key {}: {source}", path.display())]
+    WriteIdentity { path: PathBuf, source: io::Error },
+    #[error("TLS is configured but the server was built without the `tls` feature")]
+    TlsUnsupported,
+    #[error("Failed to read TLS {what} from {}: {reason}", path.display())]
+    TlsFile {
+        what: &'static str,
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("Invalid TLS certificate or key: {0}")]
+    TlsConfig(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+}
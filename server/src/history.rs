@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+
+use common::{ClientId, MessageId};
+
+/// A message as it was broadcast.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub id: MessageId,
+    pub sender: ClientId,
+    pub channel: String,
+    pub content: String,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HistoryError {
+    #[error("message not found")]
+    NotFound,
+    #[error("message was sent by someone else")]
+    NotOwner,
+    #[error("message id is already in use")]
+    IdInUse,
+    /// The sender sent the same message again, e.g. retrying after a dropped connection.
+    #[error("message was already sent")]
+    AlreadySent,
+}
+
+/// The most recent broadcast messages, oldest are dropped once `capacity` is reached.
+#[derive(Debug)]
+pub struct MessageHistory {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+}
+
+impl MessageHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+
+    /// Adds a new message, unless a kept one already has its id. Ids are picked by senders, so
+    /// one sender must not reuse another's and then edit or delete it.
+    pub fn push(&mut self, entry: HistoryEntry) -> Result<(), HistoryError> {
+        if let Some(kept) = self.entries.iter().find(|kept| kept.id == entry.id) {
+            return Err(match kept.sender == entry.sender {
+                true => HistoryError::AlreadySent,
+                false => HistoryError::IdInUse,
+            });
+        }
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        Ok(())
+    }
+
+    /// Replaces the content of message `id` if `requester` sent it, returning the updated entry.
+    pub fn edit(
+        &mut self,
+        id: MessageId,
+        requester: &ClientId,
+        content: String,
+    ) -> Result<&HistoryEntry, HistoryError> {
+        let index = self.owned_index(id, requester)?;
+        let entry = &mut self.entries[index];
+        entry.content = content;
+        Ok(entry)
+    }
+
+    /// Removes message `id` if `requester` sent it, returning the removed entry.
+    pub fn delete(
+        &mut self,
+        id: MessageId,
+        requester: &ClientId,
+    ) -> Result<HistoryEntry, HistoryError> {
+        let index = self.owned_index(id, requester)?;
+        self.entries.remove(index).ok_or(HistoryError::NotFound)
+    }
+
+    fn owned_index(&self, id: MessageId, requester: &ClientId) -> Result<usize, HistoryError> {
+        let index = self
+            .entries
+            .iter()
+            .rposition(|entry| entry.id == id)
+            .ok_or(HistoryError::NotFound)?;
+        if &self.entries[index].sender != requester {
+            return Err(HistoryError::NotOwner);
+        }
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::{ClientId, MessageId, PeerAddr};
+
+    use crate::history::{HistoryEntry, HistoryError, MessageHistory};
+
+    fn client(name: &str) -> ClientId {
+        ClientId {
+            name: name.to_owned(),
+            addr: PeerAddr::Virtual(0),
+        }
+    }
+
+    fn entry(id: u64, sender: &ClientId, content: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: MessageId(id),
+            sender: sender.clone(),
+            channel: "general".to_owned(),
+            content: content.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_reused_id_is_rejected() {
+        let (alice, mallory) = (client("alice"), client("mallory"));
+        let mut history = MessageHistory::new(8);
+        history.push(entry(1, &alice, "hi")).unwrap();
+
+        assert_eq!(
+            history.push(entry(1, &mallory, "gotcha")),
+            Err(HistoryError::IdInUse)
+        );
+        assert_eq!(
+            history.push(entry(1, &alice, "hi")),
+            Err(HistoryError::AlreadySent)
+        );
+        assert_eq!(
+            history.delete(MessageId(1), &mallory).unwrap_err(),
+            HistoryError::NotOwner
+        );
+        let edited = history.edit(MessageId(1), &mallory, "gotcha".to_owned());
+        assert_eq!(edited.unwrap_err(), HistoryError::NotOwner);
+
+        let kept = history.delete(MessageId(1), &alice).unwrap();
+        assert_eq!((kept.sender, kept.content.as_str()), (alice, "hi"));
+    }
+
+    #[test]
+    fn test_edit_and_delete_own_message() {
+        let alice = client("alice");
+        let mut history = MessageHistory::new(2);
+        history.push(entry(1, &alice, "one")).unwrap();
+        history.push(entry(2, &alice, "two")).unwrap();
+        history.push(entry(3, &alice, "three")).unwrap();
+
+        // Only the newest `capacity` messages are kept.
+        assert_eq!(
+            history.delete(MessageId(1), &alice).unwrap_err(),
+            HistoryError::NotFound
+        );
+        let edited = history.edit(MessageId(2), &alice, "2".to_owned()).unwrap();
+        assert_eq!(edited.content, "2");
+        history.delete(MessageId(3), &alice).unwrap();
+        assert_eq!(
+            history
+                .edit(MessageId(3), &alice, "3".to_owned())
+                .unwrap_err(),
+            HistoryError::NotFound
+        );
+    }
+}
@@ -0,0 +1,44 @@
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{io::Write, path::Path};
+
+use common::secure::Identity;
+
+use crate::error::ServerError;
+
+/// Name of the identity key `New` writes next to the settings file.
+pub const IDENTITY_FILE: &str = "server-identity.pem";
+
+/// Reads the identity key at `path`.
+pub fn load(path: &Path) -> Result<Identity, ServerError> {
+    let pem = std::fs::read_to_string(path).map_err(|source| ServerError::ReadIdentity {
+        path: path.to_owned(),
+        source,
+    })?;
+    Identity::from_pem(&pem).ok_or_else(|| ServerError::InvalidIdentity {
+        path: path.to_owned(),
+    })
+}
+
+/// Reads the identity key at `path`, generating it first if there's none. An existing key is
+/// never replaced, as that would break every client pinning it.
+pub fn load_or_generate(path: &Path) -> Result<Identity, ServerError> {
+    if path.exists() {
+        return load(path);
+    }
+    let identity = Identity::generate();
+    let write_error = |source| ServerError::WriteIdentity {
+        path: path.to_owned(),
+        source,
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only the owner may read the key.
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(identity.to_pem().as_bytes()))
+        .map_err(write_error)?;
+    Ok(identity)
+}
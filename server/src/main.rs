@@ -1,15 +1,40 @@
-use std::{process::ExitCode, sync::Arc};
+use std::{io::Write, path::PathBuf, process::ExitCode, sync::Arc};
 
 use clap::Parser;
-use log::error;
+use log::{error, info, warn};
 
-use crate::server::{Server, ServerSettings};
+use crate::{
+    error::ServerError,
+    server::{Server, ServerSettings},
+};
 
+pub mod access;
 pub mod error;
+pub mod history;
+pub mod identity;
+pub mod metrics;
 pub mod server;
+pub mod tls;
 
 /// Server backend for term-chat
 #[derive(clap::Parser)]
+pub struct Args {
+    /// Format of log output.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    log_format: LogFormat,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line with level, target, timestamp and message.
+    Json,
+}
+
+#[derive(clap::Subcommand)]
 pub enum Command {
     /// Create a new settings file to load.
     New {
@@ -29,85 +54,163 @@ pub enum Command {
     /// Direct input through command line interface.
     Cli {
         listen_addresses: Vec<String>,
+        /// Path of a Unix socket to listen on as well.
+        #[cfg(unix)]
+        #[arg(long)]
+        unix_socket: Option<PathBuf>,
         #[arg(long, default_value_t = 64)]
         max_concurrency: usize,
         #[arg(long, default_value_t = 2048)]
         max_message_buffer_size: usize,
+        #[arg(long, default_value_t = 256)]
+        max_clients: usize,
+        /// Idle seconds before TCP keepalive probes are sent.
+        #[arg(long)]
+        keepalive_secs: Option<u64>,
+        /// Seconds a new connection has to join before it is dropped.
+        #[arg(long, default_value_t = 10)]
+        join_timeout_secs: u64,
     },
 }
 
+fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
 fn main() -> ExitCode {
-    env_logger::init();
+    let Args {
+        log_format,
+        command,
+    } = Args::parse();
+    init_logger(log_format);
 
-    let rt = match tokio::runtime::Builder::new_multi_thread()
-        .enable_io()
-        .build()
-    {
-        Ok(rt) => rt,
+    match run(command) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            error!("Error occurred while building tokio runtime: {}", err);
-            return ExitCode::FAILURE;
+            error!("{}", err);
+            ExitCode::FAILURE
         }
-    };
+    }
+}
+
+fn run(command: Command) -> Result<(), ServerError> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(ServerError::Runtime)?;
 
-    match Command::parse() {
+    let server_settings = match command {
         Command::New { path, overwrite } => {
-            let settings = ServerSettings::default();
+            let path = PathBuf::from(path);
+            // Absolute, as the server may be run from another directory than the settings file.
+            let identity_path = path.with_file_name(identity::IDENTITY_FILE);
+            let identity_path = std::path::absolute(&identity_path).map_err(|source| {
+                ServerError::WriteIdentity {
+                    path: identity_path,
+                    source,
+                }
+            })?;
+            let settings = ServerSettings {
+                identity_key: Some(identity_path),
+                ..Default::default()
+            };
             let settings_ser =
-                ron::ser::to_string_pretty(&settings, ron::ser::PrettyConfig::new()).unwrap();
-            use std::io::Write;
+                ron::ser::to_string_pretty(&settings, ron::ser::PrettyConfig::new())?;
+            let write_error = |source: std::io::Error| match source.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    ServerError::SettingsExist { path: path.clone() }
+                }
+                _ => ServerError::WriteSettings {
+                    path: path.clone(),
+                    source,
+                },
+            };
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent).map_err(|source| ServerError::WriteSettings {
+                    path: parent.to_owned(),
+                    source,
+                })?;
+            }
             let mut file = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
                 .truncate(true)
                 .create_new(!overwrite)
-                .open(path)
-                .unwrap();
+                .open(&path)
+                .map_err(write_error)?;
 
-            write!(&mut file, "{}", settings_ser).unwrap();
+            write!(&mut file, "{}", settings_ser).map_err(write_error)?;
+            println!("Wrote default settings to {}", path.display());
+            if let Some(identity_path) = &settings.identity_key {
+                let identity = identity::load_or_generate(identity_path)?;
+                println!(
+                    "Identity key {} has fingerprint {}",
+                    identity_path.display(),
+                    identity.fingerprint()
+                );
+            }
+            return Ok(());
         }
         Command::Run { path } => {
-            let settings_ser = std::fs::read(path).unwrap();
-            let server_settings = ron::de::from_bytes(&settings_ser).unwrap();
-            let server = match rt.block_on(Server::new(server_settings)) {
-                Ok(server) => Arc::new(server),
-                Err(err) => {
-                    error!("Error occurred: {}", err);
-                    return ExitCode::FAILURE;
-                }
-            };
-
-            if let Err(err) = rt.block_on(server.run_loop()) {
-                error!("Error occurred: {}", err);
-            }
+            let path = PathBuf::from(path);
+            let settings_ser =
+                std::fs::read(&path).map_err(|source| ServerError::ReadSettings {
+                    path: path.clone(),
+                    source,
+                })?;
+            ron::de::from_bytes(&settings_ser).map_err(|source| ServerError::ParseSettings {
+                path,
+                source: Box::new(source),
+            })?
         }
         Command::Cli {
             listen_addresses,
+            #[cfg(unix)]
+            unix_socket,
             max_concurrency,
             max_message_buffer_size,
-        } => {
-            let server_settings = ServerSettings {
-                listen_addresses: listen_addresses
-                    .iter()
-                    .map(|addresss| addresss.parse().unwrap())
-                    .collect(),
-                max_concurrency,
-                max_message_buffer_size,
-            };
-
-            let server = match rt.block_on(Server::new(server_settings)) {
-                Ok(server) => Arc::new(server),
-                Err(err) => {
-                    error!("Error occurred: {}", err);
-                    return ExitCode::FAILURE;
-                }
-            };
+            max_clients,
+            keepalive_secs,
+            join_timeout_secs,
+        } => ServerSettings {
+            listen_addresses: listen_addresses
+                .into_iter()
+                .map(|address| match address.parse() {
+                    Ok(address) => Ok(address),
+                    Err(source) => Err(ServerError::InvalidAddress { address, source }),
+                })
+                .collect::<Result<_, _>>()?,
+            #[cfg(unix)]
+            unix_socket,
+            max_concurrency,
+            max_message_buffer_size,
+            max_clients,
+            keepalive_secs,
+            join_timeout_secs,
+            ..Default::default()
+        },
+    };
 
-            if let Err(err) = rt.block_on(server.run_loop()) {
-                error!("Error occurred: {}", err);
-            }
-        }
+    let server = Arc::new(rt.block_on(Server::new(server_settings))?);
+    match server.fingerprint() {
+        Some(fingerprint) => info!("Identity key fingerprint: {}", fingerprint),
+        None => warn!("No identity_key set, clients can't pin this server"),
     }
-
-    return ExitCode::SUCCESS;
+    rt.block_on(server.run_loop())?;
+    Ok(())
 }
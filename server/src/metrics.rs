@@ -0,0 +1,202 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::server::{
+    ACCEPT_BACKOFF_MAX, ACCEPT_BACKOFF_MIN, Server, is_connection_error, is_fatal_accept_error,
+};
+
+/// How long a scrape has to send its request and take the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters updated on the hot path, read when `/metrics` is scraped.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub messages_broadcast: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    pub send_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition of the counters and the `connected` clients gauge.
+    pub fn render(&self, connected: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "term_chat_connected_clients",
+            "gauge",
+            "Clients currently joined.",
+            connected as u64,
+        );
+        metric(
+            "term_chat_messages_broadcast_total",
+            "counter",
+            "Messages broadcast to a channel.",
+            self.messages_broadcast.load(Ordering::Relaxed),
+        );
+        metric(
+            "term_chat_bytes_sent_total",
+            "counter",
+            "Payload bytes written to clients.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        metric(
+            "term_chat_handshake_failures_total",
+            "counter",
+            "Connections that failed the secure handshake.",
+            self.handshake_failures.load(Ordering::Relaxed),
+        );
+        metric(
+            "term_chat_send_errors_total",
+            "counter",
+            "Writes to clients that failed for reasons other than a disconnect.",
+            self.send_errors.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Serves `GET /metrics` on `listener` until the server shuts down.
+pub async fn serve(server: Arc<Server>, listener: TcpListener) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Serving metrics on http://{}/metrics", addr),
+        Err(err) => warn!("Serving metrics on unknown address: {}", err),
+    }
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) if is_connection_error(&err) => {
+                warn!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+            Err(err) if is_fatal_accept_error(&err) => {
+                error!("Metrics listener failed, no longer serving: {}", err);
+                return;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to accept metrics connection, retrying in {:?}: {}",
+                    backoff, err
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF_MIN;
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, respond(&server, stream)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => warn!("Error serving metrics to {}: {}", addr, err),
+                Err(_) => warn!("Dropped metrics connection from {}: timed out", addr),
+            }
+        });
+    }
+}
+
+async fn respond(
+    server: &Server,
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    // Only the request line matters, the rest of the request is ignored.
+    let mut request = [0u8; 1024];
+    let len = stream.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", server.render_metrics()),
+        _ => ("404 Not Found", "Not Found\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        metrics::{Metrics, respond},
+        server::{Server, ServerSettings},
+    };
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        Metrics::add(&metrics.messages_broadcast, 3);
+        Metrics::add(&metrics.messages_broadcast, 2);
+        let text = metrics.render(4);
+
+        assert!(
+            text.contains(
+                "# TYPE term_chat_connected_clients gauge\nterm_chat_connected_clients 4\n"
+            )
+        );
+        assert!(text.contains("\nterm_chat_messages_broadcast_total 5\n"));
+        assert!(text.contains("# TYPE term_chat_send_errors_total counter\n"));
+        assert!(text.ends_with("term_chat_send_errors_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_respond() {
+        let settings = ServerSettings {
+            listen_addresses: Vec::new(),
+            ..Default::default()
+        };
+        let server = Server::new(settings).await.unwrap();
+        let get = |request: &'static str| {
+            let server = &server;
+            async move {
+                let (mut client, server_end) = tokio::io::duplex(64 * 1024);
+                client.write_all(request.as_bytes()).await.unwrap();
+                respond(server, server_end).await.unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+
+        let response = get("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("term_chat_connected_clients 0\n"));
+
+        let response = get("GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\nNot Found\n"));
+    }
+}
@@ -1,50 +1,323 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
 
-use bytes::Bytes;
 use common::{
-    ClientId, ClientMessage, ServerMessage, WriteSink, secure::SecureStream,
+    AdminCommand, ClientId, ClientMessage, DEFAULT_CHANNEL, MessageId, PROTOCOL_VERSION, PeerAddr,
+    Presence, ResumeToken, ServerLimits, ServerMessage, Transport,
+    codec::CompressionSettings,
+    rate::RateLimiter,
+    secure::{HandshakeOptions, Identity, Plaintext, SecureStreamError, secrets_match},
+    stream::{EncodedMessage, ServerSink, ServerStream, split_message_stream_with},
 };
 use futures::{SinkExt, StreamExt, stream::FuturesUnordered};
+use ipnet::IpNet;
 use log::{error, info, warn};
-use papaya::HashMap;
+use papaya::{HashMap, Operation};
 use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{Mutex, Notify, mpsc},
+    time::{Instant, timeout_at},
 };
+#[cfg(unix)]
+use {std::os::unix::fs::FileTypeExt, tokio::net::UnixListener};
 
-#[derive(Debug, thiserror::Error)]
-pub enum ServerError {
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-    #[error(transparent)]
-    JoinError(#[from] tokio::task::JoinError),
-}
+use crate::{
+    access,
+    error::ServerError,
+    history::{HistoryEntry, HistoryError, MessageHistory},
+    identity,
+    metrics::{self, Metrics},
+    tls::TlsSettings,
+};
+
+/// First delay after a failed `accept`, doubled on each further failure.
+pub const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+pub const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// How long a kicked client's writer gets to deliver the [`ServerMessage::Kicked`] notice.
+const KICK_NOTICE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Inactivity after which a client counts as idle, the others get a fresh client list when it
+/// becomes active again.
+const IDLE_AFTER: Duration = Duration::from_secs(60);
 
 pub struct Client {
     id: ClientId,
-    write_msg: Mutex<WriteSink>,
+    /// Messages waiting to be written by the client's writer task.
+    outbound: mpsc::Sender<EncodedMessage<ServerMessage>>,
+    /// Notified to disconnect the client, e.g. when its outbound queue overflows.
+    kick: Notify,
+    /// Notified to have the writer task write what's queued and then stop.
+    closing: Arc<Notify>,
+    /// Set when the server removed the client, its queue is flushed before disconnecting.
+    kicked: AtomicBool,
+    /// Sent the server's admin token when joining.
+    admin: bool,
+    /// When the client last sent anything.
+    last_active: std::sync::Mutex<Instant>,
+    /// When the client joined, kept across resumes.
+    joined_at: SystemTime,
+    /// Holds the client to `messages_per_second`.
+    send_rate: std::sync::Mutex<RateLimiter>,
+}
+
+/// Receiving end of a client's outbound queue, shared so it outlives a writer task while the
+/// client can still resume. The queue doubles as the buffer for a detached client.
+type OutboundReceiver = Arc<Mutex<Outbound>>;
+
+struct Outbound {
+    recv: mpsc::Receiver<EncodedMessage<ServerMessage>>,
+    /// Messages taken from the queue but not flushed yet, written first by the next writer task.
+    unsent: Vec<EncodedMessage<ServerMessage>>,
+}
+
+impl Outbound {
+    fn new(recv: mpsc::Receiver<EncodedMessage<ServerMessage>>) -> OutboundReceiver {
+        Arc::new(Mutex::new(Self {
+            recv,
+            unsent: Vec::new(),
+        }))
+    }
+}
+
+/// Most messages a writer feeds before flushing, even if more are queued.
+const WRITE_BATCH: usize = 32;
+
+/// What names are compared by, so `Carol` and `carol ` count as `carol`.
+fn name_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    name_key(a) == name_key(b)
+}
+
+/// Accepts connections for [`Server::run_loop`].
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        /// Connections accepted so far, numbers the peers since they have no address.
+        connections: u64,
+    },
+}
+
+impl Listener {
+    /// Binds a Unix socket at `path`, replacing a socket left behind by a previous run.
+    #[cfg(unix)]
+    fn bind_unix(path: &Path) -> Result<Self, ServerError> {
+        let bind_error = |source| ServerError::BindUnix {
+            path: path.to_owned(),
+            source,
+        };
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                std::fs::remove_file(path).map_err(bind_error)?
+            }
+            _ => (),
+        }
+        let listener = UnixListener::bind(path).map_err(bind_error)?;
+        Ok(Self::Unix {
+            listener,
+            connections: 0,
+        })
+    }
+
+    /// Fails, Unix sockets are not available on this platform.
+    #[cfg(not(unix))]
+    fn bind_unix(path: &Path) -> Result<Self, ServerError> {
+        Err(ServerError::BindUnix {
+            path: path.to_owned(),
+            source: io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            ),
+        })
+    }
+}
+
+/// A session whose connection dropped, held until it resumes or its grace window lapses.
+struct Detached {
+    client: Arc<Client>,
+    outbound_recv: OutboundReceiver,
+    channel: String,
+    /// Notified when a new connection takes over the session.
+    resumed: Notify,
+}
+
+impl Client {
+    /// Queues `message` without waiting, kicking the client if its queue is full.
+    fn enqueue(&self, message: EncodedMessage<ServerMessage>) {
+        match self.outbound.try_send(message) {
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Outbound queue of {} is full, disconnecting", self.id);
+                self.kick.notify_one();
+            }
+            // The writer task already stopped, the read loop cleans up.
+            Err(mpsc::error::TrySendError::Closed(_)) => (),
+        }
+    }
+
+    /// Records activity from the client, returning how long it had been idle.
+    fn touch(&self) -> Duration {
+        let now = Instant::now();
+        let last_active = std::mem::replace(&mut *self.last_active.lock().unwrap(), now);
+        now.duration_since(last_active)
+    }
+
+    /// Tells the client why it is removed, then disconnects it once that is written.
+    fn kick_with_reason(&self, reason: String) {
+        self.disconnect_with(&ServerMessage::Kicked { reason });
+    }
+
+    /// Sends `notice` as the last message, then disconnects the client once that is written.
+    fn disconnect_with(&self, notice: &ServerMessage) {
+        if let Some(message) = encode(notice) {
+            self.enqueue(message);
+        }
+        self.kicked.store(true, Ordering::Relaxed);
+        self.closing.notify_one();
+        self.kick.notify_one();
+    }
 }
 
+/// Settings loaded from `server-settings.ron`, missing fields take their default value.
 #[derive(Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServerSettings {
     pub listen_addresses: Vec<SocketAddr>,
+    /// Path of a Unix socket to listen on as well, `None` to only listen on TCP. Fails to start
+    /// on platforms without Unix sockets.
+    pub unix_socket: Option<PathBuf>,
     pub max_concurrency: usize,
     pub max_message_buffer_size: usize,
+    /// Maximum number of joined clients, further joins are rejected.
+    pub max_clients: usize,
+    /// Messages queued for a client before it is considered too slow and disconnected, also
+    /// bounds what is buffered for a dropped client while it can resume.
+    pub outbound_queue_size: usize,
+    /// Milliseconds a client's writer gathers messages to send them as one
+    /// [`ServerMessage::Batch`], 0 to send each on its own.
+    pub batch_window_ms: u64,
+    /// Idle seconds before TCP keepalive probes are sent, `None` to leave keepalive off.
+    pub keepalive_secs: Option<u64>,
+    /// Seconds a new connection has to complete the handshake and join before it is dropped.
+    pub join_timeout_secs: u64,
+    /// Seconds a dropped client's session is held for it to resume, 0 to disable resuming.
+    pub resume_grace_secs: u64,
+    /// Seconds a joined client may send nothing before it is disconnected, 0 to never.
+    pub idle_disconnect_secs: u64,
+    /// Compression applied to frames sent to clients.
+    pub compression: CompressionSettings,
+    /// Number of recent messages kept, only these can be edited or deleted.
+    pub max_history: usize,
+    /// Name shown to clients, empty to leave it out.
+    pub server_name: String,
+    /// Message of the day shown to clients on join, empty to leave it out.
+    pub motd: String,
+    /// Address to serve Prometheus metrics on at `/metrics`, `None` to disable.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Secret that makes a client an admin when sent in its join request, `None` to disable
+    /// admin commands.
+    pub admin_token: Option<String>,
+    /// Most names admins may ban, bans last until the server restarts.
+    pub max_banned_names: usize,
+    /// Blocks such as `"10.0.0.0/8"` allowed to connect over TCP, empty to allow any. A single
+    /// address is written as a block of one, e.g. `"192.0.2.1/32"`.
+    pub allow_cidrs: Vec<IpNet>,
+    /// Blocks refused even if allowed by `allow_cidrs`.
+    pub deny_cidrs: Vec<IpNet>,
+    /// Longest name a client may join with, in characters.
+    pub max_name_length: usize,
+    /// Longest message, edit or private message a client may send, in characters.
+    pub max_message_length: usize,
+    /// Messages and private messages a client may send per second, in bursts of up to a
+    /// second's worth. 0 for no limit.
+    pub messages_per_second: u32,
+    /// Names no client may join with, compared ignoring case, e.g. to stop impersonating
+    /// `"admin"`.
+    pub reserved_names: Vec<String>,
+    /// Serve TCP connections over TLS, needs the `tls` feature. `None` uses the built-in
+    /// handshake.
+    pub tls: Option<TlsSettings>,
+    /// PEM file of the key signing every built-in handshake, which clients pin the server by.
+    /// `None` leaves the server unidentified.
+    pub identity_key: Option<PathBuf>,
+    /// Let clients started with `--insecure` connect without encryption, for debugging.
+    pub allow_insecure: bool,
 }
 
 impl Default for ServerSettings {
     fn default() -> Self {
         Self {
             listen_addresses: vec!["0.0.0.0:6942".parse().unwrap()],
+            unix_socket: None,
             max_concurrency: 128,
             max_message_buffer_size: 2048,
+            max_clients: 256,
+            outbound_queue_size: 256,
+            batch_window_ms: 0,
+            keepalive_secs: None,
+            join_timeout_secs: 10,
+            resume_grace_secs: 30,
+            idle_disconnect_secs: 0,
+            compression: CompressionSettings::default(),
+            max_history: 1024,
+            server_name: String::new(),
+            motd: String::new(),
+            metrics_addr: None,
+            admin_token: None,
+            max_banned_names: 1024,
+            allow_cidrs: Vec::new(),
+            deny_cidrs: Vec::new(),
+            max_name_length: 32,
+            max_message_length: 4096,
+            messages_per_second: 0,
+            reserved_names: Vec::new(),
+            tls: None,
+            identity_key: None,
+            allow_insecure: false,
         }
     }
 }
 
 pub struct Server {
     clients: HashMap<ClientId, Arc<Client>>,
+    /// Slots taken towards `max_clients`, by joined clients and joins in progress.
+    joined: AtomicUsize,
+    /// Sessions waiting to be resumed, by the token that resumes them.
+    detached: HashMap<ResumeToken, Arc<Detached>>,
+    /// Members of each channel, channels are removed once empty.
+    channels: HashMap<String, HashSet<ClientId>>,
+    /// Listeners bound in [`Server::new`], taken by [`Server::run_loop`].
+    listeners: Mutex<Vec<Listener>>,
+    bound_addresses: Vec<SocketAddr>,
+    /// Names refused from joining by an admin, as [`name_key`]s.
+    banned_names: papaya::HashSet<String>,
+    /// Addresses whose connections are dropped, from clients banned by an admin.
+    banned_ips: papaya::HashSet<IpAddr>,
+    /// Recent messages, used to check ownership for edits and deletes.
+    history: std::sync::Mutex<MessageHistory>,
+    metrics: Metrics,
+    started: Instant,
+    /// Bound in [`Server::new`] when `metrics_addr` is set, taken by [`Server::run_loop`].
+    metrics_listener: Mutex<Option<TcpListener>>,
+    /// Built from `tls` in [`Server::new`].
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<tokio_rustls::TlsAcceptor>,
+    /// Loaded from `identity_key` in [`Server::new`].
+    identity: Option<Identity>,
 
     settings: ServerSettings,
 }
@@ -52,54 +325,269 @@ pub struct Server {
 impl Server {
     pub async fn new(settings: ServerSettings) -> Result<Self, ServerError> {
         let clients = HashMap::new();
+        #[cfg(feature = "tls")]
+        let tls_acceptor = settings
+            .tls
+            .as_ref()
+            .map(TlsSettings::acceptor)
+            .transpose()?;
+        #[cfg(not(feature = "tls"))]
+        if settings.tls.is_some() {
+            return Err(ServerError::TlsUnsupported);
+        }
+        let identity = settings
+            .identity_key
+            .as_deref()
+            .map(identity::load)
+            .transpose()?;
+        if settings.allow_insecure {
+            warn!("allow_insecure is set, clients may connect without encryption");
+        }
+
+        let mut listeners = Vec::new();
+        let mut bound_addresses = Vec::new();
+        for &address in settings.listen_addresses.iter() {
+            let listener = TcpListener::bind(address)
+                .await
+                .map_err(|source| ServerError::Bind { address, source })?;
+            bound_addresses.push(listener.local_addr()?);
+            listeners.push(Listener::Tcp(listener));
+        }
+        if let Some(path) = &settings.unix_socket {
+            listeners.push(Listener::bind_unix(path)?);
+        }
+        let metrics_listener = match settings.metrics_addr {
+            Some(address) => Some(
+                TcpListener::bind(address)
+                    .await
+                    .map_err(|source| ServerError::Bind { address, source })?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            clients,
+            joined: AtomicUsize::new(0),
+            detached: HashMap::new(),
+            channels: HashMap::new(),
+            listeners: Mutex::new(listeners),
+            bound_addresses,
+            banned_names: papaya::HashSet::new(),
+            banned_ips: papaya::HashSet::new(),
+            history: std::sync::Mutex::new(MessageHistory::new(settings.max_history)),
+            metrics: Metrics::default(),
+            started: Instant::now(),
+            metrics_listener: Mutex::new(metrics_listener),
+            #[cfg(feature = "tls")]
+            tls_acceptor,
+            identity,
+            settings,
+        })
+    }
 
-        Ok(Self { clients, settings })
+    /// Checks a joining client's name against [`ServerSettings`], `Err` has the reason it's
+    /// refused.
+    fn validate_name(&self, name: &str) -> Result<(), String> {
+        if name.trim().is_empty() {
+            return Err("name is empty".to_owned());
+        }
+        if name.chars().count() > self.settings.max_name_length {
+            return Err(format!(
+                "name is longer than {} characters",
+                self.settings.max_name_length
+            ));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("name contains control characters".to_owned());
+        }
+        let reserved = self
+            .settings
+            .reserved_names
+            .iter()
+            .any(|reserved| same_name(reserved, name));
+        if reserved {
+            return Err("name is reserved".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Addresses the server is actually listening on.
+    ///
+    /// Differs from [`ServerSettings::listen_addresses`] when binding to port 0.
+    pub fn bound_addresses(&self) -> Vec<SocketAddr> {
+        self.bound_addresses.clone()
     }
+
     pub async fn run_loop(self: &Arc<Self>) -> Result<(), ServerError> {
         info!("Started server!");
         let mut futures = FuturesUnordered::new();
-        let mut listeners = Vec::new();
+        let listeners = std::mem::take(&mut *self.listeners.lock().await);
 
-        for address in self.settings.listen_addresses.iter() {
-            listeners.push(TcpListener::bind(address).await?);
+        for address in self.bound_addresses.iter() {
+            info!("Listening on {}", address);
+        }
+        if let Some(path) = &self.settings.unix_socket {
+            info!("Listening on {}", path.display());
         }
-        for listener in listeners {
+        if let Some(listener) = self.metrics_listener.lock().await.take() {
+            tokio::spawn(metrics::serve(Arc::clone(self), listener));
+        }
+        for mut listener in listeners {
             let this = Arc::clone(self);
             futures.push(tokio::spawn(async move {
                 let mut futures = FuturesUnordered::new();
+                let mut backoff = ACCEPT_BACKOFF_MIN;
                 loop {
-                    if let Ok((stream, addr)) = listener.accept().await {
-                        futures.push(tokio::spawn(
-                            Arc::clone(&this).handle_new_connection(stream, addr),
-                        ));
-
-                        // Enforce max concurrency
-                        while futures.len() >= this.settings.max_concurrency {
-                            futures.next().await;
+                    let (stream, addr) = match this.accept(&mut listener).await {
+                        Ok(accepted) => accepted,
+                        Err(err) if is_connection_error(&err) => {
+                            // The peer went away before we accepted, the listener is fine.
+                            warn!("Failed to accept connection: {}", err);
+                            continue;
+                        }
+                        Err(err) if is_fatal_accept_error(&err) => {
+                            error!("Listener failed, no longer accepting: {}", err);
+                            return Err(ServerError::Io(err));
+                        }
+                        Err(err) => {
+                            // Likely resource exhaustion, e.g. too many open files.
+                            warn!(
+                                "Failed to accept connection, retrying in {:?}: {}",
+                                backoff, err
+                            );
+                            tokio::time::sleep(backoff).await;
+                            backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                            continue;
                         }
+                    };
+                    backoff = ACCEPT_BACKOFF_MIN;
+
+                    futures.push(tokio::spawn(
+                        Arc::clone(&this).handle_new_connection(stream, addr),
+                    ));
+
+                    // Enforce max concurrency
+                    while futures.len() >= this.settings.max_concurrency {
+                        futures.next().await;
                     }
                 }
             }));
         }
 
-        futures.next().await;
+        if let Some(result) = futures.next().await {
+            result??;
+        }
         Ok(())
     }
 
-    pub async fn handle_new_connection(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) {
-        let stream = match SecureStream::handshake(stream).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                error!("{}", err);
-                return;
+    /// Prometheus text exposition of the server's metrics.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(self.clients.len())
+    }
+
+    /// Accepts the next connection on `listener`.
+    async fn accept(&self, listener: &mut Listener) -> io::Result<(Box<dyn Transport>, PeerAddr)> {
+        match listener {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                self.configure_stream(&stream, addr);
+                Ok((Box::new(stream), PeerAddr::Tcp(addr)))
             }
+            #[cfg(unix)]
+            Listener::Unix {
+                listener,
+                connections,
+            } => {
+                let (stream, _) = listener.accept().await?;
+                *connections += 1;
+                Ok((Box::new(stream), PeerAddr::Unix(*connections)))
+            }
+        }
+    }
+
+    /// Applies socket options to a freshly accepted stream.
+    fn configure_stream(&self, stream: &TcpStream, addr: SocketAddr) {
+        if let Err(err) = stream.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY for {}: {}", addr, err);
+        }
+        if let Some(secs) = self.settings.keepalive_secs {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+            if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+                warn!("Failed to set SO_KEEPALIVE for {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Secures a new connection, with TLS for TCP peers when it's configured and with the
+    /// built-in handshake otherwise.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    async fn secure(
+        &self,
+        stream: impl Transport,
+        addr: &PeerAddr,
+    ) -> Result<(ServerSink, ServerStream), SecureStreamError> {
+        let compression = self.settings.compression;
+        #[cfg(feature = "tls")]
+        if let (Some(acceptor), PeerAddr::Tcp(_)) = (&self.tls_acceptor, addr) {
+            let stream = acceptor.accept(stream).await?;
+            return Ok(common::stream::split_plain_message_stream(
+                stream,
+                compression,
+            ));
+        }
+        let options = HandshakeOptions {
+            identity: self.identity.as_ref(),
+            plaintext: match self.settings.allow_insecure {
+                true => Plaintext::Allow,
+                false => Plaintext::Refuse,
+            },
+            ..Default::default()
         };
-        let (write_msg, mut read_msg) = stream.split();
+        split_message_stream_with(stream, compression, options).await
+    }
+
+    /// Fingerprint of the identity key clients can pin, `None` without one.
+    pub fn fingerprint(&self) -> Option<String> {
+        self.identity.as_ref().map(Identity::fingerprint)
+    }
+
+    /// Runs a connection from `addr` over any transport, from the handshake until it leaves.
+    pub async fn handle_new_connection(self: Arc<Self>, stream: impl Transport, addr: PeerAddr) {
+        if let Some(ip) = addr.ip() {
+            if !access::is_allowed(ip, &self.settings.allow_cidrs, &self.settings.deny_cidrs) {
+                info!("Refused {}: address not allowed", addr);
+                return;
+            }
+            if self.banned_ips.pin().contains(&ip) {
+                info!("Refused {}: address is banned", addr);
+                return;
+            }
+        }
+        let join_timeout = Duration::from_secs(self.settings.join_timeout_secs);
+        let join_deadline = Instant::now() + join_timeout;
+        let (mut write_msg, mut read_msg) =
+            match timeout_at(join_deadline, self.secure(stream, &addr)).await {
+                Ok(Ok(halves)) => halves,
+                Ok(Err(err)) => {
+                    Metrics::add(&self.metrics.handshake_failures, 1);
+                    error!("{}", err);
+                    return;
+                }
+                Err(_) => {
+                    info!("Dropped {}: no handshake within {:?}", addr, join_timeout);
+                    return;
+                }
+            };
 
         info!("Accepted {}", addr);
 
-        let client_id = loop {
-            let message: Bytes = match read_msg.next().await {
+        let resume_token = (self.settings.resume_grace_secs > 0).then(ResumeToken::random);
+        let (client, outbound_recv, mut writer, mut channel, resumed) = loop {
+            let Ok(message) = timeout_at(join_deadline, read_msg.next()).await else {
+                info!("Dropped {}: no join within {:?}", addr, join_timeout);
+                return;
+            };
+            let message = match message {
                 Some(Ok(message)) => message,
                 Some(Err(err)) => {
                     error!("Error deserialising message from {}: {}", addr, err);
@@ -113,39 +601,165 @@ impl Server {
                     return;
                 }
             };
-            let message: ClientMessage = match serde_cbor::from_slice(&message) {
-                Ok(message) => message,
-                Err(err) => {
-                    error!("Error deserialising message from {}: {}", addr, err);
-                    return;
-                }
-            };
             match message {
-                ClientMessage::JoinRequest { name } => {
-                    let client_id = ClientId { name, addr };
+                ClientMessage::JoinRequest {
+                    name,
+                    channel,
+                    protocol_version,
+                    admin_token,
+                } => {
+                    let client_id = ClientId {
+                        name,
+                        addr: addr.clone(),
+                    };
+
+                    if protocol_version != PROTOCOL_VERSION {
+                        let reason = format!(
+                            "protocol version {} is not supported, the server speaks {}",
+                            protocol_version, PROTOCOL_VERSION
+                        );
+                        reject_join(write_msg, &client_id, reason).await;
+                        return;
+                    }
+
+                    if let Err(reason) = self.validate_name(&client_id.name) {
+                        reject_join(write_msg, &client_id, reason).await;
+                        return;
+                    }
+
+                    if self.banned_names.pin().contains(&name_key(&client_id.name)) {
+                        reject_join(write_msg, &client_id, "banned".to_owned()).await;
+                        return;
+                    }
+
+                    if !self.reserve_slot() {
+                        reject_join(write_msg, &client_id, "server full".to_owned()).await;
+                        return;
+                    }
 
-                    let client = Client {
+                    let (outbound, outbound_recv) =
+                        mpsc::channel(self.settings.outbound_queue_size.max(1));
+                    let outbound_recv = Outbound::new(outbound_recv);
+                    let admin = match (&self.settings.admin_token, &admin_token) {
+                        (Some(expected), Some(given)) => secrets_match(expected, given),
+                        _ => false,
+                    };
+                    let client = Arc::new(Client {
                         id: client_id.clone(),
-                        write_msg: Mutex::new(write_msg),
+                        outbound,
+                        kick: Notify::new(),
+                        closing: Arc::new(Notify::new()),
+                        kicked: AtomicBool::new(false),
+                        admin,
+                        last_active: std::sync::Mutex::new(Instant::now()),
+                        joined_at: SystemTime::now(),
+                        send_rate: std::sync::Mutex::new(RateLimiter::new(
+                            self.settings.messages_per_second,
+                        )),
+                    });
+                    // Written before the writer starts, so it is never part of a batch.
+                    let response = ServerMessage::AcceptJoin {
+                        id: client_id.clone(),
+                        resume_token,
+                        limits: self.limits(),
                     };
+                    if let Err(err) = write_msg.send(response).await {
+                        error!("Error writing to client {}: {}", client_id, err);
+                        self.joined.fetch_sub(1, Ordering::AcqRel);
+                        return;
+                    }
+                    let writer = tokio::spawn(Arc::clone(&self).write_loop(
+                        client_id.clone(),
+                        write_msg,
+                        Arc::clone(&outbound_recv),
+                        Arc::clone(&client.closing),
+                    ));
 
-                    let clients = self.clients.pin_owned();
-                    clients.insert(client_id.clone(), Arc::new(client));
+                    let channel = match channel {
+                        Some(channel) if is_valid_channel_name(&channel) => channel,
+                        Some(channel) => {
+                            warn!(
+                                "Client {} requested invalid channel {:?}, using default",
+                                client_id, channel
+                            );
+                            DEFAULT_CHANNEL.to_owned()
+                        }
+                        None => DEFAULT_CHANNEL.to_owned(),
+                    };
 
-                    let response = serde_cbor::ser::to_vec(&ServerMessage::AcceptJoin).unwrap();
+                    self.clients
+                        .pin()
+                        .insert(client_id.clone(), Arc::clone(&client));
+                    self.join_channel(&channel, &client_id);
 
-                    if let Err(err) = clients
-                        .get(&client_id)
-                        .unwrap()
-                        .write_msg
-                        .lock()
-                        .await
-                        .send(Bytes::from(response))
-                        .await
-                    {
-                        error!("Error writing to client {}: {}", client_id, err)
+                    let mut responses = vec![ServerMessage::ChannelJoined {
+                        channel: channel.clone(),
+                    }];
+                    // After `ChannelJoined`, which clears the client's message view.
+                    if !self.settings.server_name.is_empty() || !self.settings.motd.is_empty() {
+                        responses.push(ServerMessage::Welcome {
+                            server_name: self.settings.server_name.clone(),
+                            motd: self.settings.motd.clone(),
+                        });
+                    }
+                    // Sent directly so the roster can't race ahead of or behind the join.
+                    responses.push(ServerMessage::ClientListUpdate {
+                        clients: self.client_list(&channel),
+                    });
+                    for response in responses {
+                        let response = match EncodedMessage::new(&response) {
+                            Ok(response) => response,
+                            Err(err) => {
+                                error!("Error serialising message: {}", err);
+                                continue;
+                            }
+                        };
+                        // The queue is fresh, wait for room rather than kicking.
+                        if client.outbound.send(response).await.is_err() {
+                            error!("Writer task of {} stopped before joining", client_id)
+                        }
+                    }
+                    break (client, outbound_recv, writer, channel, false);
+                }
+                ClientMessage::Resume { token } => {
+                    let detached = self.detached.pin().remove(&token).cloned();
+                    let Some(detached) = detached else {
+                        info!("Rejected resume from {}: unknown or expired token", addr);
+                        if let Err(err) = write_msg.send(ServerMessage::ResumeRejected).await {
+                            error!("Error writing to {}: {}", addr, err);
+                            return;
+                        }
+                        continue;
+                    };
+                    detached.resumed.notify_one();
+                    let client = Arc::clone(&detached.client);
+                    let channel = detached.channel.clone();
+                    info!("{} resumed from {}", client.id, addr);
+
+                    // Written before the writer starts, so it precedes anything queued meanwhile.
+                    let response = ServerMessage::AcceptJoin {
+                        id: client.id.clone(),
+                        resume_token,
+                        limits: self.limits(),
+                    };
+                    if let Err(err) = write_msg.send(response).await {
+                        error!("Error writing to client {}: {}", client.id, err);
+                        self.remove_client(&client.id, &channel);
+                        return;
                     }
-                    break client_id;
+                    let writer = tokio::spawn(Arc::clone(&self).write_loop(
+                        client.id.clone(),
+                        write_msg,
+                        Arc::clone(&detached.outbound_recv),
+                        Arc::clone(&client.closing),
+                    ));
+                    break (
+                        client,
+                        Arc::clone(&detached.outbound_recv),
+                        writer,
+                        channel,
+                        true,
+                    );
                 }
                 message => {
                     warn!(
@@ -156,102 +770,2156 @@ impl Server {
             }
         };
 
-        let message = ServerMessage::ClientListUpdate {
-            clients: self.clients.pin_owned().keys().cloned().collect(),
-        };
-        let message = match serde_cbor::to_vec(&message) {
-            Ok(message) => Bytes::from(message),
-            Err(err) => {
-                error!("Error deserialising message: {}", err);
-                return;
-            }
-        };
-
-        let this = Arc::clone(&self);
-        tokio::task::spawn(async move {
-            this.broadcast(&message).await;
-        });
+        let client_id = client.id.clone();
+        // A resumed client never left as far as the others are concerned.
+        if !resumed {
+            let clients = self.client_list(&channel);
+            self.broadcast_except(
+                &channel,
+                &ServerMessage::ClientListUpdate { clients },
+                Some(&client_id),
+            );
+        }
 
-        loop {
-            let message = match read_msg.next().await {
-                Some(Ok(message)) => message,
-                Some(Err(err)) => {
-                    error!("Error deserialising message from {}: {}", addr, err);
-                    continue;
+        // Whether the connection dropped, rather than the client leaving or being kicked.
+        let idle_timeout = Duration::from_secs(self.settings.idle_disconnect_secs);
+        let mut last_activity = Instant::now();
+        let dropped = loop {
+            let message = tokio::select! {
+                message = read_msg.next() => message,
+                () = tokio::time::sleep_until(last_activity + idle_timeout),
+                    if !idle_timeout.is_zero() =>
+                {
+                    info!("{} sent nothing for {:?}", client_id, idle_timeout);
+                    let reason = "idle timeout".to_owned();
+                    client.disconnect_with(&ServerMessage::Error { reason });
+                    break false;
                 }
-                None => {
-                    error!("Error deserialising message from {}", addr);
-                    break;
+                () = client.kick.notified() => {
+                    info!("Kicked {}", client_id);
+                    break false;
                 }
+                // A failed write means the connection is gone even if reading hasn't noticed,
+                // unless the writer stopped after delivering a kick notice.
+                _ = &mut writer => break !client.kicked.load(Ordering::Relaxed),
             };
-            let message: ClientMessage = match serde_cbor::from_slice(&message) {
-                Ok(message) => message,
-                Err(err) => {
+            let message = match message {
+                Some(Ok(message)) => {
+                    last_activity = Instant::now();
+                    if client.touch() >= IDLE_AFTER && !matches!(message, ClientMessage::Leave) {
+                        self.broadcast_client_list(&channel);
+                    }
+                    message
+                }
+                Some(Err(err)) if err.is_recoverable() => {
                     error!("Error deserialising message from {}: {}", addr, err);
                     continue;
                 }
+                Some(Err(err)) if err.is_disconnect() => {
+                    info!("{} disconnected: {}", client_id, err);
+                    break true;
+                }
+                Some(Err(err)) => {
+                    error!("Error reading from {}: {}", addr, err);
+                    break true;
+                }
+                None => {
+                    info!("{} disconnected", client_id);
+                    break true;
+                }
             };
             match message {
-                ClientMessage::JoinRequest { name: _ } => {
+                ClientMessage::JoinRequest { .. } => {
                     warn!("Client {} has already joined", client_id);
                 }
-                ClientMessage::SendMessage { message } => {
-                    info!("Client {} sent message: {:?}", client_id, message);
+                ClientMessage::Resume { .. } => {
+                    warn!("Client {} has already joined", client_id);
+                }
+                ClientMessage::Leave => {
+                    info!("{} left", client_id);
+                    break false;
+                }
+                ClientMessage::JoinChannel {
+                    channel: new_channel,
+                } => {
+                    if !is_valid_channel_name(&new_channel) {
+                        warn!(
+                            "Client {} requested invalid channel {:?}",
+                            client_id, new_channel
+                        );
+                        continue;
+                    }
+                    if new_channel == channel {
+                        continue;
+                    }
+                    info!(
+                        "Client {} moved from #{} to #{}",
+                        client_id, channel, new_channel
+                    );
 
-                    let message = ServerMessage::ReceiveMessage {
+                    self.leave_channel(&channel, &client_id);
+                    self.broadcast_client_list(&channel);
+                    self.join_channel(&new_channel, &client_id);
+                    channel = new_channel;
+
+                    self.send_to(
+                        &client_id,
+                        &ServerMessage::ChannelJoined {
+                            channel: channel.clone(),
+                        },
+                    );
+                    self.broadcast_client_list(&channel);
+                }
+                ClientMessage::SendMessage {
+                    id,
+                    message,
+                    reply_to,
+                } => {
+                    info!("Client {} sent message {}: {:?}", client_id, id, message);
+                    let checked = self
+                        .check_length(&message)
+                        .and_then(|()| self.check_rate(&client));
+                    if let Err(reason) = checked {
+                        self.send_to(&client_id, &ServerMessage::MessageRejected { id, reason });
+                        continue;
+                    }
+                    let message = sanitize(&message);
+
+                    let pushed = self.history.lock().unwrap().push(HistoryEntry {
+                        id,
                         sender: client_id.clone(),
-                        message,
-                    };
-                    let message = match serde_cbor::to_vec(&message) {
-                        Ok(message) => Bytes::from(message),
+                        channel: channel.clone(),
+                        content: message.clone(),
+                    });
+                    match pushed {
+                        Ok(()) => (),
+                        // Broadcast the first time, the sender only missed the ack.
+                        Err(HistoryError::AlreadySent) => {
+                            self.send_to(&client_id, &ServerMessage::Ack { id });
+                            continue;
+                        }
                         Err(err) => {
-                            error!("Error deserialising message from {}: {}", client_id, err);
+                            warn!("Rejected message {} from {}: {}", id, client_id, err);
+                            let reason = err.to_string();
+                            self.send_to(
+                                &client_id,
+                                &ServerMessage::MessageRejected { id, reason },
+                            );
                             continue;
                         }
+                    }
+
+                    let message = ServerMessage::ReceiveMessage {
+                        id,
+                        sender: client_id.clone(),
+                        message,
+                        reply_to,
                     };
-                    let this = Arc::clone(&self);
-                    tokio::task::spawn(async move {
-                        this.broadcast(&message).await;
-                    });
+                    self.broadcast(&channel, &message);
+                    Metrics::add(&self.metrics.messages_broadcast, 1);
+                    self.send_to(&client_id, &ServerMessage::Ack { id });
+                }
+                ClientMessage::EditMessage { id, new_content } => {
+                    if let Err(reason) = self.check_length(&new_content) {
+                        self.send_to(&client_id, &ServerMessage::EditRejected { id, reason });
+                        continue;
+                    }
+                    let result = self
+                        .history
+                        .lock()
+                        .unwrap()
+                        .edit(id, &client_id, sanitize(&new_content))
+                        .map(|entry| {
+                            let message = ServerMessage::MessageEdited {
+                                id,
+                                sender: entry.sender.clone(),
+                                content: entry.content.clone(),
+                            };
+                            (entry.channel.clone(), message)
+                        });
+                    self.apply_history_change(&client_id, id, result);
+                }
+                ClientMessage::DeleteMessage { id } => {
+                    let result = self
+                        .history
+                        .lock()
+                        .unwrap()
+                        .delete(id, &client_id)
+                        .map(|entry| {
+                            let message = ServerMessage::MessageDeleted {
+                                id,
+                                sender: entry.sender,
+                            };
+                            (entry.channel, message)
+                        });
+                    self.apply_history_change(&client_id, id, result);
+                }
+                ClientMessage::Admin(command) => self.admin_command(&client, command),
+                ClientMessage::PrivateMessage { to, message } => {
+                    let checked = self
+                        .check_length(&message)
+                        .and_then(|()| self.check_rate(&client));
+                    if let Err(reason) = checked {
+                        let rejected = ServerMessage::PrivateMessageRejected { to, reason };
+                        self.send_to(&client_id, &rejected);
+                        continue;
+                    }
+                    self.private_message(&client_id, to, sanitize(&message));
                 }
+                ClientMessage::Whois { name } => self.whois(&client_id, name),
             }
+        };
+        if client.kicked.load(Ordering::Relaxed) && !writer.is_finished() {
+            let _ = tokio::time::timeout(KICK_NOTICE_TIMEOUT, &mut writer).await;
         }
-        self.clients.pin().remove(&client_id);
-        info!("{} has been removed from clients list.", client_id);
+        // The writer may be stuck on a client that stopped reading, drop the connection.
+        writer.abort();
+
+        if let Some(token) = resume_token.filter(|_| dropped) {
+            let detached = Arc::new(Detached {
+                client: Arc::clone(&client),
+                outbound_recv,
+                channel: channel.clone(),
+                resumed: Notify::new(),
+            });
+            self.detached.pin().insert(token, Arc::clone(&detached));
+            let grace = Duration::from_secs(self.settings.resume_grace_secs);
+            info!("Holding the session of {} for {:?}", client_id, grace);
+
+            let kicked = tokio::select! {
+                () = tokio::time::sleep(grace) => false,
+                // Its queue overflowed while detached.
+                () = client.kick.notified() => true,
+                () = detached.resumed.notified() => return,
+            };
+            if self.detached.pin().remove(&token).is_none() {
+                // Resumed meanwhile, the kick is for the new connection.
+                if kicked {
+                    client.kick.notify_one();
+                }
+                return;
+            }
+            info!("{} did not resume in time", client_id);
+        }
+
+        self.remove_client(&client_id, &channel);
+    }
 
-        let message = ServerMessage::ClientListUpdate {
-            clients: self.clients.pin_owned().keys().cloned().collect(),
+    /// Runs an admin command from `client`, refusing it unless `client` is an admin.
+    fn admin_command(&self, client: &Client, command: AdminCommand) {
+        if !client.admin {
+            warn!(
+                "Rejected admin command from {}, not an admin: {:?}",
+                client.id, command
+            );
+            let reason = "not an admin".to_owned();
+            self.send_to(&client.id, &ServerMessage::AdminRejected { reason });
+            return;
+        }
+        let (name, reason, ban) = match command {
+            AdminCommand::Kick { name } => (name, "kicked by an admin", false),
+            AdminCommand::Ban { name } => (name, "banned by an admin", true),
+            AdminCommand::Stats => {
+                let stats = ServerMessage::Stats {
+                    client_count: self.clients.len() as u64,
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    total_messages: self.metrics.messages_broadcast.load(Ordering::Relaxed),
+                };
+                self.send_to(&client.id, &stats);
+                return;
+            }
         };
-        let message = match serde_cbor::to_vec(&message) {
-            Ok(message) => Bytes::from(message),
-            Err(err) => {
-                error!("Error deserialising message: {}", err);
+        if ban {
+            let banned_names = self.banned_names.pin();
+            // Inserted before checking so concurrent bans can't overshoot the limit.
+            let key = name_key(&name);
+            if banned_names.insert(key.clone())
+                && banned_names.len() > self.settings.max_banned_names
+            {
+                banned_names.remove(&key);
+                let reason = format!(
+                    "can't ban more than {} names",
+                    self.settings.max_banned_names
+                );
+                self.send_to(&client.id, &ServerMessage::AdminRejected { reason });
                 return;
             }
+        }
+        let targets: Vec<_> = self
+            .clients
+            .pin()
+            .values()
+            .filter(|target| same_name(&target.id.name, &name))
+            .cloned()
+            .collect();
+        if targets.is_empty() && !ban {
+            let reason = format!("no client called {}", name);
+            self.send_to(&client.id, &ServerMessage::AdminRejected { reason });
+            return;
+        }
+        for target in targets {
+            info!("{} was {} ({})", target.id, reason, client.id);
+            if let Some(ip) = target.id.addr.ip().filter(|_| ban) {
+                self.banned_ips.pin().insert(ip);
+            }
+            target.kick_with_reason(reason.to_owned());
+        }
+    }
+
+    /// Delivers a private message from `sender` to every client called `to`, and echoes it back.
+    fn private_message(&self, sender: &ClientId, to: String, message: String) {
+        let clients = self.clients.pin();
+        let recipients: Vec<_> = clients
+            .values()
+            .filter(|recipient| recipient.id.name == to)
+            .collect();
+        if recipients.is_empty() {
+            let reason = format!("no client called {}", to);
+            self.send_to(
+                sender,
+                &ServerMessage::PrivateMessageRejected { to, reason },
+            );
+            return;
+        }
+        // The text stays out of the log, it is private.
+        info!("{} sent a private message to {}", sender, to);
+        let sent_to_self = recipients.iter().any(|recipient| recipient.id == *sender);
+        let message = ServerMessage::PrivateMessage {
+            sender: sender.clone(),
+            to,
+            message,
         };
-        let this = Arc::clone(&self);
-        tokio::task::spawn(async move {
-            this.broadcast(&message).await;
-        });
+        let Some(message) = encode(&message) else {
+            return;
+        };
+        for recipient in recipients {
+            recipient.enqueue(message.clone());
+        }
+        if !sent_to_self {
+            if let Some(client) = clients.get(sender) {
+                client.enqueue(message);
+            }
+        }
     }
 
-    pub async fn broadcast(self: &Arc<Self>, message: &Bytes) {
-        let mut futures = FuturesUnordered::new();
+    /// What clients are told to keep to when they join.
+    fn limits(&self) -> ServerLimits {
+        ServerLimits {
+            max_message_length: self.settings.max_message_length,
+            messages_per_second: self.settings.messages_per_second,
+            max_clients: self.settings.max_clients,
+        }
+    }
+
+    /// Refuses a message from `client` beyond `messages_per_second`, with the reason to tell it.
+    fn check_rate(&self, client: &Client) -> Result<(), String> {
+        match client.send_rate.lock().unwrap().try_acquire() {
+            true => Ok(()),
+            false => Err(format!(
+                "sending faster than {} messages a second",
+                self.settings.messages_per_second
+            )),
+        }
+    }
+
+    /// Refuses `content` longer than `max_message_length`, with the reason to tell its sender.
+    fn check_length(&self, content: &str) -> Result<(), String> {
+        let max = self.settings.max_message_length;
+        match content.chars().count() > max {
+            true => Err(format!("longer than the limit of {} characters", max)),
+            false => Ok(()),
+        }
+    }
+
+    /// Tells `requester` about every client called `name`.
+    fn whois(&self, requester: &ClientId, name: String) {
+        let clients = self.clients.pin();
+        let mut found = false;
+        for client in clients.values().filter(|client| client.id.name == name) {
+            found = true;
+            let idle = client.last_active.lock().unwrap().elapsed();
+            let joined_at = client
+                .joined_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let result = ServerMessage::WhoisResult {
+                id: client.id.clone(),
+                joined_at: joined_at.as_secs(),
+                away: idle >= IDLE_AFTER,
+                last_active: idle.as_secs(),
+            };
+            self.send_to(requester, &result);
+        }
+        if !found {
+            let reason = format!("no client called {}", name);
+            self.send_to(requester, &ServerMessage::WhoisRejected { name, reason });
+        }
+    }
+
+    /// Takes a slot for a joining client, `false` if `max_clients` are already taken. Checked
+    /// and taken in one step so concurrent joins can't overshoot the limit.
+    fn reserve_slot(&self) -> bool {
+        self.joined
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |joined| {
+                (joined < self.settings.max_clients).then_some(joined + 1)
+            })
+            .is_ok()
+    }
 
-        let mut client_vec = Vec::new();
-        for (_id, client) in self.clients.pin().iter() {
-            client_vec.push(Arc::clone(client));
+    /// Removes a client for good and tells the rest of its channel.
+    fn remove_client(&self, client_id: &ClientId, channel: &str) {
+        // Unlisted first, so the broadcast below and any later ones skip this client.
+        self.leave_channel(channel, client_id);
+        if self.clients.pin().remove(client_id).is_some() {
+            self.joined.fetch_sub(1, Ordering::AcqRel);
         }
+        info!("{} has been removed from clients list.", client_id);
+
+        self.broadcast_client_list(channel);
+    }
+
+    fn join_channel(&self, channel: &str, client_id: &ClientId) {
+        self.channels.pin().update_or_insert_with(
+            channel.to_owned(),
+            |members| {
+                let mut members = members.clone();
+                members.insert(client_id.clone());
+                members
+            },
+            || HashSet::from([client_id.clone()]),
+        );
+    }
 
-        for client in client_vec {
-            let message = message.clone();
-            futures.push(async move {
-                if let Err(err) = client.write_msg.lock().await.send(message).await {
-                    error!("Error sending message to {}: {}", client.id, err);
+    fn leave_channel(&self, channel: &str, client_id: &ClientId) {
+        self.channels
+            .pin()
+            .compute(channel.to_owned(), |entry| match entry {
+                Some((_, members)) if members.contains(client_id) => {
+                    let mut members = members.clone();
+                    members.remove(client_id);
+                    if members.is_empty() {
+                        Operation::Remove
+                    } else {
+                        Operation::Insert(members)
+                    }
                 }
+                _ => Operation::Abort(()),
+            });
+    }
+
+    /// Sends the current member list of `channel` to everyone in it.
+    fn broadcast_client_list(&self, channel: &str) {
+        let clients = self.client_list(channel);
+        self.broadcast(channel, &ServerMessage::ClientListUpdate { clients });
+    }
+
+    /// Current members of `channel` and how long they have been idle.
+    fn client_list(&self, channel: &str) -> Vec<Presence> {
+        let clients = self.clients.pin();
+        let channels = self.channels.pin();
+        let Some(members) = channels.get(channel) else {
+            return Vec::new();
+        };
+        members
+            .iter()
+            .map(|id| Presence {
+                id: id.clone(),
+                last_active: clients.get(id).map_or(0, |client| {
+                    client.last_active.lock().unwrap().elapsed().as_secs()
+                }),
             })
+            .collect()
+    }
+
+    /// Broadcasts the outcome of an edit or delete, or tells the requester why it was rejected.
+    fn apply_history_change(
+        &self,
+        client_id: &ClientId,
+        id: MessageId,
+        result: Result<(String, ServerMessage), HistoryError>,
+    ) {
+        match result {
+            Ok((channel, message)) => {
+                info!("Client {} changed message {}", client_id, id);
+                self.broadcast(&channel, &message);
+            }
+            Err(err) => {
+                warn!(
+                    "Rejected change to message {} from {}: {}",
+                    id, client_id, err
+                );
+                let reason = err.to_string();
+                self.send_to(client_id, &ServerMessage::EditRejected { id, reason });
+            }
+        }
+    }
+
+    /// Sends `message` to a single client, if it is still connected.
+    fn send_to(&self, client_id: &ClientId, message: &ServerMessage) {
+        let Some(message) = encode(message) else {
+            return;
+        };
+        if let Some(client) = self.clients.pin().get(client_id) {
+            client.enqueue(message);
         }
+    }
+
+    /// Queues `message` for every client in `channel`, slow clients don't hold up the rest.
+    ///
+    /// The message is serialised once and shared between the clients.
+    pub fn broadcast(&self, channel: &str, message: &ServerMessage) {
+        self.broadcast_except(channel, message, None);
+    }
 
-        while let Some(()) = futures.next().await {}
+    /// Like [`Server::broadcast`], skipping `except` if given.
+    fn broadcast_except(&self, channel: &str, message: &ServerMessage, except: Option<&ClientId>) {
+        let Some(message) = encode(message) else {
+            return;
+        };
+        let clients = self.clients.pin();
+        if let Some(members) = self.channels.pin().get(channel) {
+            for id in members.iter().filter(|id| Some(*id) != except) {
+                if let Some(client) = clients.get(id) {
+                    client.enqueue(message.clone());
+                }
+            }
+        }
+    }
+
+    /// Writes queued messages for one client until its queue is dropped or a write fails.
+    async fn write_loop(
+        self: Arc<Self>,
+        client_id: ClientId,
+        mut write_msg: ServerSink,
+        outbound: OutboundReceiver,
+        closing: Arc<Notify>,
+    ) {
+        let batch_window = Duration::from_millis(self.settings.batch_window_ms);
+        let mut outbound = outbound.lock().await;
+        // How many of `outbound.unsent` were fed on this connection.
+        let mut fed = 0;
+        let result = loop {
+            let batch_done = fed == outbound.unsent.len()
+                && (fed >= WRITE_BATCH || (fed > 0 && outbound.recv.is_empty()));
+            if batch_done {
+                // One flush for everything fed, rather than one per message.
+                if let Err(err) = write_msg.flush().await {
+                    break Err(err);
+                }
+                let len = outbound.unsent.drain(..).map(|m| m.len() as u64).sum();
+                Metrics::add(&self.metrics.bytes_sent, len);
+                fed = 0;
+            }
+            if fed == outbound.unsent.len() {
+                let message = tokio::select! {
+                    message = outbound.recv.recv() => message,
+                    // Stop taking messages, those already queued are still written.
+                    () = closing.notified() => {
+                        outbound.recv.close();
+                        continue;
+                    }
+                };
+                let Some(message) = message else {
+                    break Ok(());
+                };
+                // Kept until flushed, so a write cut short is retried after a resume.
+                outbound.unsent.push(message);
+                if !batch_window.is_zero() {
+                    let deadline = Instant::now() + batch_window;
+                    while outbound.unsent.len() - fed < WRITE_BATCH {
+                        match timeout_at(deadline, outbound.recv.recv()).await {
+                            Ok(Some(message)) => outbound.unsent.push(message),
+                            // A closed queue is noticed on the next receive.
+                            Ok(None) | Err(_) => break,
+                        }
+                    }
+                }
+            }
+            let pending = &outbound.unsent[fed..];
+            let count = match batch_window.is_zero() {
+                true => 1,
+                false => pending.len().min(WRITE_BATCH),
+            };
+            let message = match count {
+                1 => pending[0].clone(),
+                _ => EncodedMessage::batch(&pending[..count]),
+            };
+            if let Err(err) = write_msg.feed_encoded(message).await {
+                break Err(err);
+            }
+            fed += count;
+        };
+        if let Err(err) = result {
+            if err.is_disconnect() {
+                info!("{} disconnected while sending: {}", client_id, err);
+            } else {
+                Metrics::add(&self.metrics.send_errors, 1);
+                error!("Error sending message to {}: {}", client_id, err);
+            }
+        }
+        let _ = write_msg.close().await;
+    }
+}
+
+/// Tells a client why it can't join and closes the connection.
+async fn reject_join(mut write_msg: ServerSink, client_id: &ClientId, reason: String) {
+    info!("Rejected {}: {}", client_id, reason);
+    if let Err(err) = write_msg.send(ServerMessage::JoinRejected { reason }).await {
+        error!("Error writing to client {}: {}", client_id, err)
+    }
+    let _ = write_msg.close().await;
+}
+
+fn encode(message: &ServerMessage) -> Option<EncodedMessage<ServerMessage>> {
+    match EncodedMessage::new(message) {
+        Ok(message) => Some(message),
+        Err(err) => {
+            error!("Error serialising message: {}", err);
+            None
+        }
+    }
+}
+
+/// Errors from `accept` caused by a single connection, the listener is still usable.
+pub fn is_connection_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Errors from `accept` that won't go away by retrying, e.g. a closed or invalid socket.
+pub fn is_fatal_accept_error(err: &io::Error) -> bool {
+    // A bad descriptor has no `ErrorKind` of its own.
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EBADF) {
+        return true;
+    }
+    matches!(
+        err.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotConnected | io::ErrorKind::Unsupported
+    )
+}
+
+/// Message content with control characters other than newlines replaced by their visible
+/// symbol, e.g. ESC by `␛`, so a client can't send escape sequences to others' terminals.
+pub fn sanitize(content: &str) -> String {
+    content
+        .replace("\r\n", "\n")
+        .chars()
+        .map(|c| match c {
+            '\n' => c,
+            '\u{0}'..='\u{1f}' => char::from_u32(0x2400 + u32::from(c)).unwrap_or('�'),
+            '\u{7f}' => '␡',
+            c if c.is_control() => '�',
+            c => c,
+        })
+        .collect()
+}
+
+/// Channel names are short, non-empty and free of whitespace and control characters.
+pub fn is_valid_channel_name(channel: &str) -> bool {
+    !channel.is_empty()
+        && channel.chars().count() <= 32
+        && !channel.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicU64, Ordering},
+        },
+        time::{Duration, SystemTime},
+    };
+
+    use common::{
+        AdminCommand, ClientId, ClientMessage, MessageId, PROTOCOL_VERSION, PeerAddr, ResumeToken,
+        ServerLimits, ServerMessage,
+        codec::CompressionSettings,
+        rate::RateLimiter,
+        secure::{HandshakeOptions, Plaintext, SecureStreamError},
+        stream::{
+            ClientSink, ClientStream, EncodedMessage, ServerSink, split_message_stream,
+            split_message_stream_with,
+        },
+    };
+    use futures::{SinkExt, StreamExt};
+    use socket2::SockRef;
+    use tokio::{
+        sync::{Notify, mpsc},
+        task::JoinHandle,
+        time::Instant,
+    };
+
+    use crate::{
+        error::ServerError,
+        identity,
+        server::{
+            Client, Outbound, Server, ServerSettings, is_connection_error, is_fatal_accept_error,
+            sanitize,
+        },
+        tls::TlsSettings,
+    };
+
+    fn add_client(
+        server: &Server,
+        name: &str,
+        queue_size: usize,
+    ) -> (Arc<Client>, mpsc::Receiver<EncodedMessage<ServerMessage>>) {
+        let id = ClientId {
+            name: name.to_owned(),
+            addr: PeerAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+        };
+        let (outbound, outbound_recv) = mpsc::channel(queue_size);
+        let client = Arc::new(Client {
+            id: id.clone(),
+            outbound,
+            kick: Notify::new(),
+            closing: Arc::default(),
+            kicked: AtomicBool::new(false),
+            admin: false,
+            last_active: std::sync::Mutex::new(Instant::now()),
+            joined_at: SystemTime::now(),
+            send_rate: std::sync::Mutex::new(RateLimiter::new(0)),
+        });
+        server.clients.pin().insert(id.clone(), Arc::clone(&client));
+        server.joined.fetch_add(1, Ordering::AcqRel);
+        server.join_channel("general", &id);
+        (client, outbound_recv)
+    }
+
+    /// A server without listeners, driven by virtual clients.
+    async fn test_server(settings: ServerSettings) -> Arc<Server> {
+        let settings = ServerSettings {
+            listen_addresses: Vec::new(),
+            ..settings
+        };
+        Arc::new(Server::new(settings).await.unwrap())
+    }
+
+    fn join_request(name: &str) -> ClientMessage {
+        ClientMessage::JoinRequest {
+            name: name.to_owned(),
+            channel: None,
+            protocol_version: PROTOCOL_VERSION,
+            admin_token: None,
+        }
+    }
+
+    fn test_id(name: &str) -> ClientId {
+        ClientId {
+            name: name.to_owned(),
+            addr: PeerAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+        }
+    }
+
+    /// Server side sending half of a loopback connection, with the client's receiving half unless
+    /// `reset` is set, in which case the client has already reset the connection.
+    async fn writer_connection(reset: bool) -> (ServerSink, Option<ClientStream>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = tokio::spawn(async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            if reset {
+                // Reset rather than close on drop, like a client that vanished.
+                SockRef::from(&stream)
+                    .set_linger(Some(Duration::ZERO))
+                    .unwrap();
+            }
+            split_message_stream::<ClientMessage, ServerMessage>(
+                stream,
+                CompressionSettings::default(),
+            )
+            .await
+            .unwrap()
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let (write_msg, _) = split_message_stream::<ServerMessage, ClientMessage>(
+            stream,
+            CompressionSettings::default(),
+        )
+        .await
+        .unwrap();
+        let (_, read_msg) = connect.await.unwrap();
+        if reset {
+            drop(read_msg);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            return (write_msg, None);
+        }
+        (write_msg, Some(read_msg))
+    }
+
+    /// A client wired to a server over an in-memory pipe, and the connection's handler task.
+    type VirtualClient = (JoinHandle<()>, ClientSink, ClientStream);
+
+    /// Numbers virtual clients so their ids differ across calls.
+    static NEXT_VIRTUAL: AtomicU64 = AtomicU64::new(0);
+
+    /// Connects `count` clients to `server` over [`tokio::io::duplex`] pipes, without sockets.
+    async fn connect_virtual(server: &Arc<Server>, count: usize) -> Vec<VirtualClient> {
+        let mut clients = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (client, server_end) = tokio::io::duplex(64 * 1024);
+            let addr = PeerAddr::Virtual(NEXT_VIRTUAL.fetch_add(1, Ordering::Relaxed));
+            let handler = tokio::spawn(Arc::clone(server).handle_new_connection(server_end, addr));
+            let (write_msg, read_msg) =
+                split_message_stream(client, CompressionSettings::default())
+                    .await
+                    .unwrap();
+            clients.push((handler, write_msg, read_msg));
+        }
+        clients
+    }
+
+    async fn connect(server: &Arc<Server>) -> VirtualClient {
+        connect_virtual(server, 1).await.pop().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_failure_reports_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = taken.local_addr().unwrap();
+        let settings = ServerSettings {
+            listen_addresses: vec![address],
+            ..Default::default()
+        };
+
+        match Server::new(settings).await {
+            Err(ServerError::Bind {
+                address: failed, ..
+            }) => assert_eq!(failed, address),
+            Err(err) => panic!("expected a bind error, got {}", err),
+            Ok(_) => panic!("bound an address that is already in use"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_reader_is_kicked() {
+        let server = test_server(ServerSettings::default()).await;
+
+        // Nobody drains the stalled client's queue, like a writer blocked on a full socket.
+        let (stalled, _stalled_recv) = add_client(&server, "stalled", 2);
+        let (fast, mut fast_recv) = add_client(&server, "fast", 16);
+
+        let message = |n| ServerMessage::Ack { id: MessageId(n) };
+        for n in 0..8 {
+            server.broadcast("general", &message(n));
+        }
+
+        for n in 0..8 {
+            let expected = EncodedMessage::new(&message(n)).unwrap();
+            assert_eq!(fast_recv.try_recv().unwrap(), expected);
+        }
+        tokio::time::timeout(Duration::from_secs(1), stalled.kick.notified())
+            .await
+            .expect("stalled client should be kicked");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), fast.kick.notified())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_is_dropped() {
+        let server = test_server(ServerSettings {
+            join_timeout_secs: 1,
+            ..Default::default()
+        })
+        .await;
+        let (handler, _write_msg, mut read_msg) = connect(&server).await;
+
+        // Handshake done, but no join request is ever sent.
+        tokio::time::timeout(Duration::from_secs(3), handler)
+            .await
+            .expect("connection should be dropped after the join timeout")
+            .unwrap();
+        assert!(read_msg.next().await.is_none());
+        assert!(server.clients.pin().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_list_reports_idle_time() {
+        let server = test_server(ServerSettings::default()).await;
+        let (alice, _alice_recv) = add_client(&server, "alice", 16);
+        let (bob, _bob_recv) = add_client(&server, "bob", 16);
+        *bob.last_active.lock().unwrap() = Instant::now() - Duration::from_secs(120);
+
+        let idle = |id: &ClientId| {
+            let clients = server.client_list("general");
+            clients
+                .into_iter()
+                .find(|client| client.id == *id)
+                .unwrap()
+                .last_active
+        };
+        assert_eq!(idle(&alice.id), 0);
+        assert_eq!(idle(&bob.id), 120);
+        assert!(bob.touch() >= Duration::from_secs(120));
+        assert_eq!(idle(&bob.id), 0);
+    }
+
+    #[tokio::test]
+    async fn test_joining_client_receives_roster() {
+        let server = test_server(ServerSettings::default()).await;
+        let (alice, mut alice_recv) = add_client(&server, "alice", 16);
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg.send(join_request("bob")).await.unwrap();
+
+        let Some(Ok(ServerMessage::AcceptJoin { id: bob, .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        let roster = loop {
+            match tokio::time::timeout(Duration::from_secs(1), read_msg.next()).await {
+                Ok(Some(Ok(ServerMessage::ClientListUpdate { clients }))) => {
+                    break clients
+                        .into_iter()
+                        .map(|client| client.id)
+                        .collect::<Vec<_>>();
+                }
+                Ok(Some(Ok(_))) => continue,
+                _ => panic!("joining client never received the client list"),
+            }
+        };
+        assert_eq!(roster.len(), 2);
+        assert!(roster.contains(&bob));
+        assert!(roster.contains(&alice.id));
+        // The broadcast to the others doesn't send the joining client a second copy.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), read_msg.next())
+                .await
+                .is_err()
+        );
+
+        tokio::time::timeout(Duration::from_secs(1), alice_recv.recv())
+            .await
+            .expect("existing members should be told about the join")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_leave_removes_client() {
+        let server = test_server(ServerSettings::default()).await;
+        let (handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        write_msg.send(ClientMessage::Leave).await.unwrap();
+
+        // The connection is still open, only the Leave ends the session.
+        tokio::time::timeout(Duration::from_secs(1), handler)
+            .await
+            .expect("Leave should end the session")
+            .unwrap();
+        assert!(server.clients.pin().is_empty());
+    }
+
+    #[test]
+    fn test_accept_error_classes() {
+        use std::io;
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(is_connection_error(&reset) && !is_fatal_accept_error(&reset));
+        let invalid = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(is_fatal_accept_error(&invalid));
+        #[cfg(unix)]
+        {
+            assert!(is_fatal_accept_error(&io::Error::from_raw_os_error(
+                libc::EBADF
+            )));
+            // Running out of descriptors is retried with a backoff.
+            assert!(!is_fatal_accept_error(&io::Error::from_raw_os_error(
+                libc::EMFILE
+            )));
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("hi\nthere"), "hi\nthere");
+        assert_eq!(sanitize("hi\r\nthere"), "hi\nthere");
+        assert_eq!(sanitize("\x1b[2J"), "␛[2J");
+        assert_eq!(sanitize("a\tb\x07\x7f\u{9b}"), "a␉b␇␡�");
+        assert_eq!(sanitize("日本語 🚀"), "日本語 🚀");
+    }
+
+    #[tokio::test]
+    async fn test_escape_sequences_are_neutralized() {
+        let server = test_server(ServerSettings::default()).await;
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg.send(join_request("bob")).await.unwrap();
+        for _ in 0..3 {
+            // AcceptJoin, ChannelJoined and ClientListUpdate.
+            read_msg.next().await.unwrap().unwrap();
+        }
+
+        write_msg
+            .send(ClientMessage::SendMessage {
+                id: MessageId(1),
+                message: "clear\x1b[2J".to_owned(),
+                reply_to: None,
+            })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::ReceiveMessage { message, .. })) = read_msg.next().await else {
+            panic!("expected ReceiveMessage");
+        };
+        assert_eq!(message, "clear␛[2J");
+        assert!(!message.contains('\x1b'));
+        read_msg.next().await.unwrap().unwrap();
+
+        write_msg
+            .send(ClientMessage::EditMessage {
+                id: MessageId(1),
+                new_content: "\x1b]0;pwned\x07".to_owned(),
+            })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::MessageEdited { content, .. })) = read_msg.next().await else {
+            panic!("expected MessageEdited");
+        };
+        assert_eq!(content, "␛]0;pwned␇");
+    }
+
+    #[tokio::test]
+    async fn test_duplex_connection_lifecycle() {
+        let server = test_server(ServerSettings::default()).await;
+        let (handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { id, .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        assert!(matches!(id.addr, PeerAddr::Virtual(_)));
+        let Some(Ok(ServerMessage::ChannelJoined { .. })) = read_msg.next().await else {
+            panic!("expected ChannelJoined");
+        };
+        let Some(Ok(ServerMessage::ClientListUpdate { .. })) = read_msg.next().await else {
+            panic!("expected ClientListUpdate");
+        };
+
+        write_msg
+            .send(ClientMessage::SendMessage {
+                id: MessageId(1),
+                message: "hi".to_owned(),
+                reply_to: None,
+            })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::ReceiveMessage { sender, .. })) = read_msg.next().await else {
+            panic!("expected ReceiveMessage");
+        };
+        assert_eq!(sender, id);
+        let ack = read_msg.next().await.unwrap().unwrap();
+        assert_eq!(ack, ServerMessage::Ack { id: MessageId(1) });
+
+        write_msg.send(ClientMessage::Leave).await.unwrap();
+        handler.await.unwrap();
+        assert!(server.clients.pin().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_virtual_clients() {
+        let server = test_server(ServerSettings::default()).await;
+        let mut clients = connect_virtual(&server, 3).await;
+        for (i, (_, write_msg, read_msg)) in clients.iter_mut().enumerate() {
+            write_msg
+                .send(join_request(&format!("user{i}")))
+                .await
+                .unwrap();
+            let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+                panic!("expected AcceptJoin");
+            };
+        }
+
+        let (_, write_msg, _) = &mut clients[0];
+        write_msg
+            .send(ClientMessage::SendMessage {
+                id: MessageId(1),
+                message: "hi".to_owned(),
+                reply_to: None,
+            })
+            .await
+            .unwrap();
+        for (_, _, read_msg) in clients.iter_mut() {
+            // Skip the join notices sent before the message.
+            loop {
+                match read_msg.next().await.unwrap().unwrap() {
+                    ServerMessage::ReceiveMessage { message, .. } => {
+                        assert_eq!(message, "hi");
+                        break;
+                    }
+                    ServerMessage::ChannelJoined { .. }
+                    | ServerMessage::ClientListUpdate { .. } => (),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reused_message_id_is_rejected() {
+        let server = test_server(ServerSettings::default()).await;
+        let mut clients = connect_virtual(&server, 2).await;
+        for (i, (_, write_msg, _)) in clients.iter_mut().enumerate() {
+            write_msg
+                .send(join_request(&format!("user{i}")))
+                .await
+                .unwrap();
+        }
+        let send = ClientMessage::SendMessage {
+            id: MessageId(1),
+            message: "hi".to_owned(),
+            reply_to: None,
+        };
+        // The next reply to our own messages, skipping joins and broadcasts.
+        async fn reply(read_msg: &mut ClientStream) -> ServerMessage {
+            loop {
+                let message = read_msg.next().await.unwrap().unwrap();
+                if matches!(
+                    message,
+                    ServerMessage::Ack { .. } | ServerMessage::MessageRejected { .. }
+                ) {
+                    return message;
+                }
+            }
+        }
+
+        let (_, write_msg, read_msg) = &mut clients[0];
+        write_msg.send(send.clone()).await.unwrap();
+        assert_eq!(
+            reply(read_msg).await,
+            ServerMessage::Ack { id: MessageId(1) }
+        );
+        // Sending it again is acked without a second broadcast.
+        write_msg.send(send.clone()).await.unwrap();
+        assert_eq!(
+            reply(read_msg).await,
+            ServerMessage::Ack { id: MessageId(1) }
+        );
+
+        let (_, write_msg, read_msg) = &mut clients[1];
+        write_msg.send(send).await.unwrap();
+        let ServerMessage::MessageRejected { id, .. } = reply(read_msg).await else {
+            panic!("expected MessageRejected");
+        };
+        assert_eq!(id, MessageId(1));
+        write_msg
+            .send(ClientMessage::DeleteMessage { id: MessageId(1) })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::EditRejected { .. })) = read_msg.next().await else {
+            panic!("expected EditRejected");
+        };
+    }
+
+    #[tokio::test]
+    async fn test_resume_keeps_session() {
+        let server = test_server(ServerSettings::default()).await;
+        let (_alice, mut alice_recv) = add_client(&server, "alice", 16);
+        let (handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin {
+            id: bob,
+            resume_token: Some(token),
+            ..
+        })) = read_msg.next().await
+        else {
+            panic!("expected AcceptJoin with a resume token");
+        };
+        drop((write_msg, read_msg));
+        // Only the disconnect ends the handler early, the session is still held.
+        tokio::time::timeout(Duration::from_millis(100), handler)
+            .await
+            .unwrap_err();
+
+        // Sent while bob is away, delivered once he resumes.
+        let missed = ServerMessage::Ack { id: MessageId(7) };
+        server.broadcast("general", &missed);
+
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg
+            .send(ClientMessage::Resume { token })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin {
+            id,
+            resume_token: Some(new_token),
+            ..
+        })) = read_msg.next().await
+        else {
+            panic!("expected AcceptJoin after resuming");
+        };
+        assert_eq!(id, bob);
+        assert_ne!(new_token, token);
+        loop {
+            match tokio::time::timeout(Duration::from_secs(1), read_msg.next()).await {
+                Ok(Some(Ok(message))) if message == missed => break,
+                Ok(Some(Ok(_))) => continue,
+                _ => panic!("message sent while away was not delivered"),
+            }
+        }
+
+        // Alice saw bob join and the broadcast, but no leave and rejoin.
+        let mut alice_messages = 0;
+        while alice_recv.try_recv().is_ok() {
+            alice_messages += 1;
+        }
+        assert_eq!(alice_messages, 2);
+        assert!(server.clients.pin().contains_key(&bob));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_resume_token_allows_join() {
+        let server = test_server(ServerSettings::default()).await;
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        let token = ResumeToken::random();
+        write_msg
+            .send(ClientMessage::Resume { token })
+            .await
+            .unwrap();
+        assert_eq!(
+            read_msg.next().await.transpose().unwrap(),
+            Some(ServerMessage::ResumeRejected)
+        );
+
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+    }
+
+    #[tokio::test]
+    async fn test_full_server_rejects_join() {
+        let server = test_server(ServerSettings {
+            max_clients: 1,
+            ..Default::default()
+        })
+        .await;
+        let (alice_handler, mut alice_write, mut alice_read) = connect(&server).await;
+        alice_write.send(join_request("alice")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = alice_read.next().await else {
+            panic!("expected AcceptJoin");
+        };
+
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::JoinRejected { reason })) = read_msg.next().await else {
+            panic!("expected JoinRejected");
+        };
+        assert_eq!(reason, "server full");
+
+        // Leaving frees the slot.
+        alice_write.send(ClientMessage::Leave).await.unwrap();
+        alice_handler.await.unwrap();
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("carol")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_joins_respect_max_clients() {
+        let server = test_server(ServerSettings {
+            max_clients: 3,
+            ..Default::default()
+        })
+        .await;
+        let joins: Vec<_> = connect_virtual(&server, 12)
+            .await
+            .into_iter()
+            .enumerate()
+            .map(|(i, (handler, mut write_msg, mut read_msg))| {
+                tokio::spawn(async move {
+                    write_msg
+                        .send(join_request(&format!("user{}", i)))
+                        .await
+                        .unwrap();
+                    let response = read_msg.next().await.unwrap().unwrap();
+                    (handler, write_msg, read_msg, response)
+                })
+            })
+            .collect();
+
+        let mut accepted = 0;
+        let mut connections = Vec::new();
+        for join in joins {
+            let (handler, write_msg, read_msg, response) = join.await.unwrap();
+            match response {
+                ServerMessage::AcceptJoin { .. } => accepted += 1,
+                ServerMessage::JoinRejected { reason } => assert_eq!(reason, "server full"),
+                response => panic!("unexpected {:?}", response),
+            }
+            connections.push((handler, write_msg, read_msg));
+        }
+        assert_eq!(accepted, 3);
+        assert_eq!(server.joined.load(Ordering::Acquire), 3);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_not_a_send_error() {
+        let server = test_server(ServerSettings::default()).await;
+
+        let (write_msg, _) = writer_connection(true).await;
+
+        let (outbound, outbound_recv) = mpsc::channel(4);
+        for n in 0..4 {
+            let message = EncodedMessage::new(&ServerMessage::Ack { id: MessageId(n) });
+            outbound.try_send(message.unwrap()).unwrap();
+        }
+        drop(outbound);
+        let outbound_recv = Outbound::new(outbound_recv);
+        Arc::clone(&server)
+            .write_loop(
+                test_id("bob"),
+                write_msg,
+                Arc::clone(&outbound_recv),
+                Arc::default(),
+            )
+            .await;
+
+        // The write failed: nothing was counted as sent and the messages are kept for a resume.
+        assert_eq!(server.metrics.bytes_sent.load(Ordering::Relaxed), 0);
+        assert!(!outbound_recv.lock().await.unsent.is_empty());
+        assert_eq!(server.metrics.send_errors.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_write_is_retried() {
+        let server = test_server(ServerSettings::default()).await;
+
+        let message = |n| ServerMessage::Ack { id: MessageId(n) };
+        let (outbound, outbound_recv) = mpsc::channel(4);
+        let outbound_recv = Outbound::new(outbound_recv);
+        for n in 0..2 {
+            outbound
+                .try_send(EncodedMessage::new(&message(n)).unwrap())
+                .unwrap();
+        }
+
+        let (write_msg, _) = writer_connection(true).await;
+        let writer = tokio::spawn(Arc::clone(&server).write_loop(
+            test_id("bob"),
+            write_msg,
+            Arc::clone(&outbound_recv),
+            Arc::default(),
+        ));
+        tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .expect("writer should stop on a reset connection")
+            .unwrap();
+
+        // As after a resume, the next writer starts with the message that failed.
+        drop(outbound);
+        let (write_msg, read_msg) = writer_connection(false).await;
+        Arc::clone(&server)
+            .write_loop(test_id("bob"), write_msg, outbound_recv, Arc::default())
+            .await;
+        let received: Vec<_> = read_msg
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        assert_eq!(received, vec![message(0), message(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_window_coalesces_messages() {
+        let server = test_server(ServerSettings {
+            batch_window_ms: 50,
+            ..Default::default()
+        })
+        .await;
+
+        let message = |n| ServerMessage::Ack { id: MessageId(n) };
+        let (outbound, outbound_recv) = mpsc::channel(4);
+        let (write_msg, read_msg) = writer_connection(false).await;
+        let writer = tokio::spawn(Arc::clone(&server).write_loop(
+            test_id("bob"),
+            write_msg,
+            Outbound::new(outbound_recv),
+            Arc::default(),
+        ));
+        // Sent apart, but both within the window of the first.
+        outbound
+            .send(EncodedMessage::new(&message(0)).unwrap())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        outbound
+            .send(EncodedMessage::new(&message(1)).unwrap())
+            .await
+            .unwrap();
+        drop(outbound);
+        writer.await.unwrap();
+
+        let received: Vec<_> = read_msg
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            received,
+            vec![ServerMessage::Batch(vec![message(0), message(1)])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_version_mismatch_is_rejected() {
+        let server = test_server(ServerSettings::default()).await;
+        let (handler, mut write_msg, mut read_msg) = connect(&server).await;
+
+        write_msg
+            .send(ClientMessage::JoinRequest {
+                name: "bob".to_owned(),
+                channel: None,
+                protocol_version: PROTOCOL_VERSION + 1,
+                admin_token: None,
+            })
+            .await
+            .unwrap();
+        let Some(Ok(ServerMessage::JoinRejected { reason })) = read_msg.next().await else {
+            panic!("expected JoinRejected");
+        };
+        assert!(reason.contains("protocol version"));
+
+        handler.await.unwrap();
+        assert!(server.clients.pin().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_names_are_rejected() {
+        let server = test_server(ServerSettings {
+            max_name_length: 8,
+            reserved_names: vec!["Admin".to_owned()],
+            ..Default::default()
+        })
+        .await;
+
+        for (name, expected) in [
+            ("", "empty"),
+            ("   ", "empty"),
+            ("bob\nal", "control"),
+            ("b\x1b[2J", "control"),
+            ("abcdefghi", "longer than 8"),
+            ("admin", "reserved"),
+        ] {
+            let (handler, mut write_msg, mut read_msg) = connect(&server).await;
+            write_msg.send(join_request(name)).await.unwrap();
+            let Some(Ok(ServerMessage::JoinRejected { reason })) = read_msg.next().await else {
+                panic!("expected JoinRejected for {:?}", name);
+            };
+            assert!(reason.contains(expected), "{:?}: {}", name, reason);
+            handler.await.unwrap();
+        }
+        assert!(server.clients.pin().is_empty());
+
+        // Wide characters count once each.
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("日本語の名前")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket_join() {
+        let path = std::env::temp_dir().join(format!("term-chat-{}.sock", std::process::id()));
+        let server = test_server(ServerSettings {
+            unix_socket: Some(path.clone()),
+            ..Default::default()
+        })
+        .await;
+        let run = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run_loop().await }
+        });
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut write_msg, mut read_msg): (ClientSink, ClientStream) =
+            split_message_stream(stream, CompressionSettings::default())
+                .await
+                .unwrap();
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { id, .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        assert_eq!(id.addr, PeerAddr::Unix(1));
+
+        run.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(not(unix))]
+    #[tokio::test]
+    async fn test_unix_socket_unsupported() {
+        let settings = ServerSettings {
+            listen_addresses: Vec::new(),
+            unix_socket: Some(std::path::PathBuf::from("term-chat.sock")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Server::new(settings).await,
+            Err(ServerError::BindUnix { source, .. }) if source.kind() == std::io::ErrorKind::Unsupported
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_identity_signs_handshake() {
+        let path =
+            std::env::temp_dir().join(format!("term-chat-{}-identity.pem", std::process::id()));
+        let generated = identity::load_or_generate(&path).unwrap();
+        let server = test_server(ServerSettings {
+            identity_key: Some(path.clone()),
+            ..Default::default()
+        })
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        let fingerprint = server.fingerprint().unwrap();
+        assert_eq!(fingerprint, generated.fingerprint());
+
+        let (client, server_end) = tokio::io::duplex(64 * 1024);
+        let addr = PeerAddr::Virtual(NEXT_VIRTUAL.fetch_add(1, Ordering::Relaxed));
+        let handler = tokio::spawn(Arc::clone(&server).handle_new_connection(server_end, addr));
+        let options = HandshakeOptions {
+            pin: Some(&fingerprint),
+            ..Default::default()
+        };
+        let (mut write_msg, mut read_msg): (ClientSink, ClientStream) =
+            split_message_stream_with(client, CompressionSettings::default(), options)
+                .await
+                .unwrap();
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        handler.abort();
+    }
+
+    #[tokio::test]
+    async fn test_insecure_needs_allow_insecure() {
+        // Joins `server` with a client that requires going without encryption.
+        async fn join_insecure(server: &Arc<Server>) -> Result<ServerMessage, SecureStreamError> {
+            let (client, server_end) = tokio::io::duplex(64 * 1024);
+            let addr = PeerAddr::Virtual(NEXT_VIRTUAL.fetch_add(1, Ordering::Relaxed));
+            let handler = tokio::spawn(Arc::clone(server).handle_new_connection(server_end, addr));
+            let options = HandshakeOptions {
+                plaintext: Plaintext::Require,
+                ..Default::default()
+            };
+            let halves: Result<(ClientSink, ClientStream), _> =
+                split_message_stream_with(client, CompressionSettings::default(), options).await;
+            let joined = match halves {
+                Ok((mut write_msg, mut read_msg)) => {
+                    write_msg.send(join_request("bob")).await.unwrap();
+                    Ok(read_msg.next().await.unwrap().unwrap())
+                }
+                Err(err) => Err(err),
+            };
+            handler.abort();
+            joined
+        }
+
+        let server = test_server(ServerSettings::default()).await;
+        assert!(matches!(
+            join_insecure(&server).await,
+            Err(SecureStreamError::PlaintextRefused)
+        ));
+
+        let server = test_server(ServerSettings {
+            allow_insecure: true,
+            ..Default::default()
+        })
+        .await;
+        assert!(matches!(
+            join_insecure(&server).await,
+            Ok(ServerMessage::AcceptJoin { .. })
+        ));
+    }
+
+    fn test_tls_settings() -> TlsSettings {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls");
+        TlsSettings {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[tokio::test]
+    async fn test_tls_needs_feature() {
+        let settings = ServerSettings {
+            listen_addresses: Vec::new(),
+            tls: Some(test_tls_settings()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Server::new(settings).await,
+            Err(ServerError::TlsUnsupported)
+        ));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls_join() {
+        use tokio_rustls::{
+            TlsConnector,
+            rustls::{
+                ClientConfig, RootCertStore,
+                crypto::ring,
+                pki_types::{CertificateDer, ServerName, pem::PemObject},
+            },
+        };
+
+        let server = test_server(ServerSettings {
+            tls: Some(test_tls_settings()),
+            ..Default::default()
+        })
+        .await;
+
+        let mut roots = RootCertStore::empty();
+        let ca = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/ca.pem");
+        roots
+            .add(CertificateDer::from_pem_file(ca).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (client, server_end) = tokio::io::duplex(64 * 1024);
+        let addr = PeerAddr::Tcp("127.0.0.1:0".parse().unwrap());
+        let handler = tokio::spawn(Arc::clone(&server).handle_new_connection(server_end, addr));
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        let (mut write_msg, mut read_msg): (ClientSink, ClientStream) =
+            common::stream::split_plain_message_stream(stream, CompressionSettings::default());
+        write_msg.send(join_request("bob")).await.unwrap();
+        let Some(Ok(ServerMessage::AcceptJoin { id, .. })) = read_msg.next().await else {
+            panic!("expected AcceptJoin");
+        };
+        assert_eq!(id.name, "bob");
+
+        handler.abort();
+    }
+
+    /// Reads from `read_msg` until a message matches `pred`, skipping the rest.
+    async fn next_matching(
+        read_msg: &mut ClientStream,
+        pred: impl Fn(&ServerMessage) -> bool,
+    ) -> ServerMessage {
+        loop {
+            let message = read_msg.next().await.unwrap().unwrap();
+            if pred(&message) {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick_and_ban() {
+        let server = test_server(ServerSettings {
+            admin_token: Some("secret".to_owned()),
+            ..Default::default()
+        })
+        .await;
+        let mut clients = connect_virtual(&server, 3).await;
+        let [
+            (_, admin_write, _),
+            (bob, bob_write, bob_read),
+            (carol, carol_write, carol_read),
+        ] = &mut clients[..]
+        else {
+            unreachable!()
+        };
+        admin_write
+            .send(ClientMessage::JoinRequest {
+                name: "admin".to_owned(),
+                channel: None,
+                protocol_version: PROTOCOL_VERSION,
+                admin_token: Some("secret".to_owned()),
+            })
+            .await
+            .unwrap();
+        bob_write.send(join_request("bob")).await.unwrap();
+        carol_write.send(join_request("carol")).await.unwrap();
+        next_matching(bob_read, |m| matches!(m, ServerMessage::AcceptJoin { .. })).await;
+        next_matching(carol_read, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+
+        admin_write
+            .send(ClientMessage::Admin(AdminCommand::Kick {
+                name: "bob".to_owned(),
+            }))
+            .await
+            .unwrap();
+        next_matching(bob_read, |m| matches!(m, ServerMessage::Kicked { .. })).await;
+        tokio::time::timeout(Duration::from_secs(1), bob)
+            .await
+            .expect("kick should end the session")
+            .unwrap();
+        assert!(server.clients.pin().keys().all(|id| id.name != "bob"));
+
+        admin_write
+            .send(ClientMessage::Admin(AdminCommand::Ban {
+                name: "carol".to_owned(),
+            }))
+            .await
+            .unwrap();
+        next_matching(carol_read, |m| matches!(m, ServerMessage::Kicked { .. })).await;
+        tokio::time::timeout(Duration::from_secs(1), carol)
+            .await
+            .expect("ban should end the session")
+            .unwrap();
+
+        // Names are compared ignoring case and surrounding spaces, as for reserved names.
+        for name in ["carol", "Carol", "carol "] {
+            let (_, mut write_msg, mut read_msg) = connect(&server).await;
+            write_msg.send(join_request(name)).await.unwrap();
+            let Some(Ok(ServerMessage::JoinRejected { reason })) = read_msg.next().await else {
+                panic!("expected JoinRejected for {:?}", name);
+            };
+            assert_eq!(reason, "banned");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_banned_names_are_capped() {
+        let server = test_server(ServerSettings {
+            admin_token: Some("secret".to_owned()),
+            max_banned_names: 1,
+            ..Default::default()
+        })
+        .await;
+        let mut clients = connect_virtual(&server, 1).await;
+        let [(_, admin_write, admin_read)] = &mut clients[..] else {
+            unreachable!()
+        };
+        admin_write
+            .send(ClientMessage::JoinRequest {
+                name: "admin".to_owned(),
+                channel: None,
+                protocol_version: PROTOCOL_VERSION,
+                admin_token: Some("secret".to_owned()),
+            })
+            .await
+            .unwrap();
+        next_matching(admin_read, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+
+        for name in ["bob", "bob", "carol"] {
+            let ban = AdminCommand::Ban {
+                name: name.to_owned(),
+            };
+            admin_write.send(ClientMessage::Admin(ban)).await.unwrap();
+        }
+        let rejected = next_matching(admin_read, |m| {
+            matches!(m, ServerMessage::AdminRejected { .. })
+        })
+        .await;
+        assert_eq!(
+            rejected,
+            ServerMessage::AdminRejected {
+                reason: "can't ban more than 1 names".to_owned()
+            }
+        );
+        let banned_names = server.banned_names.pin();
+        assert!(banned_names.contains("bob"));
+        assert!(!banned_names.contains("carol"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_command_from_non_admin_is_rejected() {
+        let server = test_server(ServerSettings {
+            admin_token: Some("secret".to_owned()),
+            ..Default::default()
+        })
+        .await;
+        let (_alice, _alice_recv) = add_client(&server, "alice", 16);
+        let (_, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("bob")).await.unwrap();
+        write_msg
+            .send(ClientMessage::Admin(AdminCommand::Kick {
+                name: "alice".to_owned(),
+            }))
+            .await
+            .unwrap();
+
+        let rejected = next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::AdminRejected { .. })
+        })
+        .await;
+        assert_eq!(
+            rejected,
+            ServerMessage::AdminRejected {
+                reason: "not an admin".to_owned()
+            }
+        );
+        assert_eq!(server.clients.pin().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stats_are_admin_only() {
+        let server = test_server(ServerSettings {
+            admin_token: Some("secret".to_owned()),
+            ..Default::default()
+        })
+        .await;
+        let mut clients = connect_virtual(&server, 2).await;
+        let [(_, admin_write, admin_read), (_, bob_write, bob_read)] = &mut clients[..] else {
+            unreachable!()
+        };
+        admin_write
+            .send(ClientMessage::JoinRequest {
+                name: "admin".to_owned(),
+                channel: None,
+                protocol_version: PROTOCOL_VERSION,
+                admin_token: Some("secret".to_owned()),
+            })
+            .await
+            .unwrap();
+        bob_write.send(join_request("bob")).await.unwrap();
+        next_matching(bob_read, |m| matches!(m, ServerMessage::AcceptJoin { .. })).await;
+        bob_write
+            .send(ClientMessage::SendMessage {
+                id: MessageId(1),
+                message: "hi".to_owned(),
+                reply_to: None,
+            })
+            .await
+            .unwrap();
+        next_matching(bob_read, |m| matches!(m, ServerMessage::Ack { .. })).await;
+
+        admin_write
+            .send(ClientMessage::Admin(AdminCommand::Stats))
+            .await
+            .unwrap();
+        let stats = next_matching(admin_read, |m| matches!(m, ServerMessage::Stats { .. })).await;
+        let ServerMessage::Stats {
+            client_count,
+            total_messages,
+            ..
+        } = stats
+        else {
+            unreachable!()
+        };
+        assert_eq!((client_count, total_messages), (2, 1));
+
+        bob_write
+            .send(ClientMessage::Admin(AdminCommand::Stats))
+            .await
+            .unwrap();
+        next_matching(bob_read, |m| {
+            matches!(m, ServerMessage::AdminRejected { .. })
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_private_message() {
+        let server = test_server(ServerSettings::default()).await;
+        let mut clients = connect_virtual(&server, 3).await;
+        let [
+            (_, alice_write, alice_read),
+            (_, bob_write, bob_read),
+            (_, carol_write, carol_read),
+        ] = &mut clients[..]
+        else {
+            unreachable!()
+        };
+        bob_write.send(join_request("bob")).await.unwrap();
+        next_matching(bob_read, |m| matches!(m, ServerMessage::AcceptJoin { .. })).await;
+        carol_write
+            .send(ClientMessage::JoinRequest {
+                name: "carol".to_owned(),
+                channel: Some("elsewhere".to_owned()),
+                protocol_version: PROTOCOL_VERSION,
+                admin_token: None,
+            })
+            .await
+            .unwrap();
+        next_matching(carol_read, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+        alice_write.send(join_request("alice")).await.unwrap();
+        let ServerMessage::AcceptJoin { id: alice, .. } = next_matching(alice_read, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await
+        else {
+            unreachable!()
+        };
+
+        for to in ["bob", "carol"] {
+            alice_write
+                .send(ClientMessage::PrivateMessage {
+                    to: to.to_owned(),
+                    message: "psst".to_owned(),
+                })
+                .await
+                .unwrap();
+        }
+        let expected = |to: &str| ServerMessage::PrivateMessage {
+            sender: alice.clone(),
+            to: to.to_owned(),
+            message: "psst".to_owned(),
+        };
+        let is_private = |m: &ServerMessage| matches!(m, ServerMessage::PrivateMessage { .. });
+        assert_eq!(next_matching(bob_read, is_private).await, expected("bob"));
+        // Channels don't matter for private messages.
+        assert_eq!(
+            next_matching(carol_read, is_private).await,
+            expected("carol")
+        );
+        assert_eq!(next_matching(alice_read, is_private).await, expected("bob"));
+        assert_eq!(
+            next_matching(alice_read, is_private).await,
+            expected("carol")
+        );
+
+        alice_write
+            .send(ClientMessage::PrivateMessage {
+                to: "nobody".to_owned(),
+                message: "psst".to_owned(),
+            })
+            .await
+            .unwrap();
+        let rejected = next_matching(alice_read, |m| {
+            matches!(m, ServerMessage::PrivateMessageRejected { .. })
+        })
+        .await;
+        assert_eq!(
+            rejected,
+            ServerMessage::PrivateMessageRejected {
+                to: "nobody".to_owned(),
+                reason: "no client called nobody".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_whois() {
+        let server = test_server(ServerSettings::default()).await;
+        let (bob, _bob_recv) = add_client(&server, "bob", 16);
+        *bob.last_active.lock().unwrap() = Instant::now() - Duration::from_secs(120);
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("alice")).await.unwrap();
+        next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+
+        for name in ["bob", "nobody"] {
+            write_msg
+                .send(ClientMessage::Whois {
+                    name: name.to_owned(),
+                })
+                .await
+                .unwrap();
+        }
+        let ServerMessage::WhoisResult {
+            id,
+            joined_at,
+            away,
+            last_active,
+        } = next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::WhoisResult { .. })
+        })
+        .await
+        else {
+            unreachable!()
+        };
+        assert_eq!(id, bob.id);
+        assert!(joined_at > 0);
+        assert!(away);
+        assert_eq!(last_active, 120);
+
+        let rejected = next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::WhoisRejected { .. })
+        })
+        .await;
+        assert_eq!(
+            rejected,
+            ServerMessage::WhoisRejected {
+                name: "nobody".to_owned(),
+                reason: "no client called nobody".to_owned(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_messages_are_rejected() {
+        let server = test_server(ServerSettings {
+            max_message_length: 5,
+            ..Default::default()
+        })
+        .await;
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("alice")).await.unwrap();
+        let accepted = next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+        assert!(matches!(
+            accepted,
+            ServerMessage::AcceptJoin {
+                limits: ServerLimits {
+                    max_message_length: 5,
+                    ..
+                },
+                ..
+            }
+        ));
+
+        for (id, message) in [(1, "toolong"), (2, "héllo")] {
+            write_msg
+                .send(ClientMessage::SendMessage {
+                    id: MessageId(id),
+                    message: message.to_owned(),
+                    reply_to: None,
+                })
+                .await
+                .unwrap();
+        }
+        let rejected = next_matching(&mut read_msg, |m| {
+            matches!(
+                m,
+                ServerMessage::MessageRejected { .. } | ServerMessage::Ack { .. }
+            )
+        })
+        .await;
+        assert_eq!(
+            rejected,
+            ServerMessage::MessageRejected {
+                id: MessageId(1),
+                reason: "longer than the limit of 5 characters".to_owned(),
+            }
+        );
+        // Five characters in six bytes, within the limit.
+        let acked = next_matching(&mut read_msg, |m| matches!(m, ServerMessage::Ack { .. })).await;
+        assert_eq!(acked, ServerMessage::Ack { id: MessageId(2) });
+    }
+
+    #[tokio::test]
+    async fn test_fast_senders_are_rejected() {
+        let server = test_server(ServerSettings {
+            messages_per_second: 2,
+            ..Default::default()
+        })
+        .await;
+        let (_handler, mut write_msg, mut read_msg) = connect(&server).await;
+        write_msg.send(join_request("alice")).await.unwrap();
+        next_matching(&mut read_msg, |m| {
+            matches!(m, ServerMessage::AcceptJoin { .. })
+        })
+        .await;
+
+        for id in 1..=3 {
+            write_msg
+                .send(ClientMessage::SendMessage {
+                    id: MessageId(id),
+                    message: "hi".to_owned(),
+                    reply_to: None,
+                })
+                .await
+                .unwrap();
+        }
+        let mut answers = Vec::new();
+        while answers.len() < 3 {
+            answers.push(
+                next_matching(&mut read_msg, |m| {
+                    matches!(
+                        m,
+                        ServerMessage::MessageRejected { .. } | ServerMessage::Ack { .. }
+                    )
+                })
+                .await,
+            );
+        }
+        assert_eq!(
+            answers,
+            [
+                ServerMessage::Ack { id: MessageId(1) },
+                ServerMessage::Ack { id: MessageId(2) },
+                ServerMessage::MessageRejected {
+                    id: MessageId(3),
+                    reason: "sending faster than 2 messages a second".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_is_disconnected() {
+        let server = test_server(ServerSettings {
+            idle_disconnect_secs: 1,
+            ..Default::default()
+        })
+        .await;
+        let mut clients = connect_virtual(&server, 2).await;
+        let [
+            (idle, idle_write, idle_read),
+            (_, active_write, active_read),
+        ] = &mut clients[..]
+        else {
+            unreachable!()
+        };
+        idle_write.send(join_request("idle")).await.unwrap();
+        active_write.send(join_request("active")).await.unwrap();
+        next_matching(idle_read, |m| matches!(m, ServerMessage::AcceptJoin { .. })).await;
+
+        for n in 0..4 {
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            let message = ClientMessage::SendMessage {
+                id: MessageId(n),
+                message: "still here".to_owned(),
+                reply_to: None,
+            };
+            active_write.send(message).await.unwrap();
+            let ack = ServerMessage::Ack { id: MessageId(n) };
+            next_matching(active_read, |m| *m == ack).await;
+        }
+
+        let error = next_matching(idle_read, |m| matches!(m, ServerMessage::Error { .. })).await;
+        assert_eq!(
+            error,
+            ServerMessage::Error {
+                reason: "idle timeout".to_owned()
+            }
+        );
+        // Told once, then disconnected.
+        assert!(idle_read.next().await.is_none());
+        tokio::time::timeout(Duration::from_secs(1), idle)
+            .await
+            .expect("idle client should be disconnected")
+            .unwrap();
+        let names: Vec<_> = server
+            .clients
+            .pin()
+            .keys()
+            .map(|id| id.name.clone())
+            .collect();
+        assert_eq!(names, vec!["active".to_owned()]);
     }
 }
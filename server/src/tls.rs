@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Certificate and key TCP connections are served over TLS with, in place of the built-in
+/// handshake. Unix socket connections keep the built-in handshake.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsSettings {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file with the certificate's private key.
+    pub key_path: PathBuf,
+}
+
+#[cfg(feature = "tls")]
+mod acceptor {
+    use std::{path::Path, sync::Arc};
+
+    use tokio_rustls::{
+        TlsAcceptor,
+        rustls::{
+            ServerConfig,
+            crypto::ring,
+            pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        },
+    };
+
+    use crate::{error::ServerError, tls::TlsSettings};
+
+    fn file_error(what: &'static str, path: &Path, reason: impl ToString) -> ServerError {
+        ServerError::TlsFile {
+            what,
+            path: path.to_owned(),
+            reason: reason.to_string(),
+        }
+    }
+
+    impl TlsSettings {
+        /// Loads the certificate and key into an acceptor for incoming connections.
+        pub fn acceptor(&self) -> Result<TlsAcceptor, ServerError> {
+            let certs = CertificateDer::pem_file_iter(&self.cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|err| file_error("certificate", &self.cert_path, err))?;
+            if certs.is_empty() {
+                return Err(file_error(
+                    "certificate",
+                    &self.cert_path,
+                    "no certificates found",
+                ));
+            }
+            let key = PrivateKeyDer::from_pem_file(&self.key_path)
+                .map_err(|err| file_error("key", &self.key_path, err))?;
+
+            let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+                .map_err(|err| ServerError::TlsConfig(err.to_string()))?;
+            Ok(TlsAcceptor::from(Arc::new(config)))
+        }
+    }
+}